@@ -0,0 +1,340 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Instant, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, warn, Span};
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+/// Requests, tunnels and SOCKS connections in progress, by id, for the admin endpoint.
+static ACTIVE: Mutex<BTreeMap<u64, Weak<Entry>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The access log file, one line per finished request, tunnel or SOCKS connection:
+///
+/// `client - user [time] "COMMAND target" status bytes_in bytes_out egress duration_ms`
+///
+/// `bytes_in` came from the client and `bytes_out` went to it. Fields that aren't known, such
+/// as the user without authentication or the status of a connection that ended before a reply,
+/// are written as `-`.
+struct AccessLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(AccessLog { path: path.to_path_buf(), file: Mutex::new(open_append(path)?) })
+    }
+
+    /// Opens the file at the path again, after logrotate moved the old one away.
+    fn reopen(&self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write access log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Starts writing the access log to `path`. Without it, `Access` records nothing.
+pub fn init(path: &Path) -> io::Result<()> {
+    let log = AccessLog::open(path)?;
+    let _ = ACCESS_LOG.set(log);
+    Ok(())
+}
+
+/// Reopens the access log on every SIGUSR1, for logrotate.
+pub fn spawn_sigusr1() {
+    let Some(log) = ACCESS_LOG.get() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(usr1) => usr1,
+            Err(e) => {
+                error!("Failed to install SIGUSR1 handler: {}", e);
+                return;
+            }
+        };
+        while usr1.recv().await.is_some() {
+            match log.reopen() {
+                Ok(()) => info!("Reopened access log {}", log.path.display()),
+                Err(e) => warn!("Failed to reopen access log {}: {}", log.path.display(), e),
+            }
+        }
+    });
+}
+
+struct Record {
+    client: Option<IpAddr>,
+    user: Option<String>,
+    command: String,
+    target: Option<String>,
+    egress: Option<IpAddr>,
+    status: Option<u16>,
+    bytes_in: u64,
+    bytes_out: u64,
+    started: Instant,
+}
+
+impl Record {
+    fn line(&self, time: SystemTime) -> String {
+        fn or_dash<T: Display>(value: &Option<T>) -> String {
+            value.as_ref().map_or_else(|| "-".to_string(), T::to_string)
+        }
+        format!(
+            "{} - {} [{}] \"{} {}\" {} {} {} {} {}\n",
+            or_dash(&self.client),
+            or_dash(&self.user),
+            humantime::format_rfc3339_millis(time),
+            self.command,
+            or_dash(&self.target),
+            or_dash(&self.status),
+            self.bytes_in,
+            self.bytes_out,
+            or_dash(&self.egress),
+            self.started.elapsed().as_millis(),
+        )
+    }
+}
+
+/// A request, tunnel or SOCKS connection in progress, as the admin endpoint lists it.
+#[derive(Serialize)]
+pub struct Connection {
+    pub id: u64,
+    pub protocol: &'static str,
+    pub listener: SocketAddr,
+    pub client: Option<IpAddr>,
+    pub user: Option<String>,
+    pub command: String,
+    pub target: Option<String>,
+    pub egress: Option<IpAddr>,
+    pub duration_ms: u64,
+}
+
+/// Snapshots of the requests, tunnels and SOCKS connections in progress, oldest first.
+pub fn active() -> Vec<Connection> {
+    let entries: Vec<Arc<Entry>> = ACTIVE.lock().unwrap().values().filter_map(Weak::upgrade).collect();
+    // Dropped after the table is unlocked, a last reference removes itself from it
+    entries
+        .iter()
+        .map(|entry| {
+            let record = entry.record.lock().unwrap();
+            Connection {
+                id: entry.id,
+                protocol: entry.protocol,
+                listener: entry.listener,
+                client: record.client,
+                user: record.user.clone(),
+                command: record.command.clone(),
+                target: record.target.clone(),
+                egress: record.egress,
+                duration_ms: record.started.elapsed().as_millis() as u64,
+            }
+        })
+        .collect()
+}
+
+/// Closes connection `id` from the admin endpoint. Returns false if it isn't open.
+pub fn kill(id: u64) -> bool {
+    let entry = ACTIVE.lock().unwrap().get(&id).and_then(Weak::upgrade);
+    match entry {
+        Some(entry) => {
+            entry.kill.send_replace(true);
+            true
+        }
+        None => false,
+    }
+}
+
+struct Entry {
+    id: u64,
+    protocol: &'static str,
+    listener: SocketAddr,
+    record: Mutex<Record>,
+    kill: watch::Sender<bool>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.id);
+        if let Some(log) = ACCESS_LOG.get() {
+            log.write(&self.record.lock().unwrap().line(SystemTime::now()));
+        }
+    }
+}
+
+/// The access log entry of one request, tunnel or SOCKS connection, filled in while it is
+/// served. Clones share the entry, which is written once the last of them is dropped, so a
+/// tunnel or a streamed response body can carry it past the handler that started it. Until
+/// then the connection is listed by `active` and can be closed with `kill`.
+///
+/// The user, target and egress address are also recorded on the current span, so the log
+/// records inside carry them too.
+#[derive(Clone)]
+pub struct Access(Arc<Entry>);
+
+impl Access {
+    pub fn new(protocol: &'static str, listener: SocketAddr, client: Option<IpAddr>, command: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            id,
+            protocol,
+            listener,
+            record: Mutex::new(Record {
+                client,
+                user: None,
+                command: command.to_string(),
+                target: None,
+                egress: None,
+                status: None,
+                bytes_in: 0,
+                bytes_out: 0,
+                started: Instant::now(),
+            }),
+            kill: watch::Sender::new(false),
+        });
+        ACTIVE.lock().unwrap().insert(id, Arc::downgrade(&entry));
+        Access(entry)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Record)) {
+        f(&mut self.0.record.lock().unwrap());
+    }
+
+    pub fn user(&self, user: &str) {
+        Span::current().record("user", user);
+        self.update(|record| record.user = Some(user.to_string()));
+    }
+
+    /// The method or SOCKS command, when it wasn't known on creation.
+    pub fn command(&self, command: &str) {
+        self.update(|record| record.command = command.to_string());
+    }
+
+    pub fn target(&self, target: impl Display) {
+        let target = target.to_string();
+        Span::current().record("target", target.as_str());
+        self.update(|record| record.target = Some(target));
+    }
+
+    pub fn egress(&self, egress: IpAddr) {
+        Span::current().record("egress", tracing::field::display(egress));
+        self.update(|record| record.egress = Some(egress));
+    }
+
+    /// The HTTP status or SOCKS reply code sent to the client.
+    pub fn status(&self, status: u16) {
+        self.update(|record| record.status = Some(status));
+    }
+
+    /// Adds to the bytes received from and sent to the client.
+    pub fn bytes(&self, bytes_in: u64, bytes_out: u64) {
+        self.update(|record| {
+            record.bytes_in += bytes_in;
+            record.bytes_out += bytes_out;
+        });
+    }
+
+    /// Runs `future` to completion, or drops it and returns `None` once the connection is
+    /// killed, which closes the sockets it owns.
+    pub async fn killable<F: Future>(&self, future: F) -> Option<F::Output> {
+        let mut killed = self.0.kill.subscribe();
+        tokio::select! {
+            biased;
+            _ = killed.wait_for(|killed| *killed) => None,
+            output = future => Some(output),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn line_format() {
+        let mut record = Record {
+            client: Some("2001:db8::7".parse().unwrap()),
+            user: Some("alice".to_string()),
+            command: "CONNECT".to_string(),
+            target: Some("example.com:443".to_string()),
+            egress: Some("2001:db8:1::5".parse().unwrap()),
+            status: Some(200),
+            bytes_in: 517,
+            bytes_out: 4096,
+            started: Instant::now(),
+        };
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let line = record.line(time);
+        assert!(
+            line.starts_with("2001:db8::7 - alice [2023-11-14T22:13:20.123Z] \"CONNECT example.com:443\" 200 517 4096 2001:db8:1::5 "),
+            "{}",
+            line
+        );
+
+        record.user = None;
+        record.status = None;
+        record.egress = None;
+        assert!(record.line(time).contains("- - [2023-11-14T22:13:20.123Z] \"CONNECT example.com:443\" - 517 4096 - "));
+    }
+
+    #[tokio::test]
+    async fn lists_and_kills_active_connections() {
+        let access = Access::new("socks5", "127.0.0.1:1080".parse().unwrap(), None, "CONNECT");
+        access.target("example.com:443");
+        let id = access.0.id;
+        let listed = active().into_iter().find(|connection| connection.id == id).unwrap();
+        assert_eq!(listed.protocol, "socks5");
+        assert_eq!(listed.target.as_deref(), Some("example.com:443"));
+
+        let tunnel = {
+            let access = access.clone();
+            tokio::spawn(async move { access.killable(std::future::pending::<()>()).await })
+        };
+        assert!(kill(id));
+        assert_eq!(tunnel.await.unwrap(), None);
+        assert_eq!(access.killable(async { 1 }).await, None);
+
+        drop(access);
+        assert!(!active().iter().any(|connection| connection.id == id));
+        assert!(!kill(id));
+    }
+
+    #[test]
+    fn reopens_after_rotation() {
+        let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let log = AccessLog::open(&path).unwrap();
+        log.write("first\n");
+        std::fs::rename(&path, dir.join("access.log.1")).unwrap();
+        log.write("second\n");
+        log.reopen().unwrap();
+        log.write("third\n");
+
+        assert_eq!(std::fs::read_to_string(dir.join("access.log.1")).unwrap(), "first\nsecond\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
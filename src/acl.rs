@@ -0,0 +1,314 @@
+use cidr::IpCidr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::nat64::{Nat64Prefix, WELL_KNOWN_PREFIX};
+
+/// Destinations denied unless a rule allows them first: unspecified, loopback, RFC 1918,
+/// shared (CGNAT), IETF protocol assignments, benchmarking, reserved, link-local, unique-local,
+/// multicast and broadcast addresses.
+const PRIVATE_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "255.255.255.255/32",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// One client rule: `allow <cidr>`, `deny <cidr>`, or a bare `<cidr>` meaning allow.
+/// A plain address is the same as a `/32` or `/128`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    pub network: IpCidr,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (action, network) = match s.split_once(char::is_whitespace) {
+            Some(("allow", network)) => (Action::Allow, network),
+            Some(("deny", network)) => (Action::Deny, network),
+            Some((action, _)) => return Err(format!("unknown action '{}', expected allow or deny", action)),
+            None => (Action::Allow, s),
+        };
+        let network = network.trim().parse().map_err(|e| format!("{}", e))?;
+        Ok(Rule { action, network })
+    }
+}
+
+/// Ordered allow/deny rules for client addresses; the first rule that matches decides.
+///
+/// If none matches, the client is denied when the list has any allow rule, so a plain list of
+/// networks stays an allow-list, and allowed when it only has deny rules. An empty list
+/// allows everyone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Acl { rules }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        match self.rules.iter().find(|rule| rule.network.contains(&ip)) {
+            Some(rule) => rule.action == Action::Allow,
+            None => !self.rules.iter().any(|rule| rule.action == Action::Allow),
+        }
+    }
+}
+
+/// What a destination rule matches.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Any,
+    Network(IpCidr),
+    /// Lowercase glob, `*` matches any run of characters
+    Domain(String),
+}
+
+/// One destination rule: `<allow|deny> <target> [<port>|<from>-<to>]`, where the target is
+/// `*`, an address or CIDR, or a domain glob such as `*.corp.example.com`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DestinationRule {
+    pub action: Action,
+    pub target: Target,
+    pub ports: Option<(u16, u16)>,
+}
+
+impl FromStr for DestinationRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let (action, target, ports) = match parts.as_slice() {
+            [action, target] => (action, target, None),
+            [action, target, ports] => (action, target, Some(ports)),
+            _ => return Err("expected <allow|deny> <target> [ports]".to_string()),
+        };
+        let action = match *action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            action => return Err(format!("unknown action '{}', expected allow or deny", action)),
+        };
+        let target = if *target == "*" {
+            Target::Any
+        } else if let Ok(network) = target.parse() {
+            Target::Network(network)
+        } else if target.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '*')) {
+            Target::Domain(target.to_ascii_lowercase())
+        } else {
+            return Err(format!("invalid target '{}'", target));
+        };
+        let ports = ports
+            .map(|ports| {
+                let (from, to) = ports.split_once('-').unwrap_or((ports, ports));
+                match (from.parse::<u16>(), to.parse::<u16>()) {
+                    (Ok(from), Ok(to)) if from <= to => Ok((from, to)),
+                    _ => Err(format!("invalid port range '{}'", ports)),
+                }
+            })
+            .transpose()?;
+        Ok(DestinationRule { action, target, ports })
+    }
+}
+
+impl DestinationRule {
+    fn matches(&self, host: &str, addr: SocketAddr) -> bool {
+        if let Some((from, to)) = self.ports {
+            if addr.port() < from || addr.port() > to {
+                return false;
+            }
+        }
+        match &self.target {
+            Target::Any => true,
+            Target::Network(network) => network.contains(&addr.ip().to_canonical()),
+            Target::Domain(glob) => glob_match(glob.as_bytes(), host.trim_end_matches('.').to_ascii_lowercase().as_bytes()),
+        }
+    }
+}
+
+/// Ordered rules for where the proxies may connect, checked after the target is resolved;
+/// the first rule that matches decides and anything unmatched is allowed. Unless disabled,
+/// private and link-local networks are denied after the configured rules, so an explicit
+/// `allow` can still open one of them.
+///
+/// An address inside the well-known NAT64 prefix or the listener's own is also checked as the
+/// IPv4 address it embeds, which is where the gateway delivers it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DestinationAcl {
+    rules: Vec<DestinationRule>,
+    nat64: Option<Nat64Prefix>,
+}
+
+impl Default for DestinationAcl {
+    fn default() -> Self {
+        DestinationAcl::new(Vec::new(), true)
+    }
+}
+
+impl DestinationAcl {
+    pub fn new(mut rules: Vec<DestinationRule>, block_private: bool) -> Self {
+        if block_private {
+            rules.extend(PRIVATE_NETWORKS.iter().map(|network| DestinationRule {
+                action: Action::Deny,
+                target: Target::Network(network.parse().unwrap()),
+                ports: None,
+            }));
+        }
+        DestinationAcl { rules, nat64: None }
+    }
+
+    /// Also checks the IPv4 addresses embedded in `prefix`, the listener's NAT64 prefix.
+    pub fn with_nat64(mut self, prefix: Option<Nat64Prefix>) -> Self {
+        self.nat64 = prefix;
+        self
+    }
+
+    /// `host` is the name the client asked for (or the address, if it gave one) and `addr`
+    /// what it resolved to.
+    pub fn allows(&self, host: &str, addr: SocketAddr) -> bool {
+        let embedded = self.nat64_target(addr.ip()).map(|ip| SocketAddr::new(ip.into(), addr.port()));
+        self.decides(host, addr) && embedded.is_none_or(|embedded| self.decides(host, embedded))
+    }
+
+    fn decides(&self, host: &str, addr: SocketAddr) -> bool {
+        match self.rules.iter().find(|rule| rule.matches(host, addr)) {
+            Some(rule) => rule.action == Action::Allow,
+            None => true,
+        }
+    }
+
+    /// The IPv4 address a NAT64 gateway would translate `ip` to.
+    fn nat64_target(&self, ip: IpAddr) -> Option<Ipv4Addr> {
+        let IpAddr::V6(ip) = ip else {
+            return None;
+        };
+        let well_known: Nat64Prefix = WELL_KNOWN_PREFIX.parse().unwrap();
+        well_known.extract(ip).or_else(|| self.nat64.and_then(|prefix| prefix.extract(ip)))
+    }
+}
+
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(rules: &[&str]) -> Acl {
+        Acl::new(rules.iter().map(|rule| rule.parse().unwrap()).collect())
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let acl = acl(&["deny 10.1.0.0/16", "allow 10.0.0.0/8", "2001:db8::/32", "192.0.2.1"]);
+        assert!(acl.allows(ip("10.2.3.4")));
+        assert!(!acl.allows(ip("10.1.3.4")));
+        assert!(acl.allows(ip("2001:db8:1::1")));
+        assert!(acl.allows(ip("192.0.2.1")));
+        assert!(!acl.allows(ip("192.0.2.2")));
+        assert!(acl.allows(ip("::ffff:10.2.3.4")));
+    }
+
+    #[test]
+    fn default_depends_on_allow_rules() {
+        assert!(acl(&[]).allows(ip("198.51.100.1")));
+        let deny_only = acl(&["deny 198.51.100.0/24"]);
+        assert!(!deny_only.allows(ip("198.51.100.1")));
+        assert!(deny_only.allows(ip("203.0.113.1")));
+    }
+
+    fn destinations(rules: &[&str]) -> DestinationAcl {
+        DestinationAcl::new(rules.iter().map(|rule| rule.parse().unwrap()).collect(), true)
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn private_destinations_are_denied_by_default() {
+        let acl = DestinationAcl::default();
+        for target in [
+            "127.0.0.1:80", "10.1.2.3:443", "192.168.1.1:22", "169.254.169.254:80", "[::1]:80", "[fe80::1]:80", "[::ffff:10.0.0.1]:80",
+            "192.0.0.170:53", "198.18.0.1:80", "224.0.0.251:5353", "240.0.0.1:80", "255.255.255.255:9", "[ff02::1]:80",
+        ] {
+            assert!(!acl.allows("internal", addr(target)), "{}", target);
+        }
+        assert!(acl.allows("example.com", addr("93.184.216.34:443")));
+        assert!(DestinationAcl::new(Vec::new(), false).allows("localhost", addr("127.0.0.1:80")));
+    }
+
+    #[test]
+    fn nat64_addresses_are_checked_as_their_ipv4_target() {
+        let acl = DestinationAcl::default();
+        for target in ["[64:ff9b::a00:1]:22", "[64:ff9b::7f00:1]:80"] {
+            assert!(!acl.allows("internal", addr(target)), "{}", target);
+        }
+        assert!(acl.allows("public", addr("[64:ff9b::c633:6401]:443")));
+
+        let acl = DestinationAcl::default().with_nat64(Some("2001:db8:64::/96".parse().unwrap()));
+        assert!(!acl.allows("internal", addr("[2001:db8:64::a00:1]:22")));
+        let acl = destinations(&["deny 198.51.100.0/24"]);
+        assert!(!acl.allows("public", addr("[64:ff9b::c633:6401]:443")));
+    }
+
+    #[test]
+    fn destination_rules_match_domains_networks_and_ports() {
+        let acl = destinations(&["allow 10.0.5.0/24 8000-8999", "deny *.corp.example.com", "deny * 25", "deny 203.0.113.0/24"]);
+        assert!(acl.allows("10.0.5.7", addr("10.0.5.7:8080")));
+        assert!(!acl.allows("10.0.5.7", addr("10.0.5.7:22")));
+        assert!(!acl.allows("Git.Corp.Example.com.", addr("198.51.100.1:443")));
+        assert!(acl.allows("corp.example.com", addr("198.51.100.1:443")));
+        assert!(!acl.allows("mail.example.com", addr("198.51.100.2:25")));
+        assert!(!acl.allows("example.net", addr("203.0.113.9:443")));
+    }
+
+    #[test]
+    fn invalid_destination_rules_are_rejected() {
+        for rule in ["deny", "block example.com", "deny example.com 80-20", "deny example.com 99999", "deny exa/mple"] {
+            assert!(rule.parse::<DestinationRule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!("permit 10.0.0.0/8".parse::<Rule>().is_err());
+        assert!("10.0.0.1/8".parse::<Rule>().is_err());
+        assert!("deny".parse::<Rule>().is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use cidr::IpCidr;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::access_log;
+use crate::auth::{Auth, User};
+use crate::config::UserConfig;
+use crate::pool::AddressPool;
+use crate::reload::Reloader;
+use crate::shutdown;
+
+/// What the admin endpoint inspects and changes.
+struct Admin {
+    /// Bearer token every request must carry, if set
+    token: Option<String>,
+    reloader: Arc<Reloader>,
+    pool: Arc<AddressPool>,
+    auth: Arc<Auth>,
+}
+
+/// Serves the admin endpoint. Every request needs an `Authorization: Bearer <token>` header;
+/// only a loopback address may go without a token, which the configuration has to opt into
+/// with `admin_no_token`. Changes to the pool and the users last until the next reload.
+///
+/// - `POST /reload`: re-reads the configuration, like SIGHUP, and returns a summary
+/// - `GET /connections`: requests, tunnels and SOCKS connections in progress
+/// - `DELETE /connections/{id}`: closes one
+/// - `GET /pool`: the subnets and selection counters
+/// - `POST /pool/subnets`, `DELETE /pool/subnets`: adds or removes `{"subnet": "..."}`
+/// - `GET /leases`: addresses added to the interface in system-route mode
+/// - `GET /users`, `POST /users`: lists users, adds or replaces one (a `[[users]]` entry)
+/// - `PATCH /users/{name}`: `{"enabled": false}` turns new logins away
+/// - `DELETE /users/{name}`: removes a user
+/// - `GET /drain`, `PUT /drain`: `{"draining": true}` makes the listeners turn new
+///   connections away while open ones finish
+pub async fn start_admin(
+    listen_addr: SocketAddr,
+    token: Option<String>,
+    reloader: Arc<Reloader>,
+    pool: Arc<AddressPool>,
+    auth: Arc<Auth>,
+) -> Result<(), Box<dyn Error>> {
+    if token.is_none() {
+        if !listen_addr.ip().is_loopback() {
+            return Err(format!("Refusing to serve the admin endpoint on {} without a token", listen_addr).into());
+        }
+        warn!("Admin endpoint on {} has no token, any local user can control the proxy", listen_addr);
+    }
+    let admin = Arc::new(Admin { token, reloader, pool, auth });
+    let make_service = make_service_fn(move |_| {
+        let admin = Arc::clone(&admin);
+        async move { Ok::<_, hyper::Error>(service_fn(move |req| handle(req, Arc::clone(&admin)))) }
+    });
+
+    info!("Admin endpoint listening on {}", listen_addr);
+    Server::bind(&listen_addr).serve(make_service).await.map_err(|err| err.into())
+}
+
+async fn handle(req: Request<Body>, admin: Arc<Admin>) -> Result<Response<Body>, hyper::Error> {
+    if !admin.authorized(&req) {
+        let mut response = error(StatusCode::UNAUTHORIZED, "missing or wrong bearer token");
+        response.headers_mut().insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        return Ok(response);
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (&method, segments.as_slice()) {
+        (&Method::POST, ["reload"]) => match admin.reloader.reload_and_log() {
+            Ok(summary) => ok(json!({ "reloaded": true, "summary": summary })),
+            Err(e) => error(StatusCode::UNPROCESSABLE_ENTITY, e),
+        },
+        (&Method::GET, ["connections"]) => ok(json!(access_log::active())),
+        (&Method::DELETE, ["connections", id]) => match id.parse() {
+            Ok(id) if access_log::kill(id) => {
+                info!("Admin: closed connection {}", id);
+                ok(json!({ "closed": id }))
+            }
+            Ok(_) => error(StatusCode::NOT_FOUND, "no such connection"),
+            Err(e) => error(StatusCode::BAD_REQUEST, e),
+        },
+        (&Method::GET, ["pool"]) => ok(admin.pool_json()),
+        (&Method::POST | &Method::DELETE, ["pool", "subnets"]) => admin.change_subnet(&method, req).await,
+        (&Method::GET, ["leases"]) => match admin.pool.leases() {
+            Some(leases) => ok(json!(leases)),
+            None => error(StatusCode::NOT_FOUND, "system-route mode is off"),
+        },
+        (&Method::GET, ["users"]) => ok(json!(admin.auth.users().iter().map(|(user, open)| user_json(user, *open)).collect::<Vec<_>>())),
+        (&Method::POST, ["users"]) => admin.upsert_user(req).await,
+        (&Method::PATCH, ["users", name]) => match read_json::<EnabledBody>(req).await {
+            Ok(body) if admin.auth.set_enabled(name, body.enabled) => {
+                info!("Admin: user {} {}", name, if body.enabled { "enabled" } else { "disabled" });
+                ok(json!({ "name": name, "enabled": body.enabled }))
+            }
+            Ok(_) => error(StatusCode::NOT_FOUND, "no such user"),
+            Err(response) => response,
+        },
+        (&Method::DELETE, ["users", name]) => {
+            if admin.auth.remove_user(name) {
+                info!("Admin: removed user {}", name);
+                ok(json!({ "removed": name }))
+            } else {
+                error(StatusCode::NOT_FOUND, "no such user")
+            }
+        }
+        (&Method::GET, ["drain"]) => ok(json!({ "draining": shutdown::is_draining() })),
+        (&Method::PUT, ["drain"]) => match read_json::<DrainBody>(req).await {
+            Ok(body) => {
+                shutdown::set_draining(body.draining);
+                info!("Admin: drain mode {}", if body.draining { "on" } else { "off" });
+                ok(json!({ "draining": body.draining }))
+            }
+            Err(response) => response,
+        },
+        _ => error(StatusCode::NOT_FOUND, "Not Found"),
+    };
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubnetBody {
+    subnet: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnabledBody {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DrainBody {
+    draining: bool,
+}
+
+impl Admin {
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let given = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // Digests of equal length, so the comparison doesn't reveal how much of the token matched
+        given.is_some_and(|given| Sha256::digest(given) == Sha256::digest(token))
+    }
+
+    fn pool_json(&self) -> Value {
+        let subnets = self.pool.subnets();
+        let stats = self.pool.stats();
+        json!({
+            "ipv6_subnets": subnets.ipv6.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "ipv4_subnets": subnets.ipv4.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "ipv6_selected": stats.ipv6_selected,
+            "ipv4_selected": stats.ipv4_selected,
+            "open_connections": self.pool.open_connections(),
+        })
+    }
+
+    async fn change_subnet(&self, method: &Method, req: Request<Body>) -> Response<Body> {
+        let body = match read_json::<SubnetBody>(req).await {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let subnet: IpCidr = match body.subnet.parse() {
+            Ok(subnet) => subnet,
+            Err(e) => return error(StatusCode::BAD_REQUEST, format!("subnet: {}", e)),
+        };
+        if method == Method::POST {
+            if !self.pool.add_subnet(subnet) {
+                return error(StatusCode::CONFLICT, "subnet is already in the pool");
+            }
+            info!("Admin: added subnet {}", subnet);
+        } else {
+            if !self.pool.remove_subnet(subnet) {
+                return error(StatusCode::NOT_FOUND, "subnet is not in the pool");
+            }
+            info!("Admin: removed subnet {}", subnet);
+        }
+        ok(self.pool_json())
+    }
+
+    async fn upsert_user(&self, req: Request<Body>) -> Response<Body> {
+        let config = match read_json::<UserConfig>(req).await {
+            Ok(config) => config,
+            Err(response) => return response,
+        };
+        let subnets = self.pool.subnets();
+        let user = match config.build("user", &subnets.ipv6, &subnets.ipv4) {
+            Ok(user) => user,
+            Err(e) => return error(StatusCode::UNPROCESSABLE_ENTITY, e),
+        };
+        let body = user_json(&user, 0);
+        let name = user.name.clone();
+        if self.auth.upsert_user(user) {
+            info!("Admin: replaced user {}", name);
+            ok(body)
+        } else {
+            info!("Admin: added user {}", name);
+            json_response(StatusCode::CREATED, body)
+        }
+    }
+}
+
+/// A user without its credential.
+fn user_json(user: &User, connections: usize) -> Value {
+    let subnets = user.subnets.as_ref().map(|subnets| {
+        subnets.ipv6.iter().map(ToString::to_string).chain(subnets.ipv4.iter().map(ToString::to_string)).collect::<Vec<_>>()
+    });
+    json!({
+        "name": user.name,
+        "enabled": user.enabled,
+        "max_connections": user.max_connections,
+        "subnets": subnets,
+        "limits": user.limits,
+        "connections": connections,
+    })
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+    let bytes = hyper::body::to_bytes(req.into_body()).await.map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    serde_json::from_slice(&bytes).map_err(|e| error(StatusCode::BAD_REQUEST, e))
+}
+
+fn ok(body: Value) -> Response<Body> {
+    json_response(StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, message: impl Display) -> Response<Body> {
+    json_response(status, json!({ "error": message.to_string() }))
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string() + "\n"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Credential, Users};
+    use crate::config::options;
+    use crate::limit::{Limiter, Limits};
+
+    fn admin(token: Option<&str>) -> Arc<Admin> {
+        let pool = Arc::new(AddressPool::for_tests(&["2001:db8::/48"], &[]));
+        let auth = Arc::new(Auth::new(Users::new(vec![User::new("alice", Credential::Plain("pw".into()))]).unwrap()));
+        let matches = options().parse(Vec::<String>::new()).unwrap();
+        let reloader = Arc::new(Reloader::new(matches, Arc::clone(&pool), Arc::clone(&auth), Arc::new(Limiter::new(Limits::default(), Limits::default())), Vec::new(), Vec::new(), Vec::new(), Vec::new()));
+        Arc::new(Admin { token: token.map(str::to_string), reloader, pool, auth })
+    }
+
+    async fn call(admin: &Arc<Admin>, method: Method, path: &str, token: Option<&str>, body: &str) -> (StatusCode, Value) {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = handle(req.body(Body::from(body.to_string())).unwrap(), Arc::clone(admin)).await.unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_without_a_token_off_loopback() {
+        let admin = admin(None);
+        let started = start_admin("0.0.0.0:0".parse().unwrap(), None, Arc::clone(&admin.reloader), Arc::clone(&admin.pool), Arc::clone(&admin.auth));
+        let err = started.await.err().unwrap();
+        assert_eq!(err.to_string(), "Refusing to serve the admin endpoint on 0.0.0.0:0 without a token");
+    }
+
+    #[tokio::test]
+    async fn reload_answers_in_json() {
+        let admin = admin(Some("s3cret"));
+        let (status, body) = call(&admin, Method::POST, "/reload", Some("s3cret"), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["reloaded"], json!(true));
+        assert!(body["summary"].is_string(), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let admin = admin(Some("s3cret"));
+        assert_eq!(call(&admin, Method::GET, "/pool", None, "").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&admin, Method::GET, "/pool", Some("wrong"), "").await.0, StatusCode::UNAUTHORIZED);
+        let (status, pool) = call(&admin, Method::GET, "/pool", Some("s3cret"), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(pool["ipv6_subnets"], json!(["2001:db8::/48"]));
+    }
+
+    #[tokio::test]
+    async fn changes_subnets_and_users() {
+        let admin = admin(None);
+        let (status, pool) = call(&admin, Method::POST, "/pool/subnets", None, r#"{"subnet": "192.0.2.0/24"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(pool["ipv4_subnets"], json!(["192.0.2.0/24"]));
+        assert_eq!(call(&admin, Method::POST, "/pool/subnets", None, r#"{"subnet": "192.0.2.0/24"}"#).await.0, StatusCode::CONFLICT);
+        assert_eq!(call(&admin, Method::DELETE, "/pool/subnets", None, r#"{"subnet": "2001:db8::/48"}"#).await.0, StatusCode::OK);
+        assert!(admin.pool.subnets().ipv6.is_empty());
+
+        let (status, user) = call(&admin, Method::POST, "/users", None, r#"{"username": "bob", "password": "pw", "ipv4_subnets": ["192.0.2.0/25"]}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(user["subnets"], json!(["192.0.2.0/25"]));
+        let (status, e) = call(&admin, Method::POST, "/users", None, r#"{"username": "carol", "password": "pw", "ipv4_subnets": ["198.51.100.0/24"]}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(e["error"].as_str().unwrap().starts_with("user.ipv4_subnets: "), "{}", e);
+
+        assert_eq!(call(&admin, Method::PATCH, "/users/bob", None, r#"{"enabled": false}"#).await.0, StatusCode::OK);
+        assert_eq!(call(&admin, Method::DELETE, "/users/alice", None, "").await.0, StatusCode::OK);
+        let (_, users) = call(&admin, Method::GET, "/users", None, "").await;
+        assert_eq!(users, json!([{ "name": "bob", "enabled": false, "max_connections": null, "subnets": ["192.0.2.0/25"], "limits": {}, "connections": 0 }]));
+        assert_eq!(call(&admin, Method::DELETE, "/users/alice", None, "").await.0, StatusCode::NOT_FOUND);
+    }
+}
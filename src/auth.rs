@@ -0,0 +1,399 @@
+use arc_swap::ArcSwap;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::limit::Limits;
+use crate::pool::Subnets;
+
+/// How a user's password is stored.
+#[derive(Clone)]
+pub enum Credential {
+    Plain(String),
+    /// `$2a$`, `$2b$` or `$2y$`
+    Bcrypt(String),
+    /// PHC string, e.g. `$argon2id$v=19$...`
+    Argon2(String),
+}
+
+impl Credential {
+    /// Recognizes a bcrypt or argon2 hash. Other htpasswd schemes (`$apr1$`, `{SHA}`, crypt)
+    /// are rejected rather than compared as plain text.
+    pub fn parse_hash(hash: &str) -> Result<Self, String> {
+        if ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+            hash.parse::<bcrypt::HashParts>().map_err(|e| format!("invalid bcrypt hash: {}", e))?;
+            Ok(Credential::Bcrypt(hash.to_string()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).map_err(|e| format!("invalid argon2 hash: {}", e))?;
+            Ok(Credential::Argon2(hash.to_string()))
+        } else {
+            Err("unsupported hash, use bcrypt (htpasswd -B) or argon2".to_string())
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Credential::Plain(expected) => expected == password,
+            Credential::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Credential::Argon2(hash) => PasswordHash::new(hash)
+                .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+                .unwrap_or(false),
+        }
+    }
+
+    fn is_hashed(&self) -> bool {
+        !matches!(self, Credential::Plain(_))
+    }
+
+    fn stored(&self) -> &str {
+        match self {
+            Credential::Plain(s) | Credential::Bcrypt(s) | Credential::Argon2(s) => s,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    pub credential: Credential,
+    pub enabled: bool,
+    /// Connections this user may have open at once, unlimited if `None`
+    pub max_connections: Option<usize>,
+    /// Egress subnets of this user, the whole pool if `None`
+    pub subnets: Option<Subnets>,
+    pub limits: Limits,
+}
+
+impl User {
+    /// An enabled user without limits, using the whole pool.
+    pub fn new(name: &str, credential: Credential) -> Self {
+        User {
+            name: name.to_string(),
+            credential,
+            enabled: true,
+            max_connections: None,
+            subnets: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// The user table. Authentication is off when it is empty.
+#[derive(Default)]
+pub struct Users {
+    users: HashMap<String, Arc<User>>,
+    /// Digests of credentials that passed a hash check, bcrypt and argon2 are too slow to run
+    /// on every request of a keep-alive connection
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl Users {
+    pub fn new(users: Vec<User>) -> Result<Self, String> {
+        let mut table = HashMap::new();
+        for user in users {
+            if table.contains_key(&user.name) {
+                return Err(format!("duplicate user '{}'", user.name));
+            }
+            table.insert(user.name.clone(), Arc::new(user));
+        }
+        Ok(Users { users: table, verified: Mutex::new(HashSet::new()) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// A copy with `edit` applied to the table, keeping the verified-password cache.
+    fn edited(&self, edit: impl FnOnce(&mut HashMap<String, Arc<User>>)) -> Users {
+        let mut users = self.users.clone();
+        edit(&mut users);
+        Users { users, verified: Mutex::new(self.verified.lock().unwrap().clone()) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    fn verify(&self, user: &User, password: &str) -> bool {
+        if !user.credential.is_hashed() {
+            return user.credential.verify(password);
+        }
+        let digest: [u8; 32] = Sha256::new()
+            .chain_update(user.credential.stored())
+            .chain_update([0])
+            .chain_update(password)
+            .finalize()
+            .into();
+        if self.verified.lock().unwrap().contains(&digest) {
+            return true;
+        }
+        let valid = user.credential.verify(password);
+        if valid {
+            self.verified.lock().unwrap().insert(digest);
+        }
+        valid
+    }
+}
+
+/// Reads an htpasswd-style file: one `name:hash` per line, `#` starts a comment.
+pub fn load_htpasswd(path: &Path) -> Result<Vec<User>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse_htpasswd(&content).map_err(|e| format!("{}:{}", path.display(), e))
+}
+
+fn parse_htpasswd(content: &str) -> Result<Vec<User>, String> {
+    let mut users = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, hash) = line.split_once(':').ok_or_else(|| format!("{}: expected name:hash", i + 1))?;
+        let credential = Credential::parse_hash(hash).map_err(|e| format!("{}: {}", i + 1, e))?;
+        users.push(User::new(name, credential));
+    }
+    Ok(users)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    UnknownUser,
+    BadPassword,
+    Disabled,
+    TooManyConnections,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnknownUser => write!(f, "unknown user"),
+            AuthError::BadPassword => write!(f, "wrong password"),
+            AuthError::Disabled => write!(f, "user is disabled"),
+            AuthError::TooManyConnections => write!(f, "too many connections"),
+        }
+    }
+}
+
+/// Checks credentials against the user table and enforces each user's connection cap.
+///
+/// The table is swapped on reload; connection counts are kept across reloads so a reload
+/// can't be used to get past a cap.
+pub struct Auth {
+    users: ArcSwap<Users>,
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// An authenticated connection. Holds one of the user's connection slots until dropped.
+pub struct Login {
+    user: Arc<User>,
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Login {
+    /// The subnets this user's addresses come from, if restricted.
+    pub fn subnets(&self) -> Option<&Subnets> {
+        self.user.subnets.as_ref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.user.name
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.user.limits
+    }
+}
+
+impl Drop for Login {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.user.name) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.user.name);
+            }
+        }
+    }
+}
+
+impl Auth {
+    pub fn new(users: Users) -> Self {
+        Auth {
+            users: ArcSwap::from_pointee(users),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_users(&self, users: Users) {
+        self.users.store(Arc::new(users));
+    }
+
+    /// Every user, by name, with the number of connections they have open.
+    pub fn users(&self) -> Vec<(Arc<User>, usize)> {
+        let connections = self.connections.lock().unwrap();
+        let mut users: Vec<_> = self
+            .users
+            .load()
+            .users
+            .values()
+            .map(|user| (Arc::clone(user), connections.get(&user.name).copied().unwrap_or(0)))
+            .collect();
+        users.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        users
+    }
+
+    /// Adds `user`, or replaces the one of the same name. Returns whether it replaced one.
+    /// Open connections keep the settings they logged in with. Like the other changes below,
+    /// this lasts until the configuration is reloaded.
+    pub fn upsert_user(&self, user: User) -> bool {
+        let user = Arc::new(user);
+        let previous = self.users.rcu(|users| {
+            users.edited(|table| {
+                table.insert(user.name.clone(), Arc::clone(&user));
+            })
+        });
+        previous.users.contains_key(&user.name)
+    }
+
+    /// Returns false if there is no such user.
+    pub fn remove_user(&self, name: &str) -> bool {
+        let previous = self.users.rcu(|users| {
+            users.edited(|table| {
+                table.remove(name);
+            })
+        });
+        previous.users.contains_key(name)
+    }
+
+    /// Enables or disables a user for new logins. Returns false if there is no such user.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+        let previous = self.users.rcu(|users| {
+            users.edited(|table| {
+                if let Some(user) = table.get_mut(name) {
+                    *user = Arc::new(User { enabled, ..User::clone(user) });
+                }
+            })
+        });
+        previous.users.contains_key(name)
+    }
+
+    /// Whether clients have to log in, i.e. whether any user is configured.
+    pub fn is_enabled(&self) -> bool {
+        !self.users.load().is_empty()
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.load().len()
+    }
+
+    /// Verifies `password` and takes a connection slot of `username`. The `-session-<id>`
+    /// suffix must already be stripped from the username.
+    pub async fn login(&self, username: &str, password: &str) -> Result<Login, AuthError> {
+        let users = self.users.load_full();
+        let user = users.users.get(username).cloned().ok_or(AuthError::UnknownUser)?;
+
+        let valid = if user.credential.is_hashed() {
+            let (users, user, password) = (Arc::clone(&users), Arc::clone(&user), password.to_string());
+            tokio::task::spawn_blocking(move || users.verify(&user, &password)).await.unwrap_or(false)
+        } else {
+            users.verify(&user, password)
+        };
+        if !valid {
+            return Err(AuthError::BadPassword);
+        }
+        if !user.enabled {
+            return Err(AuthError::Disabled);
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(user.name.clone()).or_insert(0);
+        if user.max_connections.is_some_and(|max| *count >= max) {
+            return Err(AuthError::TooManyConnections);
+        }
+        *count += 1;
+        drop(connections);
+        Ok(Login { user, connections: Arc::clone(&self.connections) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+    fn auth(users: Vec<User>) -> Auth {
+        Auth::new(Users::new(users).unwrap())
+    }
+
+    #[tokio::test]
+    async fn plain_bcrypt_and_argon2_passwords() {
+        let bcrypt_hash = bcrypt::hash("b-secret", 4).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2_hash = Argon2::default().hash_password(b"a-secret", &salt).unwrap().to_string();
+        let auth = auth(vec![
+            User::new("plain", Credential::Plain("p-secret".into())),
+            User::new("bcrypt", Credential::parse_hash(&bcrypt_hash).unwrap()),
+            User::new("argon2", Credential::parse_hash(&argon2_hash).unwrap()),
+        ]);
+
+        for (name, password) in [("plain", "p-secret"), ("bcrypt", "b-secret"), ("argon2", "a-secret")] {
+            assert!(auth.login(name, password).await.is_ok(), "{}", name);
+            assert_eq!(auth.login(name, "wrong").await.err(), Some(AuthError::BadPassword), "{}", name);
+        }
+        // Served from the cache the second time
+        assert!(auth.login("bcrypt", "b-secret").await.is_ok());
+        assert_eq!(auth.login("nobody", "x").await.err(), Some(AuthError::UnknownUser));
+    }
+
+    #[tokio::test]
+    async fn disabled_user_and_connection_cap() {
+        let auth = auth(vec![
+            User { enabled: false, ..User::new("off", Credential::Plain("pw".into())) },
+            User { max_connections: Some(1), ..User::new("capped", Credential::Plain("pw".into())) },
+        ]);
+        assert_eq!(auth.login("off", "pw").await.err(), Some(AuthError::Disabled));
+
+        let first = auth.login("capped", "pw").await.unwrap();
+        assert_eq!(auth.login("capped", "pw").await.err(), Some(AuthError::TooManyConnections));
+        // A reload keeps the count
+        auth.set_users(Users::new(vec![User { max_connections: Some(1), ..User::new("capped", Credential::Plain("pw".into())) }]).unwrap());
+        assert_eq!(auth.login("capped", "pw").await.err(), Some(AuthError::TooManyConnections));
+        drop(first);
+        assert!(auth.login("capped", "pw").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn users_changed_at_runtime() {
+        let auth = auth(vec![User::new("alice", Credential::Plain("pw".into()))]);
+        let login = auth.login("alice", "pw").await.unwrap();
+        assert!(!auth.upsert_user(User::new("bob", Credential::Plain("pw".into()))));
+        assert!(auth.upsert_user(User::new("alice", Credential::Plain("new".into()))));
+        assert_eq!(auth.login("alice", "pw").await.err(), Some(AuthError::BadPassword));
+
+        assert!(auth.set_enabled("bob", false));
+        assert_eq!(auth.login("bob", "pw").await.err(), Some(AuthError::Disabled));
+        assert!(auth.remove_user("bob"));
+        assert!(!auth.remove_user("bob"));
+        assert!(!auth.set_enabled("bob", true));
+
+        let users: Vec<(String, usize)> = auth.users().iter().map(|(user, open)| (user.name.clone(), *open)).collect();
+        assert_eq!(users, vec![("alice".to_string(), 1)]);
+        drop(login);
+    }
+
+    #[test]
+    fn htpasswd_accepts_only_bcrypt_and_argon2() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let users = parse_htpasswd(&format!("# team a\n\nalice:{}\n", hash)).unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, "alice");
+
+        let err = parse_htpasswd("alice:$apr1$abc$def\n").err().unwrap();
+        assert!(err.starts_with("1: unsupported hash"), "{}", err);
+        assert!(Users::new(vec![User::new("a", Credential::Plain("x".into())), User::new("a", Credential::Plain("y".into()))]).is_err());
+    }
+}
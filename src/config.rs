@@ -0,0 +1,1222 @@
+use cidr::{Ipv4Cidr, Ipv6Cidr};
+use arc_swap::ArcSwap;
+use getopts::Options;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::acl::{Acl, DestinationAcl};
+use crate::auth::{load_htpasswd, Credential, User, Users};
+use crate::forward::ForwardMapping;
+use crate::limit::Limits;
+use crate::logging::{self, LogFormat};
+use crate::nat64::{Nat64Prefix, WELL_KNOWN_PREFIX};
+use crate::pool::Subnets;
+use crate::resolver::Upstream;
+use crate::routing::{Route, Routes};
+use crate::strategy::Strategy;
+
+const DEFAULT_HTTP_BIND: &str = "0.0.0.0:51080";
+const DEFAULT_SOCKS5_BIND: &str = "127.0.0.1:51081";
+
+/// The configuration file as written, before validation. Every field is optional so a file
+/// only needs what differs from the defaults; command-line flags are applied on top of it.
+///
+/// ```toml
+/// ipv6_subnets = ["2001:db8::/48"]
+/// strategy = "per-client"
+///
+/// [[http]]
+/// bind = "0.0.0.0:51080"
+/// allowed_ips = ["deny 10.1.0.0/16", "10.0.0.0/8"]
+///
+/// [[forward]]
+/// local = "127.0.0.1:8443"
+/// remote = "example.com:443"
+/// sni_host = "example.com"
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub ipv6_subnets: Vec<String>,
+    pub ipv4_subnets: Vec<String>,
+    /// Client rules, see `Acl`: `allow <cidr>`, `deny <cidr>` or a bare `<cidr>`
+    pub allowed_ips: Option<Vec<String>>,
+    /// Destination rules, see `DestinationAcl`: `<allow|deny> <target> [ports]`
+    pub destinations: Vec<String>,
+    /// Deny private, loopback and link-local destinations not explicitly allowed (default true)
+    pub block_private_destinations: Option<bool>,
+    /// Egress subnets by destination host, see `Routes`; the first match wins
+    pub routes: Vec<RouteConfig>,
+    /// Shorthand for a single user with a plain-text password, as `-u`/`-p`
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub users: Vec<UserConfig>,
+    /// htpasswd-style file of `name:hash` lines, bcrypt or argon2
+    pub users_file: Option<PathBuf>,
+    /// Seconds
+    pub timeout: Option<u64>,
+    /// Seconds
+    pub session_ttl: Option<u64>,
+    /// Seconds
+    pub shutdown_timeout: Option<u64>,
+    /// Seconds a SOCKS5 UDP association may go without traffic (default 60)
+    pub udp_idle_timeout: Option<u64>,
+    /// Try IPv6 first when a target has addresses of both families (default: the resolver's order)
+    pub prefer_ipv6: Option<bool>,
+    /// Reach IPv4-only targets through this NAT64 prefix when the pool has no IPv4 subnets,
+    /// e.g. `64:ff9b::/96`
+    pub nat64_prefix: Option<String>,
+    pub strategy: Option<String>,
+    pub hash_secret: Option<String>,
+    /// Address of the admin endpoint, e.g. `127.0.0.1:51082`
+    pub admin: Option<String>,
+    /// Bearer token the admin endpoint requires
+    pub admin_token: Option<String>,
+    /// Serve the admin endpoint without a token; only allowed on a loopback address
+    pub admin_no_token: bool,
+    /// Address of the Prometheus metrics endpoint, e.g. `127.0.0.1:9090`
+    pub metrics: Option<String>,
+    /// Level or filter directives such as `warn,http_proxy_ipv6_pool::proxy=debug` (default info)
+    pub log_level: Option<String>,
+    /// `text` (default) or `json`
+    pub log_format: Option<String>,
+    /// One line per finished request or tunnel, reopened on SIGUSR1
+    pub access_log: Option<PathBuf>,
+    /// For the whole proxy
+    pub limits: LimitsConfig,
+    /// For each client address
+    pub client_limits: LimitsConfig,
+    pub system_route: Option<SystemRouteConfig>,
+    pub dns: DnsConfig,
+    pub http: Vec<ListenerConfig>,
+    pub socks5: Vec<ListenerConfig>,
+    /// HTTP, SOCKS5 and SOCKS4 on one port
+    pub mixed: Vec<ListenerConfig>,
+    pub forward: Vec<ForwardConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemRouteConfig {
+    pub interface: String,
+    pub gateway: Option<String>,
+    /// Seconds
+    pub lease_ttl: Option<u64>,
+    pub max_addresses: Option<usize>,
+    pub lease_file: Option<PathBuf>,
+    pub prune_stale: bool,
+}
+
+/// The resolver used for HTTP and SOCKS targets.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// See `Upstream`, e.g. `udp://1.1.1.1`, `tls://1.1.1.1#cloudflare-dns.com`; the system's
+    /// servers if empty
+    pub servers: Vec<String>,
+    /// Cached names (default 1024)
+    pub cache_size: Option<usize>,
+    /// Seconds, bounds for how long answers are cached regardless of their TTL
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+    /// Seconds a failed lookup is cached, the zone's SOA minimum if unset
+    pub negative_ttl: Option<u64>,
+    /// Send queries from a pool address of the server's family
+    pub from_pool: bool,
+    /// Names answered without asking DNS, e.g. `"db.internal" = ["10.0.0.5"]`
+    pub hosts: HashMap<String, Vec<String>>,
+}
+
+/// A proxy user. Set either `password` (plain text) or `password_hash` (bcrypt or argon2).
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_hash: Option<String>,
+    /// Defaults to true
+    pub enabled: Option<bool>,
+    pub max_connections: Option<usize>,
+    /// Must lie inside the top-level subnets; a family left unset uses all of them
+    pub ipv6_subnets: Option<Vec<String>>,
+    pub ipv4_subnets: Option<Vec<String>>,
+    pub limits: LimitsConfig,
+}
+
+/// Token-bucket limits, see `Limiter`; unset fields are unlimited.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// HTTP requests, SOCKS commands and forwarded requests
+    pub requests_per_second: Option<f64>,
+    /// Accepted connections; for a user, the tunnels it opens
+    pub connections_per_second: Option<f64>,
+    /// Open CONNECT, SOCKS and UDP tunnels
+    pub max_tunnels: Option<usize>,
+    pub upload_bytes_per_second: Option<u64>,
+    pub download_bytes_per_second: Option<u64>,
+}
+
+impl LimitsConfig {
+    fn build(&self, prefix: &str) -> Result<Limits, String> {
+        for (name, rate) in [("requests_per_second", self.requests_per_second), ("connections_per_second", self.connections_per_second)] {
+            if rate.is_some_and(|rate| !(rate > 0.0 && rate.is_finite())) {
+                return Err(format!("{}.{}: must be a positive number", prefix, name));
+            }
+        }
+        for (name, rate) in [("upload_bytes_per_second", self.upload_bytes_per_second), ("download_bytes_per_second", self.download_bytes_per_second)] {
+            if rate == Some(0) {
+                return Err(format!("{}.{}: must be positive", prefix, name));
+            }
+        }
+        Ok(Limits {
+            requests_per_second: self.requests_per_second,
+            connections_per_second: self.connections_per_second,
+            max_tunnels: self.max_tunnels,
+            upload_bytes_per_second: self.upload_bytes_per_second,
+            download_bytes_per_second: self.download_bytes_per_second,
+        })
+    }
+}
+
+impl UserConfig {
+    /// Checks the entry and turns it into a `User`; `prefix` names it in errors, e.g. `users[2]`.
+    /// Its subnets must lie inside the pool's.
+    pub fn build(&self, prefix: &str, ipv6_subnets: &[Ipv6Cidr], ipv4_subnets: &[Ipv4Cidr]) -> Result<User, String> {
+        let field = |name: &str| format!("{}.{}", prefix, name);
+        if self.username.is_empty() {
+            return Err(format!("{}: missing", field("username")));
+        }
+        let credential = match (&self.password, &self.password_hash) {
+            (Some(password), None) => Credential::Plain(password.clone()),
+            (None, Some(hash)) => Credential::parse_hash(hash).map_err(|e| format!("{}: {}", field("password_hash"), e))?,
+            _ => return Err(format!("{}: set exactly one of password and password_hash", prefix)),
+        };
+        let subnets = if self.ipv6_subnets.is_none() && self.ipv4_subnets.is_none() {
+            None
+        } else {
+            Some(Subnets {
+                ipv6: user_subnets(&field("ipv6_subnets"), self.ipv6_subnets.as_deref(), ipv6_subnets)?,
+                ipv4: user_subnets(&field("ipv4_subnets"), self.ipv4_subnets.as_deref(), ipv4_subnets)?,
+            })
+        };
+        Ok(User {
+            name: self.username.clone(),
+            credential,
+            enabled: self.enabled.unwrap_or(true),
+            max_connections: self.max_connections,
+            subnets,
+            limits: self.limits.build(&field("limits"))?,
+        })
+    }
+}
+
+/// Destinations matching any of `hosts` leave from these subnets, which must lie inside the
+/// top-level ones; a family left unset uses all of them.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    /// `example.com`, `.example.com` for the domain and its subdomains, or a glob
+    pub hosts: Vec<String>,
+    pub ipv6_subnets: Option<Vec<String>>,
+    pub ipv4_subnets: Option<Vec<String>>,
+}
+
+/// An HTTP, SOCKS5 or mixed listener. Unset fields fall back to the top-level values.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub bind: String,
+    pub strategy: Option<String>,
+    pub allowed_ips: Option<Vec<String>>,
+    pub destinations: Option<Vec<String>>,
+}
+
+/// A forward mapping, the structured form of `--forward`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    pub local: String,
+    pub remote: String,
+    pub sni_host: String,
+    pub proxies: Vec<String>,
+    pub proxy_type: Option<String>,
+    pub strategy: Option<String>,
+    pub allowed_ips: Option<Vec<String>>,
+}
+
+/// The validated configuration the daemon runs with.
+pub struct Settings {
+    pub ipv6_subnets: Vec<Ipv6Cidr>,
+    pub ipv4_subnets: Vec<Ipv4Cidr>,
+    pub timeout: Duration,
+    pub session_ttl: Duration,
+    pub shutdown_timeout: Duration,
+    pub hash_secret: Option<String>,
+    pub admin: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    pub metrics: Option<SocketAddr>,
+    /// Read once at startup, a reload doesn't change it
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub access_log: Option<PathBuf>,
+    /// Shared by the HTTP and SOCKS5 listeners, authentication is off when empty
+    pub users: Users,
+    pub limits: Limits,
+    pub client_limits: Limits,
+    pub system_route: Option<SystemRouteSettings>,
+    pub dns: DnsSettings,
+    pub http: Vec<Listener>,
+    pub socks5: Vec<Listener>,
+    pub mixed: Vec<Listener>,
+    pub forward: Vec<Forward>,
+}
+
+pub struct SystemRouteSettings {
+    pub interface: String,
+    pub gateway: String,
+    pub lease_ttl: Duration,
+    pub max_addresses: usize,
+    pub lease_file: Option<PathBuf>,
+    pub prune_stale: bool,
+}
+
+pub struct DnsSettings {
+    pub servers: Vec<Upstream>,
+    pub cache_size: usize,
+    pub min_ttl: Option<Duration>,
+    pub max_ttl: Option<Duration>,
+    pub negative_ttl: Option<Duration>,
+    pub from_pool: bool,
+    /// Lowercase names without the trailing dot
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+pub struct Listener {
+    pub bind: SocketAddr,
+    pub strategy: Strategy,
+    pub acl: Acl,
+    pub destinations: DestinationAcl,
+    pub udp_idle_timeout: Duration,
+    pub prefer_ipv6: bool,
+    pub nat64: Option<Nat64Prefix>,
+    pub routes: Routes,
+}
+
+impl Listener {
+    /// A listener on `127.0.0.1:0` with the defaults and private destinations allowed, for
+    /// tests that connect to loopback.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        Listener {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            strategy: Strategy::Random,
+            acl: Default::default(),
+            destinations: DestinationAcl::new(Vec::new(), false),
+            udp_idle_timeout: Duration::from_secs(5),
+            prefer_ipv6: false,
+            nat64: None,
+            routes: Default::default(),
+        }
+    }
+}
+
+pub struct Forward {
+    pub mapping: ForwardMapping,
+    pub strategy: Strategy,
+    pub acl: Acl,
+    pub routes: Routes,
+}
+
+/// A listener's current settings; reloading swaps in new ones for the connections that follow.
+pub type SharedListener = Arc<ArcSwap<Listener>>;
+pub type SharedForward = Arc<ArcSwap<Forward>>;
+
+impl Config {
+    /// Reads a YAML file if the extension is `.yaml` or `.yml`, TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let is_yaml = matches!(path.extension().and_then(|ext| ext.to_str()), Some("yaml" | "yml"));
+        let parsed = if is_yaml {
+            serde_yaml::from_str(&content).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&content).map_err(|e| e.to_string())
+        };
+        parsed.map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Checks every entry and resolves listener settings against the top-level defaults.
+    /// The first invalid entry is reported together with where it was found.
+    pub fn validate(self) -> Result<Settings, String> {
+        let strategy = match &self.strategy {
+            Some(s) => parse_field("strategy", s)?,
+            None => Strategy::Random,
+        };
+        let acl = parse_acl("allowed_ips", self.allowed_ips.as_deref())?.unwrap_or_default();
+        let block_private = self.block_private_destinations.unwrap_or(true);
+        let destinations = DestinationAcl::new(parse_list("destinations", &self.destinations)?, block_private);
+        let ipv6_subnets: Vec<Ipv6Cidr> = parse_list("ipv6_subnets", &self.ipv6_subnets)?;
+        let ipv4_subnets: Vec<Ipv4Cidr> = parse_list("ipv4_subnets", &self.ipv4_subnets)?;
+        let users = self.build_users(&ipv6_subnets, &ipv4_subnets)?;
+        let routes = self.build_routes(&ipv6_subnets, &ipv4_subnets)?;
+        let nat64 = self.nat64_prefix.as_deref().map(|prefix| parse_field("nat64_prefix", prefix)).transpose()?;
+
+        let listener = |kind: &str, i: usize, listener: &ListenerConfig| -> Result<Listener, String> {
+            let field = |name: &str| format!("{}[{}].{}", kind, i, name);
+            Ok(Listener {
+                bind: parse_field(&field("bind"), &listener.bind)?,
+                strategy: match &listener.strategy {
+                    Some(s) => parse_field(&field("strategy"), s)?,
+                    None => strategy,
+                },
+                acl: match &listener.allowed_ips {
+                    Some(rules) => Acl::new(parse_list(&field("allowed_ips"), rules)?),
+                    None => acl.clone(),
+                },
+                destinations: match &listener.destinations {
+                    Some(rules) => DestinationAcl::new(parse_list(&field("destinations"), rules)?, block_private),
+                    None => destinations.clone(),
+                }
+                .with_nat64(nat64),
+                udp_idle_timeout: Duration::from_secs(self.udp_idle_timeout.unwrap_or(60)),
+                prefer_ipv6: self.prefer_ipv6.unwrap_or(false),
+                nat64,
+                routes: routes.clone(),
+            })
+        };
+        let http = self.http.iter().enumerate().map(|(i, l)| listener("http", i, l)).collect::<Result<_, _>>()?;
+        let socks5 = self.socks5.iter().enumerate().map(|(i, l)| listener("socks5", i, l)).collect::<Result<_, _>>()?;
+        let mixed = self.mixed.iter().enumerate().map(|(i, l)| listener("mixed", i, l)).collect::<Result<_, _>>()?;
+
+        let forward = self
+            .forward
+            .iter()
+            .enumerate()
+            .map(|(i, forward)| {
+                let field = |name: &str| format!("forward[{}].{}", i, name);
+                Ok(Forward {
+                    mapping: ForwardMapping::new(
+                        &forward.local,
+                        &forward.remote,
+                        &forward.sni_host,
+                        forward.proxies.clone(),
+                        forward.proxy_type.as_deref(),
+                    )
+                    .map_err(|e| format!("forward[{}]: {}", i, e))?,
+                    strategy: match &forward.strategy {
+                        Some(s) => parse_field(&field("strategy"), s)?,
+                        None => strategy,
+                    },
+                    acl: match &forward.allowed_ips {
+                        Some(rules) => Acl::new(parse_list(&field("allowed_ips"), rules)?),
+                        None => acl.clone(),
+                    },
+                    routes: routes.clone(),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let dns = DnsSettings {
+            servers: parse_list("dns.servers", &self.dns.servers)?,
+            cache_size: self.dns.cache_size.unwrap_or(1024),
+            min_ttl: self.dns.min_ttl.map(Duration::from_secs),
+            max_ttl: self.dns.max_ttl.map(Duration::from_secs),
+            negative_ttl: self.dns.negative_ttl.map(Duration::from_secs),
+            from_pool: self.dns.from_pool,
+            hosts: self
+                .dns
+                .hosts
+                .iter()
+                .map(|(name, ips)| {
+                    let ips = parse_list(&format!("dns.hosts.{}", name), ips)?;
+                    Ok((name.trim_end_matches('.').to_ascii_lowercase(), ips))
+                })
+                .collect::<Result<_, String>>()?,
+        };
+
+        let system_route = match self.system_route {
+            None => None,
+            Some(route) if route.interface.is_empty() => {
+                // Settings for system-route mode without the interface that turns it on
+                let set = [
+                    ("gateway", route.gateway.is_some()),
+                    ("lease_ttl", route.lease_ttl.is_some()),
+                    ("max_addresses", route.max_addresses.is_some()),
+                    ("lease_file", route.lease_file.is_some()),
+                    ("prune_stale", route.prune_stale),
+                ];
+                if let Some((name, _)) = set.iter().find(|(_, set)| *set) {
+                    return Err(format!("system_route.{} requires system_route.interface", name));
+                }
+                None
+            }
+            Some(route) => Some(SystemRouteSettings {
+                interface: route.interface,
+                gateway: route.gateway.unwrap_or_default(),
+                lease_ttl: Duration::from_secs(route.lease_ttl.unwrap_or(300)),
+                max_addresses: route.max_addresses.unwrap_or(1000),
+                lease_file: route.lease_file,
+                prune_stale: route.prune_stale,
+            }),
+        };
+
+        let log_level = self.log_level.unwrap_or_else(|| "info".to_string());
+        logging::parse_filter(&log_level).map_err(|e| format!("log_level: {}", e))?;
+        let log_format = self.log_format.as_deref().map(|format| parse_field("log_format", format)).transpose()?.unwrap_or_default();
+
+        let admin: Option<SocketAddr> = self.admin.as_deref().map(|admin| parse_field("admin", admin)).transpose()?;
+        let admin_token = self.admin_token.filter(|token| !token.is_empty());
+        match admin {
+            Some(_) if admin_token.is_none() && !self.admin_no_token => {
+                return Err("admin_token: required by the admin endpoint".to_string());
+            }
+            Some(addr) if admin_token.is_none() && !addr.ip().is_loopback() => {
+                return Err(format!("admin_no_token: only allowed on a loopback address, not {}", addr));
+            }
+            _ => {}
+        }
+
+        Ok(Settings {
+            ipv6_subnets,
+            ipv4_subnets,
+            timeout: Duration::from_secs(self.timeout.unwrap_or(5)),
+            session_ttl: Duration::from_secs(self.session_ttl.unwrap_or(600)),
+            shutdown_timeout: Duration::from_secs(self.shutdown_timeout.unwrap_or(30)),
+            hash_secret: self.hash_secret,
+            admin,
+            admin_token,
+            metrics: self.metrics.as_deref().map(|metrics| parse_field("metrics", metrics)).transpose()?,
+            log_level,
+            log_format,
+            access_log: self.access_log,
+            users,
+            limits: self.limits.build("limits")?,
+            client_limits: self.client_limits.build("client_limits")?,
+            system_route,
+            dns,
+            http,
+            socks5,
+            mixed,
+            forward,
+        })
+    }
+
+    /// Builds the user table from `username`/`password`, `users_file` and `[[users]]`, in that
+    /// order. Each user's subnets are checked against the pool's.
+    fn build_users(&self, ipv6_subnets: &[Ipv6Cidr], ipv4_subnets: &[Ipv4Cidr]) -> Result<Users, String> {
+        let mut users = Vec::new();
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            if !username.is_empty() && !password.is_empty() {
+                users.push(User::new(username, Credential::Plain(password.clone())));
+            }
+        }
+        if let Some(path) = &self.users_file {
+            users.extend(load_htpasswd(path)?);
+        }
+
+        for (i, user) in self.users.iter().enumerate() {
+            users.push(user.build(&format!("users[{}]", i), ipv6_subnets, ipv4_subnets)?);
+        }
+        Users::new(users)
+    }
+
+    /// Checks the routing rules; their subnets must lie inside the pool's, like users'.
+    fn build_routes(&self, ipv6_subnets: &[Ipv6Cidr], ipv4_subnets: &[Ipv4Cidr]) -> Result<Routes, String> {
+        let routes = self
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(i, route)| {
+                let field = |name: &str| format!("routes[{}].{}", i, name);
+                if route.hosts.is_empty() {
+                    return Err(format!("{}: missing", field("hosts")));
+                }
+                Ok(Route {
+                    hosts: parse_list(&field("hosts"), &route.hosts)?,
+                    subnets: Subnets {
+                        ipv6: user_subnets(&field("ipv6_subnets"), route.ipv6_subnets.as_deref(), ipv6_subnets)?,
+                        ipv4: user_subnets(&field("ipv4_subnets"), route.ipv4_subnets.as_deref(), ipv4_subnets)?,
+                    },
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Routes::new(routes))
+    }
+}
+
+/// A user's or route's subnets of one family: each must lie inside one of the pool's, and leaving them
+/// unset means all of the pool's.
+fn user_subnets<C>(field: &str, values: Option<&[String]>, pool: &[C]) -> Result<Vec<C>, String>
+where
+    C: cidr::Cidr + FromStr + Clone + Display,
+    C::Err: Display,
+{
+    let Some(values) = values else {
+        return Ok(pool.to_vec());
+    };
+    let subnets: Vec<C> = parse_list(field, values)?;
+    for subnet in &subnets {
+        let inside = pool
+            .iter()
+            .any(|outer| outer.network_length() <= subnet.network_length() && outer.contains(&subnet.first_address()));
+        if !inside {
+            return Err(format!("{}: {} is not inside any of the pool's subnets", field, subnet));
+        }
+    }
+    Ok(subnets)
+}
+
+impl RouteConfig {
+    /// Parses the `--route` format: `host1,host2,...=subnet1,subnet2,...`, IPv6 and IPv4 mixed.
+    pub fn parse(route_str: &str) -> Result<Self, String> {
+        let Some((hosts, subnets)) = route_str.split_once('=') else {
+            return Err(format!("Invalid route: {}", route_str));
+        };
+        let (ipv6, ipv4): (Vec<String>, Vec<String>) = split_list(subnets).into_iter().partition(|subnet| subnet.contains(':'));
+        Ok(RouteConfig {
+            hosts: split_list(hosts),
+            ipv6_subnets: Some(ipv6).filter(|subnets| !subnets.is_empty()),
+            ipv4_subnets: Some(ipv4).filter(|subnets| !subnets.is_empty()),
+        })
+    }
+}
+
+impl ForwardConfig {
+    /// Parses the `--forward` format: `local_addr,remote_addr,sni_host[,proxy1|proxy2|...,proxy_type]`.
+    pub fn parse(mapping_str: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = mapping_str.split(',').collect();
+        if parts.len() < 3 || parts.len() > 5 {
+            return Err(format!("Invalid forward mapping: {}", mapping_str));
+        }
+        Ok(ForwardConfig {
+            local: parts[0].to_string(),
+            remote: parts[1].to_string(),
+            sni_host: parts[2].to_string(),
+            proxies: parts.get(3).map(|list| list.split('|').map(str::to_string).collect()).unwrap_or_default(),
+            proxy_type: parts.get(4).map(|s| s.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+/// Command-line options; every one of them overrides the matching configuration file entry.
+pub fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("b", "bind", "HTTP proxy bind address", "BIND");
+    opts.optopt(
+        "i",
+        "ipv6-subnets",
+        "Comma-separated list of IPv6 subnets (e.g., 2001:19f0:6001:48e4::/64,2001:19f0:6001:48e5::/64)",
+        "IPv6_SUBNETS",
+    );
+    opts.optopt(
+        "v",
+        "ipv4-subnets",
+        "Comma-separated list of IPv4 subnets (e.g., 192.168.0.0/24,192.168.1.0/24)",
+        "IPv4_SUBNETS",
+    );
+    opts.optopt(
+        "a",
+        "allowed-ips",
+        "Comma-separated client rules, checked in order: CIDR or address to allow, `deny <CIDR>` to deny",
+        "ALLOWED_IPS",
+    );
+    opts.optopt(
+        "S",
+        "socks5",
+        "SOCKS5 proxy bind address (e.g., 127.0.0.1:51081)",
+        "SOCKS5_ADDR",
+    );
+    opts.optopt(
+        "M",
+        "mixed",
+        "Bind address for HTTP, SOCKS5 and SOCKS4 on a single port (e.g., 0.0.0.0:51080)",
+        "MIXED_ADDR",
+    );
+    opts.optopt(
+        "",
+        "destinations",
+        "Comma-separated destination rules, checked in order: `<allow|deny> <host glob|CIDR|*> [port[-port]]`",
+        "RULES",
+    );
+    opts.optmulti(
+        "",
+        "route",
+        "Egress subnets for destination hosts, first match wins (e.g., api.example.com,.example.org=2001:db8:0:7::/64); repeatable",
+        "HOSTS=SUBNETS",
+    );
+    opts.optflag(
+        "",
+        "allow-private-destinations",
+        "Allow loopback, private and link-local destinations, denied by default",
+    );
+    opts.optopt(
+        "",
+        "dns",
+        "Comma-separated DNS servers, [udp|tcp|tls|https]://<ip>[:port][#<tls name>] (default: the system's)",
+        "SERVERS",
+    );
+    opts.optflag("", "dns-from-pool", "Send DNS queries from a pool address of the server's family");
+    opts.optflag(
+        "",
+        "prefer-ipv6",
+        "Try IPv6 first for targets with addresses of both families, instead of the resolver's order",
+    );
+    opts.optflagopt(
+        "",
+        "nat64",
+        &format!("Reach IPv4-only targets through a NAT64 prefix when the pool has no IPv4 subnets (default {})", WELL_KNOWN_PREFIX),
+        "PREFIX",
+    );
+    opts.optopt("u", "username", "Username for proxy authentication", "USERNAME");
+    opts.optopt("p", "password", "Password for proxy authentication", "PASSWORD");
+    opts.optopt(
+        "",
+        "users-file",
+        "htpasswd-style file of proxy users with bcrypt or argon2 hashes",
+        "FILE",
+    );
+    opts.optopt("t", "timeout", "Timeout duration in seconds", "TIMEOUT");  // 新增-t参数
+    opts.optopt(
+        "",
+        "session-ttl",
+        "Seconds a `user-session-<id>` keeps its egress address after its last use (default 600)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "strategy",
+        "Egress address selection: random, per-client, per-target or per-client-target (default random)",
+        "STRATEGY",
+    );
+    opts.optopt("", "http-strategy", "Selection strategy for the HTTP proxy, overrides --strategy", "STRATEGY");
+    opts.optopt("", "socks5-strategy", "Selection strategy for the SOCKS5 proxy, overrides --strategy", "STRATEGY");
+    opts.optopt("", "forward-strategy", "Selection strategy for forward mappings, overrides --strategy", "STRATEGY");
+    opts.optopt(
+        "",
+        "hash-secret",
+        "Secret key for hash-based strategies, keep it fixed so addresses survive restarts",
+        "SECRET",
+    );
+    opts.optopt(
+        "",
+        "lease-ttl",
+        "Seconds an unused system-route address stays on the interface (default 300)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "max-addresses",
+        "Idle system-route addresses are removed early once more than this many are added (default 1000)",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "lease-file",
+        "File recording system-route addresses, so the next start can remove those left behind",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "prune-stale",
+        "On start, remove single-host addresses inside the subnets that are on the system-route interface but not in use",
+    );
+    opts.optopt(
+        "",
+        "shutdown-timeout",
+        "Seconds to wait for open tunnels on SIGINT/SIGTERM before removing addresses (default 30)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "udp-idle-timeout",
+        "Seconds a SOCKS5 UDP association may go without traffic before it is closed (default 60)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "admin",
+        "Admin API bind address (e.g., 127.0.0.1:51082): connections, pool, users, drain mode and POST /reload",
+        "ADMIN_ADDR",
+    );
+    opts.optopt("", "admin-token", "Require Authorization: Bearer TOKEN on the admin endpoint", "TOKEN");
+    opts.optflag("", "admin-no-token", "Serve the admin endpoint without a token, only on a loopback address");
+    opts.optopt("", "metrics", "Prometheus metrics bind address (e.g., 127.0.0.1:9090), served at GET /metrics", "METRICS_ADDR");
+    opts.optopt("", "log-level", "Log level or filter directives, e.g. warn or info,http_proxy_ipv6_pool::proxy=debug (default info)", "LEVEL");
+    opts.optopt("", "log-format", "Log output format: text (default) or json", "FORMAT");
+    opts.optopt("", "access-log", "Write one line per finished request or tunnel to FILE, reopened on SIGUSR1", "FILE");
+    opts.optopt(
+        "c",
+        "config",
+        "TOML or YAML configuration file (.yaml/.yml), command-line options override it",
+        "FILE",
+    );
+    opts.optflag("h", "help", "Print this help menu");
+    opts.optopt("r", "system_route", "Whether to use system routing instead of ndpdd. (Provide network card interface, such as eth0)", "Network Interface");
+    opts.optopt("g", "gateway", "Some service providers need to track the route before it takes effect.", "Gateway");
+
+
+    // 新增的 --forward 参数
+    opts.optmulti(
+        "",
+        "forward",
+        "Forwarding mapping in the format local_addr,remote_addr,sni_host[,proxy_addr1|proxy_addr2|...,proxy_type]",
+        "FORWARD",
+    );
+    opts
+}
+
+/// Reads the `-c` configuration file, if any, and applies the other command-line options on
+/// top of it. Used at startup and again on every reload.
+pub fn load_settings(matches: &getopts::Matches) -> Result<Settings, String> {
+    let mut config = match matches.opt_str("c") {
+        Some(path) => Config::load(Path::new(&path))?,
+        // A single port was asked for, so the default listeners aren't started next to it
+        None if matches.opt_present("M") => Config::default(),
+        // Without a config file, keep the historical default listeners
+        None => Config {
+            http: vec![ListenerConfig { bind: DEFAULT_HTTP_BIND.to_string(), ..Default::default() }],
+            socks5: vec![ListenerConfig { bind: DEFAULT_SOCKS5_BIND.to_string(), ..Default::default() }],
+            ..Default::default()
+        },
+    };
+    apply_overrides(&mut config, matches)
+        .and_then(|_| config.validate())
+        .map_err(|e| format!("Invalid configuration: {}", e))
+}
+
+/// Applies the command-line options on top of the configuration file.
+fn apply_overrides(config: &mut Config, matches: &getopts::Matches) -> Result<(), String> {
+    if let Some(subnets) = matches.opt_str("i") {
+        config.ipv6_subnets = split_list(&subnets);
+    }
+    if let Some(subnets) = matches.opt_str("v") {
+        config.ipv4_subnets = split_list(&subnets);
+    }
+    if let Some(ips) = matches.opt_str("a") {
+        config.allowed_ips = Some(split_list(&ips));
+    }
+    if let Some(username) = matches.opt_str("u") {
+        config.username = Some(username);
+    }
+    if let Some(password) = matches.opt_str("p") {
+        config.password = Some(password);
+    }
+    if let Some(rules) = matches.opt_str("destinations") {
+        config.destinations = split_list(&rules);
+    }
+    if matches.opt_present("prefer-ipv6") {
+        config.prefer_ipv6 = Some(true);
+    }
+    if matches.opt_present("nat64") {
+        config.nat64_prefix = Some(matches.opt_str("nat64").unwrap_or_else(|| WELL_KNOWN_PREFIX.to_string()));
+    }
+    if matches.opt_present("allow-private-destinations") {
+        config.block_private_destinations = Some(false);
+    }
+    if let Some(servers) = matches.opt_str("dns") {
+        config.dns.servers = split_list(&servers);
+    }
+    config.dns.from_pool |= matches.opt_present("dns-from-pool");
+    if let Some(path) = matches.opt_str("users-file") {
+        config.users_file = Some(PathBuf::from(path));
+    }
+    if let Some(secret) = matches.opt_str("hash-secret") {
+        config.hash_secret = Some(secret);
+    }
+    if let Some(strategy) = matches.opt_str("strategy") {
+        config.strategy = Some(strategy);
+    }
+    if let Some(admin) = matches.opt_str("admin") {
+        config.admin = Some(admin);
+    }
+    if let Some(token) = matches.opt_str("admin-token") {
+        config.admin_token = Some(token);
+    }
+    config.admin_no_token |= matches.opt_present("admin-no-token");
+    if let Some(metrics) = matches.opt_str("metrics") {
+        config.metrics = Some(metrics);
+    }
+    if let Some(level) = matches.opt_str("log-level") {
+        config.log_level = Some(level);
+    }
+    if let Some(format) = matches.opt_str("log-format") {
+        config.log_format = Some(format);
+    }
+    if let Some(path) = matches.opt_str("access-log") {
+        config.access_log = Some(PathBuf::from(path));
+    }
+    config.timeout = parse_number(matches, "t")?.or(config.timeout);
+    config.session_ttl = parse_number(matches, "session-ttl")?.or(config.session_ttl);
+    config.shutdown_timeout = parse_number(matches, "shutdown-timeout")?.or(config.shutdown_timeout);
+    config.udp_idle_timeout = parse_number(matches, "udp-idle-timeout")?.or(config.udp_idle_timeout);
+
+    // -b/-S/-M replace the first listener of their kind, or add one
+    for (name, listeners) in [("b", &mut config.http), ("S", &mut config.socks5), ("M", &mut config.mixed)] {
+        if let Some(bind) = matches.opt_str(name) {
+            match listeners.first_mut() {
+                Some(listener) => listener.bind = bind,
+                None => listeners.push(ListenerConfig { bind, ..Default::default() }),
+            }
+        }
+    }
+    let routes = matches.opt_strs("route");
+    if !routes.is_empty() {
+        config.routes = routes.iter().map(|route| RouteConfig::parse(route)).collect::<Result<_, _>>()?;
+    }
+    for mapping in matches.opt_strs("forward") {
+        config.forward.push(ForwardConfig::parse(&mapping)?);
+    }
+    for listener in &mut config.http {
+        listener.strategy = matches.opt_str("http-strategy").or(listener.strategy.take());
+    }
+    for listener in &mut config.socks5 {
+        listener.strategy = matches.opt_str("socks5-strategy").or(listener.strategy.take());
+    }
+    for forward in &mut config.forward {
+        forward.strategy = matches.opt_str("forward-strategy").or(forward.strategy.take());
+    }
+
+    let route_options = ["r", "g", "lease-ttl", "max-addresses", "lease-file", "prune-stale"];
+    if route_options.iter().any(|name| matches.opt_present(name)) {
+        let route = config.system_route.get_or_insert_with(Default::default);
+        if let Some(interface) = matches.opt_str("r") {
+            route.interface = interface;
+        }
+        if let Some(gateway) = matches.opt_str("g") {
+            route.gateway = Some(gateway);
+        }
+        if let Some(lease_file) = matches.opt_str("lease-file") {
+            route.lease_file = Some(PathBuf::from(lease_file));
+        }
+        route.lease_ttl = parse_number(matches, "lease-ttl")?.or(route.lease_ttl);
+        route.max_addresses = parse_number(matches, "max-addresses")?.or(route.max_addresses);
+        route.prune_stale |= matches.opt_present("prune-stale");
+    }
+    Ok(())
+}
+
+fn parse_number<T: std::str::FromStr>(matches: &getopts::Matches, name: &str) -> Result<Option<T>, String> {
+    matches
+        .opt_str(name)
+        .map(|value| value.parse().map_err(|_| format!("-{}{}: invalid number '{}'", if name.len() > 1 { "-" } else { "" }, name, value)))
+        .transpose()
+}
+
+/// Splits a comma-separated command-line value into config entries.
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+fn parse_field<T: FromStr>(field: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|e| format!("{}: invalid value '{}': {}", field, value, e))
+}
+
+fn parse_list<T: FromStr>(field: &str, values: &[String]) -> Result<Vec<T>, String>
+where
+    T::Err: Display,
+{
+    values.iter().map(|value| parse_field(field, value)).collect()
+}
+
+fn parse_acl(field: &str, values: Option<&[String]>) -> Result<Option<Acl>, String> {
+    values.map(|values| parse_list(field, values).map(Acl::new)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+
+    #[test]
+    fn toml_listeners_inherit_top_level_settings() {
+        let config: Config = toml::from_str(
+            r#"
+            ipv6_subnets = ["2001:db8::/48"]
+            strategy = "per-client"
+            allowed_ips = ["127.0.0.1"]
+
+            [[http]]
+            bind = "0.0.0.0:8080"
+
+            [[http]]
+            bind = "0.0.0.0:8081"
+            strategy = "random"
+            allowed_ips = ["deny 10.0.0.1", "10.0.0.0/8"]
+            "#,
+        )
+        .unwrap();
+        let settings = config.validate().unwrap();
+
+        assert_eq!(settings.ipv6_subnets.len(), 1);
+        assert_eq!(settings.http[0].strategy, Strategy::PerClient);
+        assert!(settings.http[0].acl.allows("127.0.0.1".parse().unwrap()));
+        assert!(!settings.http[0].acl.allows("10.0.0.2".parse().unwrap()));
+        assert_eq!(settings.http[1].strategy, Strategy::Random);
+        assert!(!settings.http[1].acl.allows("10.0.0.1".parse().unwrap()));
+        assert!(settings.http[1].acl.allows("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn dns_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [dns]
+            servers = ["1.1.1.1", "https://1.1.1.1#cloudflare-dns.com"]
+            negative_ttl = 30
+            hosts = { "DB.Internal." = ["10.0.0.5", "fd00::5"] }
+            "#,
+        )
+        .unwrap();
+        let settings = config.validate().unwrap();
+        assert_eq!(settings.dns.servers.len(), 2);
+        assert_eq!(settings.dns.cache_size, 1024);
+        assert_eq!(settings.dns.negative_ttl, Some(Duration::from_secs(30)));
+        assert_eq!(settings.dns.hosts["db.internal"].len(), 2);
+
+        let config: Config = toml::from_str("[dns]\nservers = [\"tls://1.1.1.1\"]").unwrap();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("dns.servers: invalid value"), "{}", err);
+    }
+
+    #[test]
+    fn routes_are_checked_against_the_pool() {
+        let config: Config = toml::from_str(
+            r#"
+            ipv6_subnets = ["2001:db8::/48"]
+            ipv4_subnets = ["198.51.100.0/24"]
+            [[routes]]
+            hosts = ["api.example.com", ".example.org"]
+            ipv6_subnets = ["2001:db8:0:7::/64"]
+            [[http]]
+            bind = "127.0.0.1:51080"
+            "#,
+        )
+        .unwrap();
+        let settings = config.validate().unwrap();
+        let subnets = settings.http[0].routes.subnets_for("www.example.org").unwrap();
+        assert_eq!(subnets.ipv6, vec!["2001:db8:0:7::/64".parse().unwrap()]);
+        assert_eq!(subnets.ipv4, vec!["198.51.100.0/24".parse().unwrap()]);
+
+        let matches = options().parse(["-i", "2001:db8::/48", "--route", "api.example.com=2001:db8:1:2::/64"]).unwrap();
+        let err = load_settings(&matches).err().unwrap();
+        assert!(err.contains("routes[0].ipv6_subnets: 2001:db8:1:2::/64 is not inside"), "{}", err);
+    }
+
+    #[test]
+    fn nat64_prefix() {
+        let matches = options().parse(["-i", "2001:db8::/48", "--nat64"]).unwrap();
+        let settings = load_settings(&matches).unwrap();
+        assert_eq!(settings.http[0].nat64, Some(WELL_KNOWN_PREFIX.parse().unwrap()));
+
+        let matches = options().parse(["-i", "2001:db8::/48", "--nat64=2001:db8:64::/64"]).unwrap();
+        let settings = load_settings(&matches).unwrap();
+        assert_eq!(settings.socks5[0].nat64, Some("2001:db8:64::/64".parse().unwrap()));
+
+        let config: Config = toml::from_str("nat64_prefix = \"64:ff9b::/80\"").unwrap();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("nat64_prefix: invalid value"), "{}", err);
+    }
+
+    #[test]
+    fn log_options() {
+        let matches = options().parse(["--log-level", "warn,http_proxy_ipv6_pool::proxy=debug", "--log-format", "json"]).unwrap();
+        let settings = load_settings(&matches).unwrap();
+        assert_eq!(settings.log_level, "warn,http_proxy_ipv6_pool::proxy=debug");
+        assert_eq!(settings.log_format, LogFormat::Json);
+
+        let config: Config = toml::from_str("log_format = \"logfmt\"").unwrap();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("log_format: invalid value"), "{}", err);
+        let config: Config = toml::from_str("log_level = \"proxy=loud\"").unwrap();
+        assert!(config.validate().err().unwrap().starts_with("log_level: "));
+    }
+
+    #[test]
+    fn system_route_settings_need_an_interface() {
+        let matches = options().parse(["--lease-ttl", "60"]).unwrap();
+        assert_eq!(load_settings(&matches).err().unwrap(), "Invalid configuration: system_route.lease_ttl requires system_route.interface");
+
+        let config: Config = toml::from_str("[system_route]\nprune_stale = true").unwrap();
+        assert_eq!(config.validate().err().unwrap(), "system_route.prune_stale requires system_route.interface");
+
+        let matches = options().parse(["-r", "eth0", "--lease-ttl", "60"]).unwrap();
+        assert_eq!(load_settings(&matches).unwrap().system_route.unwrap().lease_ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn admin_endpoint_needs_a_token() {
+        let config: Config = toml::from_str("admin = \"127.0.0.1:51082\"").unwrap();
+        assert_eq!(config.validate().err().unwrap(), "admin_token: required by the admin endpoint");
+
+        let config: Config = toml::from_str("admin = \"0.0.0.0:51082\"\nadmin_no_token = true").unwrap();
+        assert_eq!(config.validate().err().unwrap(), "admin_no_token: only allowed on a loopback address, not 0.0.0.0:51082");
+
+        let matches = options().parse(["--admin", "[::1]:51082", "--admin-no-token"]).unwrap();
+        assert_eq!(load_settings(&matches).unwrap().admin_token, None);
+        let matches = options().parse(["--admin", "0.0.0.0:51082", "--admin-token", "secret"]).unwrap();
+        assert_eq!(load_settings(&matches).unwrap().admin_token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn yaml_forward_mapping() {
+        let config: Config = serde_yaml::from_str(
+            "
+forward:
+  - local: 127.0.0.1:8443
+    remote: example.com:443
+    sni_host: example.com
+    proxies: [198.51.100.1:3128]
+",
+        )
+        .unwrap();
+        let settings = config.validate().unwrap();
+        assert_eq!(settings.forward[0].mapping.proxy_addrs, vec!["198.51.100.1:3128"]);
+    }
+
+    #[test]
+    fn mixed_port_replaces_default_listeners() {
+        let matches = options().parse(["-M", "127.0.0.1:51080", "-i", "2001:db8::/48"]).unwrap();
+        let settings = load_settings(&matches).unwrap();
+        assert!(settings.http.is_empty() && settings.socks5.is_empty());
+        assert_eq!(settings.mixed[0].bind, "127.0.0.1:51080".parse().unwrap());
+
+        let matches = options().parse(["-M", "127.0.0.1:51090", "-S", "127.0.0.1:51091"]).unwrap();
+        let settings = load_settings(&matches).unwrap();
+        assert!(settings.http.is_empty());
+        assert_eq!(settings.socks5.len(), 1);
+    }
+
+    #[test]
+    fn invalid_entries_are_reported_with_their_location() {
+        let config = Config {
+            ipv6_subnets: vec!["2001:db8::/48".into(), "not-a-subnet".into()],
+            ..Default::default()
+        };
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("ipv6_subnets: invalid value 'not-a-subnet'"), "{}", err);
+
+        let config = Config {
+            socks5: vec![ListenerConfig { bind: "127.0.0.1".into(), ..Default::default() }],
+            ..Default::default()
+        };
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("socks5[0].bind"), "{}", err);
+    }
+
+    #[test]
+    fn users_get_subnets_inside_the_pool() {
+        let config: Config = toml::from_str(
+            r#"
+            ipv6_subnets = ["2001:db8::/48"]
+            ipv4_subnets = ["192.0.2.0/24"]
+            username = "admin"
+            password = "admin-pass"
+
+            [[users]]
+            username = "team-a"
+            password = "a"
+            max_connections = 10
+            ipv6_subnets = ["2001:db8:0:a::/64"]
+
+            [[users]]
+            username = "team-b"
+            password = "b"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.validate().unwrap().users.len(), 3);
+
+        let config: Config = toml::from_str(
+            r#"
+            ipv6_subnets = ["2001:db8::/48"]
+
+            [[users]]
+            username = "team-a"
+            password = "a"
+            ipv6_subnets = ["2001:db9::/64"]
+            "#,
+        )
+        .unwrap();
+        let err = config.validate().err().unwrap();
+        assert!(err.starts_with("users[0].ipv6_subnets: 2001:db9::/64 is not inside"), "{}", err);
+    }
+
+    #[test]
+    fn limits_sections() {
+        let config: Config = toml::from_str(
+            r#"
+            [limits]
+            connections_per_second = 500
+            download_bytes_per_second = 100_000_000
+
+            [client_limits]
+            requests_per_second = 20
+            max_tunnels = 50
+
+            [[users]]
+            username = "team-a"
+            password = "a"
+            limits = { requests_per_second = 2.5, upload_bytes_per_second = 1_000_000 }
+            "#,
+        )
+        .unwrap();
+        let settings = config.validate().unwrap();
+        assert_eq!(settings.limits, Limits { connections_per_second: Some(500.0), download_bytes_per_second: Some(100_000_000), ..Default::default() });
+        assert_eq!(settings.client_limits, Limits { requests_per_second: Some(20.0), max_tunnels: Some(50), ..Default::default() });
+        let (user, _) = Auth::new(settings.users).users().remove(0);
+        assert_eq!(user.limits, Limits { requests_per_second: Some(2.5), upload_bytes_per_second: Some(1_000_000), ..Default::default() });
+
+        let config: Config = toml::from_str(
+            r#"
+            [[users]]
+            username = "team-a"
+            password = "a"
+            limits = { requests_per_second = 0 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.validate().err().unwrap(), "users[0].limits.requests_per_second: must be a positive number");
+    }
+
+    #[test]
+    fn listener_destinations_override_top_level() {
+        let config: Config = toml::from_str(
+            r#"
+            destinations = ["deny *.example.com"]
+            block_private_destinations = false
+
+            [[socks5]]
+            bind = "127.0.0.1:1080"
+
+            [[socks5]]
+            bind = "127.0.0.1:1081"
+            destinations = ["deny 127.0.0.0/8 22"]
+            "#,
+        )
+        .unwrap();
+        let settings = config.validate().unwrap();
+        let (ssh, web) = ("127.0.0.1:22".parse().unwrap(), "127.0.0.1:80".parse().unwrap());
+
+        assert!(!settings.socks5[0].destinations.allows("www.example.com", web));
+        assert!(settings.socks5[0].destinations.allows("localhost", ssh));
+        assert!(settings.socks5[1].destinations.allows("www.example.com", web));
+        assert!(!settings.socks5[1].destinations.allows("localhost", ssh));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("ipv6_subnet = []").is_err());
+    }
+}
@@ -111,6 +111,11 @@ pub const CURLOPT_POSTFIELDSIZE: c_int = 60;
 
 pub const CURLOPT_FOLLOWLOCATION: c_int = 52;
 
+pub const CURLOPT_INTERFACE: c_int = 10062;
+pub const CURLOPT_IPRESOLVE: c_int = 113;
+pub const CURL_IPRESOLVE_V4: c_long = 1;
+pub const CURL_IPRESOLVE_V6: c_long = 2;
+
 
 
 // 定义 curl_easy_getinfo 的选项常量
@@ -7,10 +7,9 @@ use super::curl_wrapper::{set_curl_option_string, set_curl_option_void};
 use super::curl_ffi::*;
 use libc::{c_char, c_int};
 use std::ffi::{c_long, c_void, CStr, CString};
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
 use std::time::Duration;
 use httparse::{Request, Response};
-use cidr::{Ipv4Cidr, Ipv6Cidr};
 use std::sync::{Arc};
 use tokio::sync::Mutex;
 use tokio::net::{TcpListener, TcpStream};
@@ -24,6 +23,26 @@ use regex::Regex;
 use crate::forward::curl_ffi::CURLE_OK;
 use std::fs::File;
 use std::os::unix::io::IntoRawFd;
+use tokio::time::Instant;
+use tracing::{debug, info, warn, Instrument, Span};
+
+use crate::access_log::Access;
+use crate::proxy::parse_basic_credentials;
+use crate::lease::Lease;
+use crate::limit::{Direction, Limiter};
+use crate::config::SharedForward;
+use crate::happy_eyeballs::order_addrs;
+use crate::logging::connection_span;
+use crate::metrics::{Cause, Failure, ListenerMetrics};
+use crate::pool::AddressPool;
+use crate::resolver::Resolver;
+use crate::routing::Routes;
+use crate::session::split_session;
+use crate::shutdown;
+use crate::strategy::{SelectionKey, Strategy};
+
+/// Status code, header lines and body of a response fetched with libcurl.
+type CurlResult = Result<(i64, Vec<String>, Vec<u8>), Box<dyn Error + Send + Sync>>;
 
 /// 定义 ForwardMapping 结构体和 ProxyType 枚举
 #[derive(Clone)]
@@ -42,126 +61,82 @@ pub enum ProxyType {
     Socks5,
 }
 
-/// 解析命令行参数中的 forward 映射
-pub fn parse_forward_mapping(mapping_str: &str) -> Option<ForwardMapping> {
-    let parts: Vec<&str> = mapping_str.split(',').collect();
-    if parts.len() < 3 || parts.len() > 5 {
-        eprintln!("Invalid forward mapping: {}", mapping_str);
-        return None;
-    }
-
-    let local_addr = match parts[0].parse::<SocketAddr>() {
-        Ok(addr) => addr,
-        Err(e) => {
-            eprintln!("Invalid local address in mapping '{}': {}", mapping_str, e);
-            return None;
-        }
-    };
-
-    let remote_addr = parts[1].to_string();
-    let sni_host = parts[2].to_string();
-
-    let proxy_addrs = if parts.len() >= 4 {
-        // 分割代理地址列表
-        let proxy_addr_list: Vec<String> = parts[3]
-            .split('|')
-            .filter(|addr_str| {
-                if addr_str.contains(':') {
-                    true
-                } else {
-                    eprintln!("Invalid proxy address format '{}'", addr_str);
-                    false
-                }
-            })
-            .map(|s| s.to_string())  // 将 &str 转换为 String
-            .collect();
-
-
-        if proxy_addr_list.is_empty() {
-            eprintln!("No valid proxy addresses in mapping '{}'", mapping_str);
-            return None;
+impl ForwardMapping {
+    /// Validates one mapping; `proxy_type` defaults to HTTP when proxies are given.
+    pub fn new(
+        local_addr: &str,
+        remote_addr: &str,
+        sni_host: &str,
+        proxy_addrs: Vec<String>,
+        proxy_type: Option<&str>,
+    ) -> Result<Self, String> {
+        let local_addr = local_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid local address '{}': {}", local_addr, e))?;
+
+        if let Some(addr) = proxy_addrs.iter().find(|addr| !addr.contains(':')) {
+            return Err(format!("Invalid proxy address format '{}'", addr));
         }
 
-        proxy_addr_list
-    } else {
-        Vec::new()
-    };
-
-    let proxy_type = if parts.len() == 5 {
-        match parts[4].to_lowercase().as_str() {
-            "http" => ProxyType::Http,
-            "socks5" => ProxyType::Socks5,
-            _ => {
-                eprintln!("Invalid proxy type in mapping '{}'", mapping_str);
-                return None;
-            }
-        }
-    } else if !proxy_addrs.is_empty() {
-        // 默认代理类型为 HTTP
-        ProxyType::Http
-    } else {
-        ProxyType::None
-    };
-
-    Some(ForwardMapping {
-        local_addr,
-        remote_addr,
-        sni_host,
-        proxy_addrs,
-        proxy_type,
-    })
-}
-
-/// 检查客户端 IP 是否在允许的范围内
-fn is_allowed_ip(
-    ip: &IpAddr,
-    ipv6_subnets: &Vec<Ipv6Cidr>,
-    ipv4_subnets: &Vec<Ipv4Cidr>,
-    allowed_ips: &Option<Vec<IpAddr>>,
-) -> bool {
-    // 如果 allowed_ips 是 None 或者是空的，直接允许
-    if allowed_ips.as_ref().map_or(true, |ips| ips.is_empty()) {
-        return true;
-    }
-
-    if let Some(allowed_ips) = allowed_ips {
-        if allowed_ips.contains(ip) {
-            return true;
-        }
-    }
+        let proxy_type = match proxy_type.map(str::to_lowercase).as_deref() {
+            Some("http") => ProxyType::Http,
+            Some("socks5") => ProxyType::Socks5,
+            Some(other) => return Err(format!("Invalid proxy type '{}'", other)),
+            // 默认代理类型为 HTTP
+            None if !proxy_addrs.is_empty() => ProxyType::Http,
+            None => ProxyType::None,
+        };
 
-    match ip {
-        IpAddr::V4(ipv4) => ipv4_subnets.iter().any(|subnet| subnet.contains(ipv4)),
-        IpAddr::V6(ipv6) => ipv6_subnets.iter().any(|subnet| subnet.contains(ipv6)),
+        Ok(ForwardMapping {
+            local_addr,
+            remote_addr: remote_addr.to_string(),
+            sni_host: sni_host.to_string(),
+            proxy_addrs,
+            proxy_type,
+        })
     }
 }
+
 pub async fn start_forward_proxy(
-    mapping: ForwardMapping,
-    ipv6_subnets: Arc<Vec<Ipv6Cidr>>,
-    ipv4_subnets: Arc<Vec<Ipv4Cidr>>,
-    allowed_ips: Option<Vec<IpAddr>>,
+    forward: SharedForward,
+    pool: Arc<AddressPool>,
+    limiter: Arc<Limiter>,
+    resolver: Arc<Resolver>,
     timeout_duration: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(mapping.local_addr).await?;
-    println!("Listening on {}", mapping.local_addr);
+    let local_addr = forward.load().mapping.local_addr;
+    let listener = TcpListener::bind(local_addr).await?;
+    info!("Forward proxy listening on {}", local_addr);
 
     loop {
         let (local_stream, client_addr) = listener.accept().await?;
-        let mapping = mapping.clone();
-        let ipv6_subnets = Arc::clone(&ipv6_subnets);
-        let ipv4_subnets = Arc::clone(&ipv4_subnets);
-        let allowed_ips = allowed_ips.clone();
+        if shutdown::is_draining() {
+            debug!(client = %client_addr.ip(), listener = %local_addr, "Draining, connection turned away");
+            continue;
+        }
+        // 每个连接读取当前配置, 重载只影响新连接
+        let current = forward.load_full();
+        let mapping = current.mapping.clone();
+        let strategy = current.strategy;
+        let routes = current.routes.clone();
+        let pool = Arc::clone(&pool);
         let local_stream = Arc::new(Mutex::new(local_stream));
+        let metrics = ListenerMetrics::new(local_addr, "forward");
+        metrics.connection();
 
-        if !is_allowed_ip(
-            &client_addr.ip(),
-            &*ipv6_subnets,
-            &*ipv4_subnets,
-            &allowed_ips,
-        ) {
-            eprintln!("Connection from {} is not allowed", client_addr);
+        // 只检查客户端规则, 出口子网与客户端地址无关
+        if !current.acl.allows(client_addr.ip()) {
+            info!(client = %client_addr.ip(), listener = %local_addr, "Access denied for client");
+            metrics.error(Cause::Denied);
             continue;
         }
+        if let Err(e) = limiter.connect(client_addr.ip()) {
+            debug!(client = %client_addr.ip(), listener = %local_addr, "Connection turned away: {}", e);
+            metrics.error(Cause::Limited);
+            continue;
+        }
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
         fn assert_send<T: Send>(_: T) {}
         // 在 `tokio::spawn` 外部引用 `client_addr`
         let client_address = client_addr.clone();
@@ -175,11 +150,24 @@ pub async fn start_forward_proxy(
             assert_send(&mapping);
             assert_send(timeout_duration);
 
+            let span = connection_span("forward", local_addr, Some(client_address.ip()));
             async move {
-                if let Err(e) = handle_connection(local_stream, mapping, timeout_duration).await {
-                    eprintln!("Error handling connection from {}: {}", client_address, e);
+                let started = Instant::now();
+                // The method is set once the request has been read
+                let access = Access::new("forward", local_addr, Some(client_address.ip()), "-");
+                let served = access
+                    .killable(handle_connection(local_stream, mapping, timeout_duration, pool, strategy, routes, &limiter, &resolver, &metrics, &access))
+                    .await;
+                match served {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => {
+                        warn!(duration_ms = started.elapsed().as_millis() as u64, "{}", e);
+                        metrics.error(Cause::of(&*e));
+                    }
+                    None => info!(duration_ms = started.elapsed().as_millis() as u64, "Closed from the admin endpoint"),
                 }
             }
+            .instrument(span)
         });
     }
 }
@@ -227,7 +215,7 @@ fn parse_http_request(
         format!("https://{}{}", host, path)
     };
 
-    eprintln!("请求方法: {}, URL: {}", method, target_url);
+    debug!(method = %method, url = %target_url, "Parsed forward request");
 
     
 
@@ -239,11 +227,20 @@ fn parse_http_request(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_connection(
     local_stream: Arc<Mutex<TcpStream>>,
     mapping: ForwardMapping,
-    _timeout_duration: Duration,
+    timeout_duration: Duration,
+    pool: Arc<AddressPool>,
+    strategy: Strategy,
+    routes: Routes,
+    limiter: &Limiter,
+    resolver: &Resolver,
+    metrics: &ListenerMetrics,
+    access: &Access,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
     let client_addr = local_stream.lock().await.peer_addr()?;
     // eprintln!("处理来自 {} 的连接", client_addr);
 
@@ -329,6 +326,17 @@ pub async fn handle_connection(
     if body.len() < content_length {
         return Err("Incomplete HTTP body".into());
     }
+    metrics.request("request");
+    let request_bytes = (body_start + body.len()) as u64;
+    let permit = match limiter.admit(Some(client_addr.ip()), None, false) {
+        Ok(permit) => permit,
+        Err(e) => {
+            access.status(429);
+            local_stream.lock().await.write_all(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n").await?;
+            return Err(Failure::new(Cause::Limited, e.to_string()).into());
+        }
+    };
+    permit.pace(Direction::Upload, request_bytes).await;
 
 
     
@@ -343,18 +351,61 @@ pub async fn handle_connection(
     };
 
     if !matches!(status, httparse::Status::Complete(_)) {
-        eprintln!("不完整的 HTTP 请求");
         return Err("Incomplete HTTP request".into());
     }
     let (method,path,headers_map,target_url,host) =  parse_http_request(buffer)?;;
-
+    access.command(&method);
+    access.target(&host);
+
+    // `user-session-<id>` in Proxy-Authorization pins the egress address for the whole session
+    let session = headers_map
+        .get("proxy-authorization")
+        .and_then(|value| parse_basic_credentials(value))
+        .map(|(username, _)| username)
+        .filter(|username| split_session(username).1.is_some());
+    let key = SelectionKey {
+        client: Some(client_addr.ip()),
+        target: Some(&host),
+        session: session.as_deref(),
+    };
+    // Direct requests leave from a pool address of the target's family; with upstream
+    // proxies the egress address is the proxy's
+    let lease = if mapping.proxy_addrs.is_empty() {
+        let uri: hyper::Uri = target_url.parse().map_err(|e| format!("Invalid target URL {}: {}", target_url, e))?;
+        let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("http") { 80 } else { 443 });
+        let name = uri.host().unwrap_or_default();
+        let subnets = routes.subnets_for(name);
+        let pool_subnets = pool.subnets();
+        let addrs = resolver
+            .lookup(name, port, subnets.unwrap_or(&pool_subnets), false)
+            .await
+            .map_err(|e| Failure::new(Cause::Unreachable, format!("Failed to resolve: {}", e)))?;
+        // curl connects to the name itself, so only the family of the first usable address matters
+        let target = order_addrs(&addrs, subnets.unwrap_or(&pool_subnets), false, None)
+            .first()
+            .copied()
+            .ok_or_else(|| Failure::new(Cause::Unreachable, "No egress address family for the target".to_string()))?;
+        Some(
+            pool.acquire(target.ip(), strategy, &key, subnets, timeout_duration)
+                .await
+                .map_err(|e| Failure::new(Cause::Pool, format!("No egress address: {}", e)))?,
+        )
+    } else {
+        None
+    };
+    let bind_addr = lease.as_ref().map(Lease::ip);
+    if let Some(bind_addr) = bind_addr {
+        access.egress(bind_addr);
+    }
 
     let mut chrome_so = format!("chrome116");
 
 
 
     // 使用 libcurl-impersonate 发起请求并收集响应数据
-    let (status_line, response_headers, response_body) = task::spawn_blocking(move || -> Result<(String, Vec<String>, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let span = Span::current();
+    let (status_code, response_headers, response_body) = task::spawn_blocking(move || -> CurlResult {
+        let _entered = span.enter();
         unsafe {
             // 初始化 MemoryStruct 和 HeaderStruct
             let mem_ptr =  init_memory() ;
@@ -370,7 +421,7 @@ pub async fn handle_connection(
             // 初始化 CURL easy handle
             let easy_handle = curl_easy_init();
             if easy_handle.is_null() {
-                eprintln!("Failed to initialize CURL easy handle");
+                debug!("Failed to initialize CURL easy handle");
                 unsafe { free_memory(mem_ptr) };
                 unsafe { free_headers(headers_ptr) };
                 return Err("CURL initialization failed".into());
@@ -385,7 +436,7 @@ pub async fn handle_connection(
             let target_url_c = CString::new(target_url)?;
             let res = curl_easy_setopt(easy_handle, CURLOPT_URL, target_url_c.as_ptr() as *const c_void);
             if res.0 != CURLE_OK.0 {
-                eprintln!("curl_easy_setopt CURLOPT_URL failed: {}", res);
+                debug!("curl_easy_setopt CURLOPT_URL failed: {}", res);
                 unsafe { free_memory(mem_ptr) };
                 unsafe { free_headers(headers_ptr) };
                 return Err(format!("curl_easy_setopt CURLOPT_URL failed: {}", res).into());
@@ -401,7 +452,7 @@ pub async fn handle_connection(
                 let method_c = CString::new(method)?;
                 let res = curl_easy_setopt(easy_handle, CURLOPT_CUSTOMREQUEST, method_c.as_ptr() as *const c_void);
                 if res.0 != CURLE_OK.0 {
-                    eprintln!("curl_easy_setopt CURLOPT_CUSTOMREQUEST failed: {}", res);
+                    debug!("curl_easy_setopt CURLOPT_CUSTOMREQUEST failed: {}", res);
                     unsafe { free_memory(mem_ptr) };
                     unsafe { free_headers(headers_ptr) };
                     return Err(format!("curl_easy_setopt CURLOPT_CUSTOMREQUEST failed: {}", res).into());
@@ -412,12 +463,12 @@ pub async fn handle_connection(
             // 设置请求体（仅当存在时）
             if !body.is_empty() {
 
-                eprintln!("请求体大小: {}", body.len());
+                debug!(body_bytes = body.len(), "Sending request body");
 
                 // 设置二进制数据为请求体
                 let res = curl_easy_setopt(easy_handle, CURLOPT_POSTFIELDS, body.as_ptr() as *const c_void);
                 if res.0 != CURLE_OK.0 {
-                    eprintln!("curl_easy_setopt CURLOPT_POSTFIELDS failed: {}", res);
+                    debug!("curl_easy_setopt CURLOPT_POSTFIELDS failed: {}", res);
                     unsafe { free_memory(mem_ptr) };
                     unsafe { free_headers(headers_ptr) };
                     return Err(format!("curl_easy_setopt CURLOPT_POSTFIELDS failed: {}", res).into());
@@ -426,7 +477,7 @@ pub async fn handle_connection(
                 // 设置请求体的大小
                 let res = curl_easy_setopt(easy_handle, CURLOPT_POSTFIELDSIZE, body.len() as c_long as *const c_void);
                 if res.0 != CURLE_OK.0 {
-                    eprintln!("curl_easy_setopt CURLOPT_POSTFIELDSIZE failed: {}", res);
+                    debug!("curl_easy_setopt CURLOPT_POSTFIELDSIZE failed: {}", res);
                     unsafe { free_memory(mem_ptr) };
                     unsafe { free_headers(headers_ptr) };
                     return Err(format!("curl_easy_setopt CURLOPT_POSTFIELDSIZE failed: {}", res).into());
@@ -450,6 +501,10 @@ pub async fn handle_connection(
                     continue
                 }
 
+                if key.to_lowercase() == "proxy-authorization" {
+                    continue
+                }
+
                 if key.to_lowercase().starts_with("proxy"){
                     proxy_addr = format!("{}",  value);
                     continue
@@ -479,13 +534,34 @@ pub async fn handle_connection(
             //     unsafe { free_headers(headers_ptr) };
             //     return Err(format!("curl_easy_setopt CURLOPT_FOLLOWLOCATION failed: {}", res).into());
             // }
+            if let Some(bind_addr) = bind_addr {
+                // Leave from the address chosen by the pool
+                let interface_c = CString::new(format!("host!{}", bind_addr)).unwrap();
+                let res = curl_easy_setopt(easy_handle, CURLOPT_INTERFACE, interface_c.as_ptr() as *const c_void);
+                if res.0 != CURLE_OK.0 {
+                    debug!("curl_easy_setopt CURLOPT_INTERFACE failed: {}", res);
+                    free_memory(mem_ptr);
+                    free_headers(headers_ptr);
+                    return Err("Failed to set egress address".into());
+                }
+
+                let ip_resolve = if bind_addr.is_ipv6() { CURL_IPRESOLVE_V6 } else { CURL_IPRESOLVE_V4 };
+                let res = curl_easy_setopt(easy_handle, CURLOPT_IPRESOLVE, ip_resolve as *const c_void);
+                if res.0 != CURLE_OK.0 {
+                    debug!("curl_easy_setopt CURLOPT_IPRESOLVE failed: {}", res);
+                    free_memory(mem_ptr);
+                    free_headers(headers_ptr);
+                    return Err("Failed to set egress address family".into());
+                }
+            }
+
             if !mapping.proxy_addrs.is_empty() {
                 // 设置代理地址
 
                 let proxy_c = CString::new(proxy_addr.clone()).unwrap();
                 let res = curl_easy_setopt(easy_handle, CURLOPT_PROXY, proxy_c.as_ptr() as *const c_void);
                 if res.0 != CURLE_OK.0 {
-                    eprintln!("curl_easy_setopt CURLOPT_PROXY failed: {}", res);
+                    debug!("curl_easy_setopt CURLOPT_PROXY failed: {}", res);
                     unsafe { free_memory(mem_ptr) };
                     unsafe { free_headers(headers_ptr) };
                     return Err("Failed to set proxy".into());
@@ -497,7 +573,7 @@ pub async fn handle_connection(
                         let proxy_type = CURLPROXY_HTTP;
                         let res = curl_easy_setopt(easy_handle, CURLOPT_PROXYTYPE, proxy_type as  c_long as *const c_void);
                         if res.0 != CURLE_OK.0 {
-                            eprintln!("curl_easy_setopt CURLOPT_PROXYTYPE (HTTP) failed: {}", res);
+                            debug!("curl_easy_setopt CURLOPT_PROXYTYPE (HTTP) failed: {}", res);
                             unsafe { free_memory(mem_ptr) };
                             unsafe { free_headers(headers_ptr) };
                             return Err("Failed to set proxy type (HTTP)".into());
@@ -507,7 +583,7 @@ pub async fn handle_connection(
                         let proxy_type = CURLPROXY_SOCKS5 ;
                         let res = curl_easy_setopt(easy_handle, CURLOPT_PROXYTYPE, proxy_type as c_long as *const c_void);
                         if res.0 != CURLE_OK.0 {
-                            eprintln!("curl_easy_setopt CURLOPT_PROXYTYPE (SOCKS5) failed: {}", res);
+                            debug!("curl_easy_setopt CURLOPT_PROXYTYPE (SOCKS5) failed: {}", res);
                             unsafe { free_memory(mem_ptr) };
                             unsafe { free_headers(headers_ptr) };
                             return Err("Failed to set proxy type (SOCKS5)".into());
@@ -540,7 +616,7 @@ pub async fn handle_connection(
             if !header_list.is_null() {
                 let res = curl_easy_setopt(easy_handle, CURLOPT_HTTPHEADER, header_list as *const c_void);
                 if res.0 != CURLE_OK.0 {
-                    eprintln!("curl_easy_setopt CURLOPT_HTTPHEADER failed: {}", res);
+                    debug!("curl_easy_setopt CURLOPT_HTTPHEADER failed: {}", res);
                     curl_slist_free_all(header_list);
                     unsafe { free_memory(mem_ptr) };
                     unsafe { free_headers(headers_ptr) };
@@ -552,7 +628,7 @@ pub async fn handle_connection(
             // eprintln!("设置回调1");
             let res = curl_easy_setopt(easy_handle, CURLOPT_WRITEFUNCTION, write_callback as *const c_void);
             if res.0 != CURLE_OK.0 {
-                eprintln!("curl_easy_setopt CURLOPT_WRITEFUNCTION failed: {}", res);
+                debug!("curl_easy_setopt CURLOPT_WRITEFUNCTION failed: {}", res);
                 if !header_list.is_null() {
                     curl_slist_free_all(header_list);
                 }
@@ -563,7 +639,7 @@ pub async fn handle_connection(
             // eprintln!("设置回调2");
             let res = curl_easy_setopt(easy_handle, CURLOPT_WRITEDATA, mem_ptr as *mut c_void);
             if res.0 != CURLE_OK.0 {
-                eprintln!("curl_easy_setopt CURLOPT_WRITEDATA failed: {}", res);
+                debug!("curl_easy_setopt CURLOPT_WRITEDATA failed: {}", res);
                 if !header_list.is_null() {
                     curl_slist_free_all(header_list);
                 }
@@ -576,7 +652,7 @@ pub async fn handle_connection(
             // eprintln!("设置回调3");
             let res = curl_easy_setopt(easy_handle, CURLOPT_HEADERFUNCTION, header_callback as *const c_void);
             if res.0 != CURLE_OK.0 {
-                eprintln!("curl_easy_setopt CURLOPT_HEADERFUNCTION failed: {}", res);
+                debug!("curl_easy_setopt CURLOPT_HEADERFUNCTION failed: {}", res);
                 if !header_list.is_null() {
                     curl_slist_free_all(header_list);
                 }
@@ -587,7 +663,7 @@ pub async fn handle_connection(
             // eprintln!("设置回调4");
             let res = curl_easy_setopt(easy_handle, CURLOPT_HEADERDATA, headers_ptr as *mut c_void);
             if res.0 != CURLE_OK.0 {
-                eprintln!("curl_easy_setopt CURLOPT_HEADERDATA failed: {}", res);
+                debug!("curl_easy_setopt CURLOPT_HEADERDATA failed: {}", res);
                 if !header_list.is_null() {
                     curl_slist_free_all(header_list);
                 }
@@ -598,7 +674,7 @@ pub async fn handle_connection(
             let target_browser = CString::new(chrome_so).unwrap(); // 选择要模拟的浏览器
             let result = curl_easy_impersonate(easy_handle, target_browser.as_ptr(), 1);
             if result.0 != CURLE_OK.0 {
-                eprintln!("Failed to impersonate browser: {}", result);
+                debug!("Failed to impersonate browser: {}", result);
                 return Err("Impersonation failed".into());
             }
 
@@ -622,7 +698,7 @@ pub async fn handle_connection(
                 } else {
                     "Unknown CURL error".to_string()
                 };
-                eprintln!("CURL request failed: {}", error_str);
+                debug!("CURL request failed: {}", error_str);
                 if !header_list.is_null() {
                     curl_slist_free_all(header_list);
                 }
@@ -638,7 +714,7 @@ pub async fn handle_connection(
                 &mut response_code as *mut c_long,
             );
             if res.0 != CURLE_OK.0 {
-                eprintln!("Failed to get response code: {}", res);
+                debug!("Failed to get response code: {}", res);
                 if !header_list.is_null() {
                     curl_slist_free_all(header_list);
                 }
@@ -647,7 +723,7 @@ pub async fn handle_connection(
                 return Err("CURL get info failed".into());
             }
 
-            eprintln!("响应码: {} {} {}", response_code,proxy_addr.clone(),client_addr.clone());
+            debug!(proxy = %proxy_addr, status = response_code, "Response received");
 
             // 读取响应头部
             let headers_lock = (*headers_ptr).count;
@@ -678,11 +754,12 @@ pub async fn handle_connection(
             free_memory(mem_ptr);
             free_headers(headers_ptr);
             let status_code: i64 = response_code as i64;
-            let status_line = format!("HTTP/1.1 {}", status_code);
-            Ok((status_line, response_headers, response_body))
+            Ok((status_code, response_headers, response_body))
         }
 
     }).await??;
+    let status_line = format!("HTTP/1.1 {}", status_code);
+    access.status(status_code as u16);
 
 
 
@@ -727,10 +804,16 @@ pub async fn handle_connection(
 
 
 
-    // 发送响应体
-    locked_stream.write_all(&response_body).await?;
+    // 发送响应体, paced in chunks to the download rates
+    for chunk in response_body.chunks(16 * 1024) {
+        permit.pace(Direction::Download, chunk.len() as u64).await;
+        locked_stream.write_all(chunk).await?;
+    }
 
     locked_stream.flush().await?;
+    metrics.bytes(request_bytes, response_body.len() as u64);
+    access.bytes(request_bytes, response_body.len() as u64);
+    info!(status = status_code, request_bytes, response_bytes = response_body.len(), duration_ms = started.elapsed().as_millis() as u64, "Request done");
     // 在函数末尾添加 Ok(())
     Ok(())
 }
@@ -0,0 +1,212 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::config::Listener;
+use crate::lease::Lease;
+use crate::nat64::Nat64Prefix;
+use crate::netlink::BackendError;
+use crate::pool::{AddressPool, Subnets};
+use crate::strategy::SelectionKey;
+
+/// RFC 8305's recommended Connection Attempt Delay
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub enum ConnectError {
+    /// None of the target's addresses is of a family the pool has subnets for
+    NoUsableAddress,
+    /// No egress address could be taken from the pool
+    Pool(BackendError),
+    Io(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NoUsableAddress => write!(f, "no address of a family the pool has subnets for"),
+            ConnectError::Pool(e) => write!(f, "no egress address: {}", e),
+            ConnectError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(error: io::Error) -> Self {
+        ConnectError::Io(error)
+    }
+}
+
+/// Keeps the addresses whose family has subnets in `subnets` and interleaves the families,
+/// as RFC 8305 section 4 does. The first family is IPv6 if `prefer_ipv6` and it is usable,
+/// otherwise that of the first address, i.e. the resolver's preference.
+///
+/// With a `nat64` prefix and only IPv6 subnets, IPv4-only targets are reached through their
+/// NAT64 address instead (DNS64, RFC 6147). This runs after the destination ACL, which checks
+/// NAT64 addresses, synthesized here or given by the client or DNS64, as their IPv4 target.
+pub(crate) fn order_addrs(addrs: &[SocketAddr], subnets: &Subnets, prefer_ipv6: bool, nat64: Option<Nat64Prefix>) -> Vec<SocketAddr> {
+    let (mut ipv6, mut ipv4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|addr| addr.is_ipv6());
+    if subnets.ipv6.is_empty() {
+        ipv6.clear();
+    }
+    if subnets.ipv4.is_empty() {
+        if let (Some(prefix), true, false) = (nat64, ipv6.is_empty(), subnets.ipv6.is_empty()) {
+            ipv6 = ipv4
+                .iter()
+                .map(|addr| match addr {
+                    SocketAddr::V4(v4) => SocketAddr::new(prefix.embed(*v4.ip()).into(), v4.port()),
+                    SocketAddr::V6(_) => *addr,
+                })
+                .collect();
+        }
+        ipv4.clear();
+    }
+
+    let ipv6_first = !ipv6.is_empty() && (prefer_ipv6 || ipv4.is_empty() || addrs.first().is_some_and(SocketAddr::is_ipv6));
+    let (first, second) = if ipv6_first { (ipv6, ipv4) } else { (ipv4, ipv6) };
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to one of `addrs` from a pool address of the matching family, racing the
+/// candidates RFC 8305 style: a new attempt starts every 250ms, or as soon as one fails,
+/// and the first established connection wins. Families without a subnet in `subnets` (the
+/// whole pool if `None`) are skipped, see [`order_addrs`] for how the listener's `prefer_ipv6`
+/// and `nat64` apply. `timeout_duration` bounds the whole race.
+///
+/// Returns the stream with the lease of its local address, to be kept for as long as the
+/// connection is open.
+pub async fn connect(
+    pool: &AddressPool,
+    settings: &Listener,
+    key: &SelectionKey<'_>,
+    subnets: Option<&Subnets>,
+    addrs: &[SocketAddr],
+    timeout_duration: Duration,
+) -> Result<(TcpStream, Lease), ConnectError> {
+    let pool_subnets = pool.subnets();
+    let addrs = order_addrs(addrs, subnets.unwrap_or(&pool_subnets), settings.prefer_ipv6, settings.nat64);
+    let strategy = settings.strategy;
+
+    let attempt = |addr: SocketAddr| async move {
+        let lease = pool.acquire(addr.ip(), strategy, key, subnets, timeout_duration).await.map_err(ConnectError::Pool)?;
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(lease.ip(), 0))?;
+        let stream = socket.connect(addr).await?;
+        Ok::<_, ConnectError>((stream, lease))
+    };
+
+    let race = async {
+        let mut pending = addrs.into_iter().peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = ConnectError::NoUsableAddress;
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(addr) => attempts.push(attempt(addr)),
+                    None => return Err(last_error),
+                }
+            }
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(connected) => return Ok(connected),
+                    Err(e) => {
+                        last_error = e;
+                        // A failed attempt starts the next one right away
+                        if let Some(addr) = pending.next() {
+                            attempts.push(attempt(addr));
+                        }
+                    }
+                },
+                _ = sleep(CONNECTION_ATTEMPT_DELAY), if pending.peek().is_some() => {
+                    attempts.push(attempt(pending.next().unwrap()));
+                }
+            }
+        }
+    };
+    timeout(timeout_duration, race)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out").into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    fn subnets(ipv6: &[&str], ipv4: &[&str]) -> Subnets {
+        Subnets {
+            ipv6: ipv6.iter().map(|s| s.parse().unwrap()).collect(),
+            ipv4: ipv4.iter().map(|s| s.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn addresses_are_interleaved_and_filtered_by_pool_family() {
+        let resolved = addrs(&["192.0.2.1:80", "192.0.2.2:80", "[2001:db8::1]:80", "[2001:db8::2]:80", "[2001:db8::3]:80"]);
+        let both = subnets(&["2001:db8:1::/48"], &["198.51.100.0/24"]);
+        assert_eq!(
+            order_addrs(&resolved, &both, false, None),
+            addrs(&["192.0.2.1:80", "[2001:db8::1]:80", "192.0.2.2:80", "[2001:db8::2]:80", "[2001:db8::3]:80"])
+        );
+        assert_eq!(
+            order_addrs(&resolved, &both, true, None),
+            addrs(&["[2001:db8::1]:80", "192.0.2.1:80", "[2001:db8::2]:80", "192.0.2.2:80", "[2001:db8::3]:80"])
+        );
+        assert_eq!(order_addrs(&resolved, &subnets(&[], &["198.51.100.0/24"]), true, None), addrs(&["192.0.2.1:80", "192.0.2.2:80"]));
+        assert!(order_addrs(&resolved, &subnets(&[], &[]), true, None).is_empty());
+    }
+
+    #[test]
+    fn nat64_reaches_ipv4_only_targets_from_ipv6() {
+        let prefix = Some(crate::nat64::WELL_KNOWN_PREFIX.parse().unwrap());
+        let ipv6_only = subnets(&["2001:db8:1::/48"], &[]);
+        let ipv4_target = addrs(&["192.0.2.1:80"]);
+        assert_eq!(order_addrs(&ipv4_target, &ipv6_only, false, prefix), addrs(&["[64:ff9b::c000:201]:80"]));
+        assert!(order_addrs(&ipv4_target, &ipv6_only, false, None).is_empty());
+        // Native IPv6 wins over synthesis, and an IPv4 subnet makes it unnecessary
+        let dual_stack = addrs(&["192.0.2.1:80", "[2001:db8::1]:80"]);
+        assert_eq!(order_addrs(&dual_stack, &ipv6_only, false, prefix), addrs(&["[2001:db8::1]:80"]));
+        let both = subnets(&["2001:db8:1::/48"], &["198.51.100.0/24"]);
+        assert_eq!(order_addrs(&ipv4_target, &both, false, prefix), ipv4_target);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_address_and_fails_cleanly() {
+        let pool = AddressPool::for_tests(&[], &["127.0.0.0/24"]);
+        let mut settings = Listener { prefer_ipv6: true, ..Listener::for_tests() };
+        let key = SelectionKey::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // Bound and dropped, so nothing listens there
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // The IPv6 address is skipped, the pool has no IPv6 subnet
+        let targets = ["[::1]:80".parse().unwrap(), closed, listener.local_addr().unwrap()];
+        let (stream, lease) = connect(&pool, &settings, &key, None, &targets, timeout).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), lease.ip());
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        settings.prefer_ipv6 = false;
+        let err = connect(&pool, &settings, &key, None, &["[::1]:80".parse().unwrap()], timeout).await.err().unwrap();
+        assert!(matches!(err, ConnectError::NoUsableAddress), "{}", err);
+        let err = connect(&pool, &settings, &key, None, &[closed], timeout).await.err().unwrap();
+        assert!(matches!(err, ConnectError::Io(ref e) if e.kind() == io::ErrorKind::ConnectionRefused), "{}", err);
+    }
+}
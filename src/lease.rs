@@ -0,0 +1,387 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// How long changes are collected before the lease file is written.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+struct LeaseEntry {
+    active: usize,
+    last_used: SystemTime,
+}
+
+/// An address in the lease table, as the admin endpoint shows it.
+#[derive(Serialize)]
+pub struct LeaseInfo {
+    pub ip: IpAddr,
+    /// Connections using the address
+    pub connections: usize,
+    /// Seconds since the last connection closed, if none is open
+    pub idle_secs: Option<u64>,
+}
+
+/// One line of the lease file.
+#[derive(Serialize, Deserialize)]
+struct PersistedLease {
+    ip: IpAddr,
+    /// Unix seconds
+    last_used: u64,
+}
+
+/// Tracks every address added to the interface in system-route mode: how many connections
+/// use it right now and when it was last released.
+///
+/// An address becomes reclaimable once it has no connections and has been idle for `ttl`, or,
+/// when more than `max` addresses are leased, as soon as it is idle (oldest first). Addresses
+/// with live connections are never reclaimed. Changes are written to `path` shortly after
+/// they happen, by the task `spawn_writer` starts, so the next start can remove whatever a
+/// crashed run left behind.
+pub struct LeaseTable {
+    ttl: Duration,
+    max: usize,
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<IpAddr, LeaseEntry>>,
+    /// Addresses on their way off the interface, which `acquire` waits for. Locked after
+    /// `entries` when both are needed
+    removing: Mutex<HashSet<IpAddr>>,
+    removed: Notify,
+    /// Set when the entries changed since the file was last written
+    dirty: AtomicBool,
+    changed: Notify,
+    /// Held while the file is written, so only one write uses the temporary file at a time
+    writing: tokio::sync::Mutex<()>,
+}
+
+/// A connection's hold on its egress address, released on drop.
+pub struct Lease {
+    ip: IpAddr,
+    table: Option<Arc<LeaseTable>>,
+    open: Option<Arc<AtomicUsize>>,
+}
+
+impl Lease {
+    /// A lease that isn't tracked anywhere, for addresses the proxy doesn't manage.
+    pub fn untracked(ip: IpAddr) -> Self {
+        Lease { ip, table: None, open: None }
+    }
+
+    /// Also counts the lease in `open` until it is dropped.
+    pub fn counted(mut self, open: &Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::Relaxed);
+        self.open = Some(Arc::clone(open));
+        self
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+/// Holds back `acquire` for an address `remove_idle` forgot until it has left the interface,
+/// so a new connection can't bind to it while the backend deletes it. Dropped once it's gone.
+pub struct Removal {
+    ip: IpAddr,
+    table: Arc<LeaseTable>,
+}
+
+impl Drop for Removal {
+    fn drop(&mut self) {
+        self.table.removing.lock().unwrap().remove(&self.ip);
+        self.table.removed.notify_waiters();
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(table) = &self.table {
+            table.release(self.ip);
+        }
+        if let Some(open) = &self.open {
+            open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl LeaseTable {
+    pub fn new(ttl: Duration, max: usize, path: Option<PathBuf>) -> Self {
+        LeaseTable {
+            ttl,
+            max,
+            path,
+            entries: Mutex::new(HashMap::new()),
+            removing: Mutex::new(HashSet::new()),
+            removed: Notify::new(),
+            dirty: AtomicBool::new(false),
+            changed: Notify::new(),
+            writing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Records a new connection on `ip`, after waiting for a removal of `ip` in progress. The
+    /// flag is true if `ip` wasn't leased before.
+    pub async fn acquire(self: &Arc<Self>, ip: IpAddr) -> (Lease, bool) {
+        loop {
+            // Created before the check so a removal finishing in between still wakes it
+            let removed = self.removed.notified();
+            if let Some(acquired) = self.try_acquire(ip) {
+                return acquired;
+            }
+            removed.await;
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<(Lease, bool)> {
+        let is_new = {
+            let mut entries = self.entries.lock().unwrap();
+            if self.removing.lock().unwrap().contains(&ip) {
+                return None;
+            }
+            let is_new = !entries.contains_key(&ip);
+            let entry = entries.entry(ip).or_insert(LeaseEntry {
+                active: 0,
+                last_used: SystemTime::now(),
+            });
+            entry.active += 1;
+            is_new
+        };
+        if is_new {
+            self.mark_dirty();
+        }
+        let lease = Lease {
+            ip,
+            table: Some(Arc::clone(self)),
+            open: None,
+        };
+        Some((lease, is_new))
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&ip) {
+            entry.active = entry.active.saturating_sub(1);
+            entry.last_used = SystemTime::now();
+        }
+    }
+
+    /// Idle addresses that should leave the interface now, oldest first.
+    pub fn reclaimable(&self) -> Vec<IpAddr> {
+        let entries = self.entries.lock().unwrap();
+        let mut idle: Vec<(IpAddr, SystemTime)> = entries
+            .iter()
+            .filter(|(_, entry)| entry.active == 0)
+            .map(|(ip, entry)| (*ip, entry.last_used))
+            .collect();
+        idle.sort_by_key(|(_, last_used)| *last_used);
+
+        let over_max = entries.len().saturating_sub(self.max);
+        idle.iter()
+            .enumerate()
+            .filter(|(i, (_, last_used))| *i < over_max || last_used.elapsed().unwrap_or_default() >= self.ttl)
+            .map(|(_, (ip, _))| *ip)
+            .collect()
+    }
+
+    /// Forgets `ip` unless a connection picked it up again since `reclaimable` returned it.
+    /// Returns a `Removal` to hold while the address leaves the interface if it was removed.
+    pub fn remove_idle(self: &Arc<Self>, ip: IpAddr) -> Option<Removal> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(&ip) {
+                Some(entry) if entry.active == 0 => entries.remove(&ip),
+                _ => return None,
+            };
+            self.removing.lock().unwrap().insert(ip);
+        }
+        self.mark_dirty();
+        Some(Removal { ip, table: Arc::clone(self) })
+    }
+
+    /// Forgets every address, in use or not, e.g. on shutdown. Call `flush` afterwards.
+    pub fn drain(&self) -> Vec<IpAddr> {
+        let drained = self.entries.lock().unwrap().drain().map(|(ip, _)| ip).collect();
+        self.mark_dirty();
+        drained
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.entries.lock().unwrap().contains_key(&ip)
+    }
+
+    pub fn is_over_max(&self) -> bool {
+        self.len() > self.max
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn active(&self) -> usize {
+        self.entries.lock().unwrap().values().filter(|entry| entry.active > 0).count()
+    }
+
+    /// Every leased address, in address order.
+    pub fn snapshot(&self) -> Vec<LeaseInfo> {
+        let mut leases: Vec<LeaseInfo> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, entry)| LeaseInfo {
+                ip: *ip,
+                connections: entry.active,
+                idle_secs: (entry.active == 0).then(|| entry.last_used.elapsed().unwrap_or_default().as_secs()),
+            })
+            .collect();
+        leases.sort_by_key(|lease| lease.ip);
+        leases
+    }
+
+    /// Addresses recorded in the lease file by a previous run.
+    pub fn load_previous(&self) -> Result<Vec<IpAddr>, Box<dyn Error + Send + Sync>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        };
+        let leases: Vec<PersistedLease> = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid lease file {}: {}", path.display(), e))?;
+        Ok(leases.into_iter().map(|lease| lease.ip).collect())
+    }
+
+    /// Notes that the lease file is out of date, for the writer task to catch up.
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+        self.changed.notify_one();
+    }
+
+    /// Writes the lease file at most once per `SAVE_DELAY` while the table changes.
+    pub fn spawn_writer(self: &Arc<Self>) {
+        if self.path.is_none() {
+            return;
+        }
+        let table = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                table.changed.notified().await;
+                tokio::time::sleep(SAVE_DELAY).await;
+                table.flush().await;
+            }
+        });
+    }
+
+    /// Writes the table to the lease file if it changed, replacing the file atomically.
+    pub async fn flush(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _writing = self.writing.lock().await;
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let leases: Vec<PersistedLease> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, entry)| PersistedLease {
+                ip: *ip,
+                last_used: entry.last_used.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            })
+            .collect();
+
+        let path = path.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            let tmp = path.with_extension("tmp");
+            let result = serde_json::to_vec_pretty(&leases)
+                .map_err(std::io::Error::from)
+                .and_then(|json| std::fs::write(&tmp, json))
+                .and_then(|_| std::fs::rename(&tmp, &path));
+            if let Err(e) = &result {
+                warn!("Failed to write lease file {}: {}", path.display(), e);
+            }
+            result.is_ok()
+        })
+        .await;
+        if !matches!(written, Ok(true)) {
+            // Try again with the next change
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn active_lease_is_never_reclaimed() {
+        let table = Arc::new(LeaseTable::new(Duration::ZERO, 0, None));
+        let (lease, is_new) = table.acquire(ip("2001:db8::1")).await;
+        assert!(is_new);
+        assert!(table.reclaimable().is_empty());
+        assert!(table.remove_idle(ip("2001:db8::1")).is_none());
+
+        drop(lease);
+        assert_eq!(table.reclaimable(), vec![ip("2001:db8::1")]);
+        assert!(table.remove_idle(ip("2001:db8::1")).is_some());
+        assert_eq!(table.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn idle_lease_waits_for_ttl() {
+        let table = Arc::new(LeaseTable::new(Duration::from_secs(60), 10, None));
+        drop(table.acquire(ip("2001:db8::1")).await.0);
+        assert!(table.reclaimable().is_empty());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reused_lease_is_not_new() {
+        let table = Arc::new(LeaseTable::new(Duration::from_secs(60), 10, None));
+        let (_first, is_new) = table.acquire(ip("2001:db8::1")).await;
+        assert!(is_new);
+        let (_second, is_new) = table.acquire(ip("2001:db8::1")).await;
+        assert!(!is_new);
+        assert_eq!(table.active(), 1);
+    }
+
+    #[tokio::test]
+    async fn over_max_reclaims_oldest_idle_first() {
+        let table = Arc::new(LeaseTable::new(Duration::from_secs(60), 1, None));
+        drop(table.acquire(ip("2001:db8::1")).await.0);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        drop(table.acquire(ip("2001:db8::2")).await.0);
+        let _busy = table.acquire(ip("2001:db8::3")).await.0;
+
+        assert_eq!(table.reclaimable(), vec![ip("2001:db8::1"), ip("2001:db8::2")]);
+    }
+
+    #[tokio::test]
+    async fn lease_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("leases-{}.json", std::process::id()));
+        let table = Arc::new(LeaseTable::new(Duration::from_secs(60), 10, Some(path.clone())));
+        let _lease = table.acquire(ip("2001:db8::1")).await.0;
+        let _lease = table.acquire(ip("192.0.2.1")).await.0;
+        table.flush().await;
+
+        let restarted = LeaseTable::new(Duration::from_secs(60), 10, Some(path.clone()));
+        let mut previous = restarted.load_previous().unwrap();
+        previous.sort();
+        assert_eq!(previous, vec![ip("192.0.2.1"), ip("2001:db8::1")]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
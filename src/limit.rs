@@ -0,0 +1,467 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+use crate::auth::Login;
+
+/// Per-client scopes idle this long are dropped once the table grows.
+const CLIENT_IDLE: Duration = Duration::from_secs(60);
+const CLIENT_SWEEP_MIN: usize = 1024;
+
+/// Token-bucket limits of one scope: the whole proxy, a client address or a user. Unset
+/// fields are unlimited. Rates may burst to one second's worth.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Limits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_second: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connections_per_second: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tunnels: Option<usize>,
+    /// From the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_bytes_per_second: Option<u64>,
+    /// To the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_bytes_per_second: Option<u64>,
+}
+
+impl Limits {
+    fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// A request, connection or tunnel turned away by a limit.
+#[derive(Debug)]
+pub struct Limited {
+    limit: &'static str,
+    scope: String,
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} limit of {} reached", self.limit, self.scope)
+    }
+}
+
+impl Error for Limited {}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    /// From the client
+    Upload,
+    /// To the client
+    Download,
+}
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Bucket { rate, tokens: Self::burst(rate), updated: now }
+    }
+
+    fn burst(rate: f64) -> f64 {
+        rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(Self::burst(self.rate));
+        self.updated = now;
+    }
+
+    /// Takes one token if there is one.
+    fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes `amount` tokens, going into debt if needed, and returns how long until the debt
+    /// is paid off.
+    fn charge(&mut self, amount: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+struct Buckets {
+    limits: Limits,
+    requests: Option<Bucket>,
+    connections: Option<Bucket>,
+    upload: Option<Bucket>,
+    download: Option<Bucket>,
+    last_used: Instant,
+}
+
+impl Buckets {
+    fn new(limits: Limits, now: Instant) -> Self {
+        let bucket = |rate: Option<f64>| rate.map(|rate| Bucket::new(rate, now));
+        Buckets {
+            limits,
+            requests: bucket(limits.requests_per_second),
+            connections: bucket(limits.connections_per_second),
+            upload: bucket(limits.upload_bytes_per_second.map(|rate| rate as f64)),
+            download: bucket(limits.download_bytes_per_second.map(|rate| rate as f64)),
+            last_used: now,
+        }
+    }
+}
+
+/// The buckets and the open tunnels of one scope.
+struct Scope {
+    name: String,
+    buckets: Mutex<Buckets>,
+    tunnels: AtomicUsize,
+}
+
+impl Scope {
+    fn new(name: String, limits: Limits) -> Self {
+        Scope { name, buckets: Mutex::new(Buckets::new(limits, Instant::now())), tunnels: AtomicUsize::new(0) }
+    }
+
+    /// Starts over with new limits if they changed, the open tunnels stay counted.
+    fn set_limits(&self, limits: Limits) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.limits != limits {
+            *buckets = Buckets::new(limits, Instant::now());
+        }
+    }
+
+    fn take(&self, limit: &'static str, bucket: fn(&mut Buckets) -> Option<&mut Bucket>) -> Result<(), Limited> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.last_used = now;
+        match bucket(&mut buckets).map(|bucket| bucket.take(now)) {
+            Some(false) => Err(Limited { limit, scope: self.name.clone() }),
+            _ => Ok(()),
+        }
+    }
+
+    fn charge(&self, direction: Direction, amount: u64) -> Duration {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.last_used = now;
+        let bucket = match direction {
+            Direction::Upload => buckets.upload.as_mut(),
+            Direction::Download => buckets.download.as_mut(),
+        };
+        bucket.map_or(Duration::ZERO, |bucket| bucket.charge(amount, now))
+    }
+
+    fn is_paced(&self) -> bool {
+        let buckets = self.buckets.lock().unwrap();
+        buckets.upload.is_some() || buckets.download.is_some()
+    }
+
+    fn max_tunnels(&self) -> Option<usize> {
+        self.buckets.lock().unwrap().limits.max_tunnels
+    }
+
+    fn is_idle(self: &Arc<Self>) -> bool {
+        Arc::strong_count(self) == 1 && self.buckets.lock().unwrap().last_used.elapsed() >= CLIENT_IDLE
+    }
+}
+
+/// Rate limits and bandwidth shaping for the whole proxy, per client address and per user,
+/// shared by every listener.
+///
+/// New connections are checked against the global and per-client connection rates when they
+/// are accepted. Each HTTP request, SOCKS command and forwarded request then takes a request
+/// token from its user, client and global scopes, most specific first; a tunnel also takes
+/// one of the user's connection tokens and a tunnel slot in every scope. The bytes of a
+/// tunnel, or of a request and its response, are paced to the byte rates of those scopes.
+pub struct Limiter {
+    global: Arc<Scope>,
+    client_limits: Mutex<Limits>,
+    clients: Mutex<HashMap<IpAddr, Arc<Scope>>>,
+    /// Table size that triggers the next sweep of idle clients
+    sweep_at: AtomicUsize,
+    users: Mutex<HashMap<String, Arc<Scope>>>,
+}
+
+impl Limiter {
+    pub fn new(global: Limits, per_client: Limits) -> Self {
+        Limiter {
+            global: Arc::new(Scope::new("the proxy".to_string(), global)),
+            client_limits: Mutex::new(per_client),
+            clients: Mutex::new(HashMap::new()),
+            sweep_at: AtomicUsize::new(CLIENT_SWEEP_MIN),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies reloaded limits. Users' limits are picked up from their logins.
+    pub fn set_limits(&self, global: Limits, per_client: Limits) {
+        self.global.set_limits(global);
+        *self.client_limits.lock().unwrap() = per_client;
+        for scope in self.clients.lock().unwrap().values() {
+            scope.set_limits(per_client);
+        }
+    }
+
+    fn client(&self, ip: IpAddr) -> Option<Arc<Scope>> {
+        let limits = *self.client_limits.lock().unwrap();
+        if limits.is_unlimited() {
+            return None;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(scope) = clients.get(&ip) {
+            return Some(Arc::clone(scope));
+        }
+        if clients.len() >= self.sweep_at.load(Ordering::Relaxed) {
+            clients.retain(|_, scope| !scope.is_idle());
+            self.sweep_at.store((clients.len() * 2).max(CLIENT_SWEEP_MIN), Ordering::Relaxed);
+        }
+        let scope = Arc::new(Scope::new(format!("client {}", ip), limits));
+        clients.insert(ip, Arc::clone(&scope));
+        Some(scope)
+    }
+
+    fn user(&self, login: &Login) -> Option<Arc<Scope>> {
+        let limits = *login.limits();
+        if limits.is_unlimited() {
+            return None;
+        }
+        let mut users = self.users.lock().unwrap();
+        let scope = users
+            .entry(login.name().to_string())
+            .or_insert_with(|| Arc::new(Scope::new(format!("user {}", login.name()), limits)));
+        // The user may have been changed by a reload or the admin endpoint
+        scope.set_limits(limits);
+        Some(Arc::clone(scope))
+    }
+
+    /// Checks a newly accepted connection from `client`.
+    pub fn connect(&self, client: IpAddr) -> Result<(), Limited> {
+        if let Some(scope) = self.client(client) {
+            scope.take("connection rate", |buckets| buckets.connections.as_mut())?;
+        }
+        self.global.take("connection rate", |buckets| buckets.connections.as_mut())
+    }
+
+    /// Admits a request, or a tunnel if `tunnel`, of `client` and the user of `login`. Keep
+    /// the permit for as long as the tunnel is open.
+    pub fn admit(&self, client: Option<IpAddr>, login: Option<&Login>, tunnel: bool) -> Result<Permit, Limited> {
+        let user = login.and_then(|login| self.user(login));
+        let client = client.and_then(|client| self.client(client));
+        let scopes: Vec<Arc<Scope>> = user.iter().chain(client.iter()).cloned().chain([Arc::clone(&self.global)]).collect();
+
+        for scope in &scopes {
+            scope.take("request rate", |buckets| buckets.requests.as_mut())?;
+        }
+        if let (true, Some(user)) = (tunnel, &user) {
+            user.take("connection rate", |buckets| buckets.connections.as_mut())?;
+        }
+
+        let mut slots = Vec::new();
+        if tunnel {
+            for scope in &scopes {
+                let open = scope.tunnels.fetch_add(1, Ordering::Relaxed);
+                // Pushed first, so a refusal gives back the slots taken so far
+                slots.push(Arc::clone(scope));
+                if scope.max_tunnels().is_some_and(|max| open >= max) {
+                    drop(Permit(Arc::new(Held { paced: Vec::new(), slots })));
+                    return Err(Limited { limit: "tunnel", scope: scope.name.clone() });
+                }
+            }
+        }
+        let paced = scopes.into_iter().filter(|scope| scope.is_paced()).collect();
+        Ok(Permit(Arc::new(Held { paced, slots })))
+    }
+}
+
+struct Held {
+    /// Scopes with a byte rate
+    paced: Vec<Arc<Scope>>,
+    /// Scopes this tunnel is counted in
+    slots: Vec<Arc<Scope>>,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        for scope in &self.slots {
+            scope.tunnels.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An admitted request or tunnel. Clones share the tunnel slots, which are given back once
+/// the last of them is dropped.
+#[derive(Clone)]
+pub struct Permit(Arc<Held>);
+
+impl Permit {
+    /// Whether any byte rate applies, otherwise pacing can be skipped.
+    pub fn is_paced(&self) -> bool {
+        !self.0.paced.is_empty()
+    }
+
+    /// Counts `amount` bytes and returns how long to wait before moving more.
+    fn charge(&self, direction: Direction, amount: u64) -> Duration {
+        self.0.paced.iter().map(|scope| scope.charge(direction, amount)).max().unwrap_or_default()
+    }
+
+    /// Counts `amount` bytes and waits until they fit the byte rates.
+    pub async fn pace(&self, direction: Direction, amount: u64) {
+        let wait = self.charge(direction, amount);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// The client side of a tunnel, with reads paced to the upload rates and writes to the
+/// download rates of its permit.
+pub struct Shaped<S> {
+    inner: S,
+    permit: Permit,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Shaped<S> {
+    pub fn new(inner: S, permit: Permit) -> Self {
+        Shaped { inner, permit, read_wait: None, write_wait: None }
+    }
+}
+
+/// Waits out a pending delay, if any.
+fn poll_wait(wait: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = wait {
+        ready!(sleep.as_mut().poll(cx));
+        *wait = None;
+    }
+    Poll::Ready(())
+}
+
+fn delay(wait: Duration) -> Option<Pin<Box<Sleep>>> {
+    (!wait.is_zero()).then(|| Box::pin(sleep(wait)))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shaped<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_wait(&mut this.read_wait, cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - before;
+        // The bytes are passed on now and the next read waits for them
+        this.read_wait = delay(this.permit.charge(Direction::Upload, read as u64));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shaped<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_wait(&mut this.write_wait, cx));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.write_wait = delay(this.permit.charge(Direction::Download, written as u64));
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Auth, Credential, User, Users};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2.0, start);
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+        assert!(bucket.take(start + Duration::from_millis(500)));
+        // Bursts are capped at one second's worth
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(later) && bucket.take(later) && !bucket.take(later));
+
+        let mut bytes = Bucket::new(1000.0, start);
+        assert_eq!(bytes.charge(1000, start), Duration::ZERO);
+        assert_eq!(bytes.charge(500, start), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn scopes_are_checked_most_specific_first() {
+        let limiter = Limiter::new(
+            Limits { max_tunnels: Some(2), ..Default::default() },
+            Limits { requests_per_second: Some(1.0), ..Default::default() },
+        );
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let first = limiter.admit(Some(a), None, true).unwrap();
+        let err = limiter.admit(Some(a), None, true).err().unwrap();
+        assert_eq!(err.to_string(), "request rate limit of client 192.0.2.1 reached");
+        let second = limiter.admit(Some(b), None, true).unwrap();
+
+        let auth = Auth::new(Users::new(vec![User {
+            limits: Limits { max_tunnels: Some(1), ..Default::default() },
+            ..User::new("alice", Credential::Plain("pw".into()))
+        }]).unwrap());
+        let login = auth.login("alice", "pw").await.unwrap();
+        assert_eq!(limiter.admit(None, Some(&login), true).err().unwrap().to_string(), "tunnel limit of the proxy reached");
+        drop(first);
+        let third = limiter.admit(None, Some(&login), true).unwrap();
+        drop(second);
+        assert_eq!(limiter.admit(None, Some(&login), true).err().unwrap().to_string(), "tunnel limit of user alice reached");
+        // Plain requests don't take a tunnel slot
+        assert!(limiter.admit(None, Some(&login), false).is_ok());
+        drop(third);
+        assert!(limiter.admit(None, Some(&login), true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shaped_stream_keeps_to_the_rate() {
+        let limiter = Limiter::new(Limits { download_bytes_per_second: Some(1000), ..Default::default() }, Limits::default());
+        let permit = limiter.admit(None, None, true).unwrap();
+        let (client, mut far_end) = tokio::io::duplex(64 * 1024);
+        let mut shaped = Shaped::new(client, permit);
+
+        let started = Instant::now();
+        // A second's worth goes out at once, and one more on credit; after that one per second
+        for _ in 0..3 {
+            shaped.write_all(&[0; 1000]).await.unwrap();
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(950) && elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+        let mut received = vec![0; 3000];
+        far_end.read_exact(&mut received).await.unwrap();
+    }
+}
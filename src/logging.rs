@@ -0,0 +1,52 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+/// How log records are written to stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// One human-readable line per record, span fields in front of the message
+    #[default]
+    Text,
+    /// One JSON object per line, with the record's fields and those of its spans
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+/// Checks a level or filter directive such as `info` or `warn,http_proxy_ipv6_pool::proxy=debug`.
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(filter).map_err(|e| e.to_string())
+}
+
+/// Installs the global subscriber. Records below `filter` are dropped before they are
+/// formatted, so debug output costs little when it is off.
+pub fn init(filter: EnvFilter, format: LogFormat) {
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_target(false);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).init(),
+    }
+}
+
+/// The span a connection, or an HTTP request, is handled in. `user`, `target` and `egress`
+/// are recorded once they are known, so every record inside carries them.
+pub fn connection_span(protocol: &'static str, listener: SocketAddr, client: Option<IpAddr>) -> Span {
+    let span = info_span!("conn", protocol, %listener, client = Empty, user = Empty, target = Empty, egress = Empty);
+    if let Some(client) = client {
+        span.record("client", tracing::field::display(client));
+    }
+    span
+}
@@ -1,15 +1,51 @@
+mod access_log;
+mod acl;
+mod admin;
+mod auth;
+mod config;
 mod proxy;
+mod socks4;
 mod socks5;
+mod socks5_udp;
 mod forward;
+mod happy_eyeballs;
+mod lease;
+mod limit;
+mod logging;
+mod metrics;
+mod mixed;
+mod nat64;
+mod netlink;
+mod pool;
+mod reload;
+mod resolver;
+mod routing;
+mod session;
+mod shutdown;
+mod strategy;
 
-use cidr::{Ipv4Cidr, Ipv6Cidr};
 use getopts::Options;
+use admin::start_admin;
+use auth::Auth;
+use arc_swap::ArcSwap;
+use config::{load_settings, options, SharedForward, SharedListener};
 use proxy::start_proxy;
 use socks5::start_socks5_proxy;
-use std::{env, net::IpAddr, net::SocketAddr, process::exit};
+use std::{env, process::exit};
 use std::sync::Arc;
-use std::time::Duration;
-use forward::{parse_forward_mapping, start_forward_proxy};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+use forward::start_forward_proxy;
+use lease::LeaseTable;
+use limit::Limiter;
+use metrics::start_metrics;
+use mixed::start_mixed_proxy;
+use netlink::NetlinkBackend;
+use pool::{AddressPool, SystemRoute};
+use reload::Reloader;
+use resolver::Resolver;
+use session::SessionStore;
+use strategy::{AddressDeriver, Strategy};
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
     print!("{}", opts.usage(&brief));
@@ -20,48 +56,7 @@ async fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
-    let mut opts = Options::new();
-    opts.optopt("b", "bind", "HTTP proxy bind address", "BIND");
-    opts.optopt(
-        "i",
-        "ipv6-subnets",
-        "Comma-separated list of IPv6 subnets (e.g., 2001:19f0:6001:48e4::/64,2001:19f0:6001:48e5::/64)",
-        "IPv6_SUBNETS",
-    );
-    opts.optopt(
-        "v",
-        "ipv4-subnets",
-        "Comma-separated list of IPv4 subnets (e.g., 192.168.0.0/24,192.168.1.0/24)",
-        "IPv4_SUBNETS",
-    );
-    opts.optopt(
-        "a",
-        "allowed-ips",
-        "Comma-separated list of allowed IP addresses",
-        "ALLOWED_IPS",
-    );
-    opts.optopt(
-        "S",
-        "socks5",
-        "SOCKS5 proxy bind address (e.g., 127.0.0.1:51081)",
-        "SOCKS5_ADDR",
-    );
-    opts.optopt("u", "username", "Username for SOCKS5 authentication", "USERNAME");
-    opts.optopt("p", "password", "Password for SOCKS5 authentication", "PASSWORD");
-    opts.optopt("t", "timeout", "Timeout duration in seconds", "TIMEOUT");  // 新增-t参数
-    opts.optflag("h", "help", "Print this help menu");
-    opts.optopt("r", "system_route", "Whether to use system routing instead of ndpdd. (Provide network card interface, such as eth0)", "Network Interface");
-    opts.optopt("g", "gateway", "Some service providers need to track the route before it takes effect.", "Gateway");
-
-
-    // 新增的 --forward 参数
-    opts.optmulti(
-        "",
-        "forward",
-        "Forwarding mapping in the format local_addr,remote_addr,sni_host[,proxy_addr1|proxy_addr2|...,proxy_type]",
-        "FORWARD",
-    );
-
+    let opts = options();
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -77,125 +72,179 @@ async fn main() {
         return;
     }
 
-    let system_route = matches.opt_str("r").unwrap_or_else(|| "".to_string());
-    println!("System route option received: {}", system_route);
-
-    let gateway = matches.opt_str("g").unwrap_or_else(|| "".to_string());
-    println!("Gateway: {}", gateway);
-
-    let bind_addr = matches.opt_str("b").unwrap_or_else(|| "0.0.0.0:51080".to_string());
-    let socks5_bind_addr = matches.opt_str("S").unwrap_or_else(|| "127.0.0.1:51081".to_string());
-
-    let ipv6_subnets = matches
-        .opt_str("i")
-        .map(|s| parse_subnets::<Ipv6Cidr>(&s))
-        .unwrap_or_else(Vec::new);
-
-    let ipv4_subnets = matches
-        .opt_str("v")
-        .map(|s| parse_subnets::<Ipv4Cidr>(&s))
-        .unwrap_or_else(Vec::new);
-
-    let allowed_ips = matches.opt_str("a")
-        .map(|s| parse_allowed_ips(&s));
-
-    let username = matches.opt_str("u").unwrap_or_else(|| "".to_string());
-    let password = matches.opt_str("p").unwrap_or_else(|| "".to_string());
+    let settings = match load_settings(&matches) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    // Checked by load_settings
+    logging::init(logging::parse_filter(&settings.log_level).unwrap(), settings.log_format);
+    if let Some(path) = &settings.access_log {
+        if let Err(e) = access_log::init(path) {
+            error!("Failed to open access log {}: {}", path.display(), e);
+            exit(1);
+        }
+        access_log::spawn_sigusr1();
+    }
 
-    // Parse the timeout duration from the command line arguments
-    let timeout_duration = matches.opt_str("t")
-        .and_then(|t| t.parse::<u64>().ok())
-        .map(Duration::from_secs)
-        .unwrap_or(Duration::from_secs(5));  // Default to 5 seconds if not specified
+    let sessions = SessionStore::new(settings.session_ttl);
 
-    let bind_addr = match bind_addr.parse() {
-        Ok(b) => b,
-        Err(e) => {
-            println!("Bind address not valid: {}", e);
-            return;
+    let hash_secret = settings.hash_secret.clone().unwrap_or_else(|| {
+        let strategies = settings.http.iter().chain(&settings.socks5).chain(&settings.mixed).map(|l| l.strategy);
+        if strategies.chain(settings.forward.iter().map(|f| f.strategy)).any(|s| s != Strategy::Random) {
+            warn!("No --hash-secret given, hash-based addresses will change on restart");
+        }
+        rand::random::<[u8; 32]>().iter().map(|b| format!("{:02x}", b)).collect()
+    });
+    let deriver = AddressDeriver::new(hash_secret);
+
+    let mut prune_stale = false;
+    let system_route = match &settings.system_route {
+        None => None,
+        Some(route) => {
+            info!(interface = %route.interface, gateway = %route.gateway, "System route enabled");
+            prune_stale = route.prune_stale;
+            let leases = Arc::new(LeaseTable::new(route.lease_ttl, route.max_addresses, route.lease_file.clone()));
+            leases.spawn_writer();
+            match NetlinkBackend::new(&route.interface).await {
+                Ok(backend) => Some(SystemRoute { backend: Box::new(backend), gateway: route.gateway.clone(), leases }),
+                Err(e) => {
+                    error!("System route setup failed: {}", e);
+                    exit(1);
+                }
+            }
         }
     };
+    let pool = Arc::new(AddressPool::new(
+        settings.ipv6_subnets.clone(),
+        settings.ipv4_subnets.clone(),
+        sessions,
+        deriver,
+        system_route,
+    ));
+    pool.restore_leases().await;
+    match pool.reconcile(prune_stale).await {
+        Ok(stale) if stale.is_empty() => {}
+        Ok(stale) if prune_stale => {
+            info!("Removed {} stale addresses from the interface", stale.len());
+        }
+        Ok(stale) => {
+            warn!("{} stale addresses inside the subnets are on the interface, use --prune-stale to remove them", stale.len());
+        }
+        Err(e) => warn!("Failed to check the interface for stale addresses: {}", e),
+    }
+    pool.spawn_reclaimer();
+    info!("Address pool: {}", pool.stats());
 
-    let socks5_bind_addr = match socks5_bind_addr.parse::<SocketAddr>() {
-        Ok(b) => b,
+    let auth = Arc::new(Auth::new(settings.users));
+    let limiter = Arc::new(Limiter::new(settings.limits, settings.client_limits));
+    if auth.is_enabled() {
+        info!("{} proxy users", auth.user_count());
+    }
+
+    let timeout_duration = settings.timeout;
+    let resolver = match Resolver::new(settings.dns, &pool, timeout_duration).await {
+        Ok(resolver) => Arc::new(resolver),
         Err(e) => {
-            println!("SOCKS5 bind address not valid: {}", e);
-            return;
+            error!("DNS resolver setup failed: {}", e);
+            exit(1);
         }
     };
-
-    // 解析并存储代理映射
-    let forward_mappings = matches
-        .opt_strs("forward")
-        .into_iter()
-        .filter_map(|mapping_str| parse_forward_mapping(&mapping_str))
-        .collect::<Vec<_>>();
+    let mut listeners = JoinSet::new();
+    let forwards: Vec<SharedForward> = shared(settings.forward);
+    let http_listeners: Vec<SharedListener> = shared(settings.http);
+    let socks5_listeners: Vec<SharedListener> = shared(settings.socks5);
+    let mixed_listeners: Vec<SharedListener> = shared(settings.mixed);
 
     // 启动代理映射任务
-    for mapping in forward_mappings {
-        let ipv6_subnets = ipv6_subnets.clone();
-        let ipv4_subnets = ipv4_subnets.clone();
-        let allowed_ips = allowed_ips.clone();
-
-        tokio::spawn(async move {
-            if let Err(e) = start_forward_proxy(
-                mapping.clone(),                       // 克隆 mapping
-                Arc::from(ipv6_subnets),             // 克隆 Arc
-                Arc::from(ipv4_subnets),             // 克隆 Arc
-                allowed_ips.clone(),                   // 克隆 allowed_ips
-                timeout_duration,                      // Copy 类型，无需克隆
-            )
-                .await
-            {
-                eprintln!(
-                    "Forward proxy for {} encountered an error: {}",
-                    mapping.local_addr, e
-                );
+    for forward in &forwards {
+        let pool = Arc::clone(&pool);
+        let forward = Arc::clone(forward);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
+        listeners.spawn(async move {
+            let local_addr = forward.load().mapping.local_addr;
+            if let Err(e) = start_forward_proxy(forward, pool, limiter, resolver, timeout_duration).await {
+                error!("Forward proxy for {} encountered an error: {}", local_addr, e);
             }
         });
     }
 
+    // 启动HTTP代理和SOCKS5代理
+    for listener in &http_listeners {
+        let pool = Arc::clone(&pool);
+        let listener = Arc::clone(listener);
+        let auth = Arc::clone(&auth);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
+        listeners.spawn(async move {
+            let bind = listener.load().bind;
+            if let Err(e) = start_proxy(pool, listener, auth, limiter, resolver, timeout_duration).await {
+                error!("HTTP Proxy on {} encountered an error: {}", bind, e);
+            }
+        });
+    }
+    for listener in &socks5_listeners {
+        let pool = Arc::clone(&pool);
+        let listener = Arc::clone(listener);
+        let auth = Arc::clone(&auth);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
+        listeners.spawn(async move {
+            let bind = listener.load().bind;
+            if let Err(e) = start_socks5_proxy(pool, listener, auth, limiter, resolver, timeout_duration).await {
+                error!("SOCKS5 Proxy on {} encountered an error: {}", bind, e);
+            }
+        });
+    }
+    for listener in &mixed_listeners {
+        let pool = Arc::clone(&pool);
+        let listener = Arc::clone(listener);
+        let auth = Arc::clone(&auth);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
+        listeners.spawn(async move {
+            let bind = listener.load().bind;
+            if let Err(e) = start_mixed_proxy(pool, listener, auth, limiter, resolver, timeout_duration).await {
+                error!("Mixed proxy on {} encountered an error: {}", bind, e);
+            }
+        });
+    }
 
+    if let Some(metrics) = settings.metrics {
+        let pool = Arc::clone(&pool);
+        listeners.spawn(async move {
+            if let Err(e) = start_metrics(metrics, pool).await {
+                error!("Metrics endpoint on {} encountered an error: {}", metrics, e);
+            }
+        });
+    }
 
-    let ipv6_subnets = Arc::new(ipv6_subnets);
-    let ipv4_subnets = Arc::new(ipv4_subnets);
-
-    // 启动HTTP代理和SOCKS5代理，并处理结果
-    let (http_result, socks5_result) = tokio::join!(
-        start_proxy(
-            bind_addr,
-            !system_route.is_empty(),
-            gateway.clone(),
-            system_route.clone(),
-            ipv6_subnets.clone(),
-            ipv4_subnets.clone(),
-            allowed_ips.clone(),
-            username.clone(),
-            password.clone(),
-            timeout_duration  // 传递timeout_duration
-        ),
-        start_socks5_proxy(socks5_bind_addr, ipv6_subnets, ipv4_subnets, allowed_ips, username, password, timeout_duration)
-    );
-
-    if let Err(e) = http_result {
-        eprintln!("HTTP Proxy encountered an error: {}", e);
+    let reloader = Arc::new(Reloader::new(matches, Arc::clone(&pool), Arc::clone(&auth), limiter, http_listeners, socks5_listeners, mixed_listeners, forwards));
+    reloader.spawn_sighup();
+    if let Some(admin) = settings.admin {
+        let reloader = Arc::clone(&reloader);
+        let pool = Arc::clone(&pool);
+        let token = settings.admin_token.clone();
+        listeners.spawn(async move {
+            if let Err(e) = start_admin(admin, token, reloader, pool, auth).await {
+                error!("Admin endpoint on {} encountered an error: {}", admin, e);
+            }
+        });
     }
 
-    if let Err(e) = socks5_result {
-        eprintln!("SOCKS5 Proxy encountered an error: {}", e);
+    // Aborting the listener tasks stops accepting; connections already spawned keep running
+    tokio::select! {
+        _ = async { while listeners.join_next().await.is_some() {} } => {}
+        _ = shutdown::wait_for_signal() => {}
     }
-}
+    listeners.abort_all();
 
-fn parse_subnets<C: std::str::FromStr>(subnets_str: &str) -> Vec<C> {
-    subnets_str
-        .split(',')
-        .filter_map(|subnet_str| subnet_str.parse::<C>().ok())
-        .collect()
+    shutdown::drain(&pool, settings.shutdown_timeout).await;
 }
 
-fn parse_allowed_ips(allowed_ips_str: &str) -> Vec<IpAddr> {
-    allowed_ips_str
-        .split(',')
-        .filter_map(|ip_str| ip_str.parse::<IpAddr>().ok())
-        .collect()
+fn shared<T>(items: Vec<T>) -> Vec<Arc<ArcSwap<T>>> {
+    items.into_iter().map(|item| Arc::new(ArcSwap::from_pointee(item))).collect()
 }
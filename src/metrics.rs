@@ -0,0 +1,330 @@
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::info;
+
+use crate::happy_eyeballs::ConnectError;
+use crate::pool::AddressPool;
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::new();
+}
+
+struct Metrics {
+    registry: Registry,
+    connections: IntCounterVec,
+    requests: IntCounterVec,
+    active_tunnels: IntGaugeVec,
+    bytes: IntCounterVec,
+    connect_seconds: HistogramVec,
+    errors: IntCounterVec,
+    pool_subnets: IntGaugeVec,
+    system_route_addresses: IntGauge,
+    system_route_addresses_in_use: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let labels = &["listener", "protocol"];
+        let metrics = Metrics {
+            connections: IntCounterVec::new(Opts::new("proxy_connections_total", "Client connections accepted"), labels).unwrap(),
+            requests: IntCounterVec::new(
+                Opts::new("proxy_requests_total", "Requests by command: connect, request (plain HTTP), bind or udp_associate"),
+                &["listener", "protocol", "command"],
+            )
+            .unwrap(),
+            active_tunnels: IntGaugeVec::new(Opts::new("proxy_active_tunnels", "Tunnels currently relaying"), labels).unwrap(),
+            bytes: IntCounterVec::new(
+                Opts::new("proxy_tunnel_bytes_total", "Bytes relayed by closed tunnels, `in` from the client and `out` to it"),
+                &["listener", "protocol", "direction"],
+            )
+            .unwrap(),
+            connect_seconds: HistogramVec::new(
+                HistogramOpts::new("proxy_connect_duration_seconds", "Time to establish the outgoing connection of a tunnel")
+                    .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+                labels,
+            )
+            .unwrap(),
+            errors: IntCounterVec::new(Opts::new("proxy_errors_total", "Failed requests by cause"), &["listener", "protocol", "cause"]).unwrap(),
+            pool_subnets: IntGaugeVec::new(Opts::new("proxy_pool_subnets", "Configured egress subnets"), &["family"]).unwrap(),
+            system_route_addresses: IntGauge::new("proxy_pool_system_route_addresses", "Addresses currently added to the system-route interface").unwrap(),
+            system_route_addresses_in_use: IntGauge::new(
+                "proxy_pool_system_route_addresses_in_use",
+                "System-route addresses with an open connection",
+            )
+            .unwrap(),
+            registry,
+        };
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(metrics.connections.clone()),
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.active_tunnels.clone()),
+            Box::new(metrics.bytes.clone()),
+            Box::new(metrics.connect_seconds.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.pool_subnets.clone()),
+            Box::new(metrics.system_route_addresses.clone()),
+            Box::new(metrics.system_route_addresses_in_use.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+}
+
+/// Why a request failed, the `cause` label of `proxy_errors_total`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cause {
+    /// Missing or wrong credentials, or a user over its limits
+    Auth,
+    /// Client allow-list or destination rules
+    Denied,
+    /// A rate or tunnel limit, see `Limiter`
+    Limited,
+    /// Name resolution failed, or no address of a usable family
+    Unreachable,
+    Refused,
+    Timeout,
+    /// No egress address could be taken from the pool
+    Pool,
+    /// A command or address type the proxy doesn't serve
+    Unsupported,
+    /// A malformed request
+    Protocol,
+    Io,
+}
+
+impl Cause {
+    fn as_str(self) -> &'static str {
+        match self {
+            Cause::Auth => "auth",
+            Cause::Denied => "denied",
+            Cause::Limited => "limited",
+            Cause::Unreachable => "unreachable",
+            Cause::Refused => "refused",
+            Cause::Timeout => "timeout",
+            Cause::Pool => "pool",
+            Cause::Unsupported => "unsupported",
+            Cause::Protocol => "protocol",
+            Cause::Io => "io",
+        }
+    }
+
+    /// The cause of an error returned by a connection handler: a `Failure` says it, timeouts
+    /// and I/O errors are recognized, anything else is a protocol error.
+    pub fn of(error: &(dyn Error + 'static)) -> Self {
+        if let Some(failure) = error.downcast_ref::<Failure>() {
+            failure.cause
+        } else if error.is::<tokio::time::error::Elapsed>() {
+            Cause::Timeout
+        } else if let Some(e) = error.downcast_ref::<io::Error>() {
+            Cause::from(e)
+        } else {
+            Cause::Protocol
+        }
+    }
+}
+
+impl From<&io::Error> for Cause {
+    fn from(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Cause::Refused,
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => Cause::Unreachable,
+            io::ErrorKind::TimedOut => Cause::Timeout,
+            _ => Cause::Io,
+        }
+    }
+}
+
+impl From<&ConnectError> for Cause {
+    fn from(error: &ConnectError) -> Self {
+        match error {
+            ConnectError::NoUsableAddress => Cause::Unreachable,
+            ConnectError::Pool(_) => Cause::Pool,
+            ConnectError::Io(e) => Cause::from(e),
+        }
+    }
+}
+
+/// An error with its cause, for handlers that return `Box<dyn Error>`.
+#[derive(Debug)]
+pub struct Failure {
+    pub cause: Cause,
+    /// The SOCKS reply code the client was sent, if any
+    pub reply: Option<u8>,
+    message: String,
+}
+
+impl Failure {
+    pub fn new(cause: Cause, message: String) -> Self {
+        Failure { cause, reply: None, message }
+    }
+
+    /// Records that the client was told with reply code `code`.
+    pub fn replied(mut self, code: u8) -> Self {
+        self.reply = Some(code);
+        self
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// The metrics of one protocol on one listener, labelled with the listener's address.
+#[derive(Clone)]
+pub struct ListenerMetrics {
+    listener: String,
+    protocol: &'static str,
+}
+
+impl ListenerMetrics {
+    pub fn new(listener: SocketAddr, protocol: &'static str) -> Self {
+        ListenerMetrics { listener: listener.to_string(), protocol }
+    }
+
+    pub fn connection(&self) {
+        METRICS.connections.with_label_values(&[&self.listener, self.protocol]).inc();
+    }
+
+    pub fn request(&self, command: &str) {
+        METRICS.requests.with_label_values(&[&self.listener, self.protocol, command]).inc();
+    }
+
+    pub fn error(&self, cause: Cause) {
+        METRICS.errors.with_label_values(&[&self.listener, self.protocol, cause.as_str()]).inc();
+    }
+
+    /// Records how long the outgoing connection of a tunnel took.
+    pub fn connected(&self, elapsed: Duration) {
+        METRICS.connect_seconds.with_label_values(&[&self.listener, self.protocol]).observe(elapsed.as_secs_f64());
+    }
+
+    /// `copy_bidirectional` between the client and the target, counted as an active tunnel
+    /// while it runs and its byte totals once it ends.
+    pub async fn relay<C, T>(&self, client: &mut C, target: &mut T) -> io::Result<(u64, u64)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+        T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    {
+        let active = METRICS.active_tunnels.with_label_values(&[&self.listener, self.protocol]);
+        active.inc();
+        // Decremented on every way out, including the caller's timeout dropping this future
+        let _active = scopeguard::guard(active, |active| active.dec());
+        let (from_client, from_target) = tokio::io::copy_bidirectional(client, target).await?;
+        self.bytes(from_client, from_target);
+        Ok((from_client, from_target))
+    }
+
+    /// Counts the bytes of a relay that didn't go through `relay`.
+    pub fn bytes(&self, from_client: u64, to_client: u64) {
+        METRICS.bytes.with_label_values(&[&self.listener, self.protocol, "in"]).inc_by(from_client);
+        METRICS.bytes.with_label_values(&[&self.listener, self.protocol, "out"]).inc_by(to_client);
+    }
+}
+
+/// The text exposition of every metric, with the pool's gauges read now.
+fn render(pool: &AddressPool) -> String {
+    let stats = pool.stats();
+    METRICS.pool_subnets.with_label_values(&["ipv6"]).set(stats.ipv6_subnets as i64);
+    METRICS.pool_subnets.with_label_values(&["ipv4"]).set(stats.ipv4_subnets as i64);
+    METRICS.system_route_addresses.set(stats.added_addresses as i64);
+    METRICS.system_route_addresses_in_use.set(stats.active_addresses as i64);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Serves `GET /metrics` in the Prometheus text format.
+pub async fn start_metrics(listen_addr: SocketAddr, pool: Arc<AddressPool>) -> Result<(), Box<dyn Error>> {
+    let make_service = make_service_fn(move |_| {
+        let pool = Arc::clone(&pool);
+        async move { Ok::<_, hyper::Error>(service_fn(move |req| handle(req, Arc::clone(&pool)))) }
+    });
+
+    info!("Metrics endpoint listening on {}", listen_addr);
+    Server::bind(&listen_addr).serve(make_service).await.map_err(|err| err.into())
+}
+
+async fn handle(req: Request<Body>, pool: Arc<AddressPool>) -> Result<Response<Body>, hyper::Error> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Body::from(render(&pool))),
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not Found\n")),
+    };
+    Ok(response.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn causes_are_recognized() {
+        let failure: Box<dyn Error> = Failure::new(Cause::Denied, "not allowed".to_string()).into();
+        assert_eq!(Cause::of(&*failure), Cause::Denied);
+        let refused: Box<dyn Error> = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert_eq!(Cause::of(&*refused), Cause::Refused);
+        let other: Box<dyn Error> = "Unsupported SOCKS version".into();
+        assert_eq!(Cause::of(&*other), Cause::Protocol);
+    }
+
+    #[tokio::test]
+    async fn tunnels_are_counted() {
+        let metrics = ListenerMetrics::new("127.0.0.1:1".parse().unwrap(), "socks5");
+        metrics.connection();
+        metrics.request("connect");
+        metrics.connected(Duration::from_millis(20));
+        metrics.error(Cause::Timeout);
+
+        let (mut client, mut client_end) = tokio::io::duplex(64);
+        let (mut target, mut target_end) = tokio::io::duplex(64);
+        let relay = tokio::spawn({
+            let metrics = metrics.clone();
+            async move { metrics.relay(&mut client_end, &mut target_end).await.unwrap() }
+        });
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        target.read_exact(&mut buf).await.unwrap();
+        target.write_all(b"pong!").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        drop((client, target));
+        assert_eq!(relay.await.unwrap(), (4, 5));
+
+        let pool = AddressPool::for_tests(&["2001:db8::/48"], &[]);
+        let text = render(&pool);
+        let labels = r#"listener="127.0.0.1:1",protocol="socks5""#;
+        for line in [
+            format!("proxy_connections_total{{{}}} 1", labels),
+            format!(r#"proxy_requests_total{{command="connect",{}}} 1"#, labels),
+            format!(r#"proxy_errors_total{{cause="timeout",{}}} 1"#, labels),
+            format!("proxy_active_tunnels{{{}}} 0", labels),
+            format!(r#"proxy_tunnel_bytes_total{{direction="in",{}}} 4"#, labels),
+            format!(r#"proxy_tunnel_bytes_total{{direction="out",{}}} 5"#, labels),
+            format!("proxy_connect_duration_seconds_count{{{}}} 1", labels),
+            r#"proxy_pool_subnets{family="ipv6"} 1"#.to_string(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} missing from\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use tokio::net::TcpListener;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, info};
+
+use crate::auth::Auth;
+use crate::config::SharedListener;
+use crate::limit::Limiter;
+use crate::metrics::{Cause, ListenerMetrics};
+use crate::pool::AddressPool;
+use crate::proxy::serve_connection;
+use crate::resolver::Resolver;
+use crate::shutdown;
+use crate::socks4::SOCKS4_VERSION;
+use crate::socks5::handle_socks5_connection;
+
+const SOCKS5_VERSION: u8 = 0x05;
+
+/// One port for HTTP, SOCKS5 and SOCKS4/4a: the first byte of each connection picks the
+/// protocol, 0x05 for SOCKS5, 0x04 for SOCKS4 and anything else (an HTTP method) for HTTP.
+/// The byte is only peeked, so the chosen handler sees the connection from the start.
+pub async fn start_mixed_proxy(
+    pool: Arc<AddressPool>,
+    settings: SharedListener,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    resolver: Arc<Resolver>,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let listen_addr = settings.load().bind;
+    let listener = TcpListener::bind(listen_addr).await?;
+    info!("Mixed HTTP/SOCKS proxy listening on {}", listen_addr);
+
+    loop {
+        let (mut socket, addr) = listener.accept().await?;
+        // Settings are read per connection so a reload applies to the next one
+        let current = settings.load_full();
+        if shutdown::is_draining() {
+            debug!(client = %addr.ip(), listener = %listen_addr, "Draining, connection turned away");
+            continue;
+        }
+        let pool = Arc::clone(&pool);
+        let auth = Arc::clone(&auth);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
+
+        tokio::spawn(async move {
+            let mut first = [0; 1];
+            match timeout(timeout_duration, socket.peek(&mut first)).await {
+                Ok(Ok(1)) => {}
+                // Closed or silent before sending anything
+                _ => return,
+            }
+
+            let protocol = match first[0] {
+                SOCKS5_VERSION => "socks5",
+                SOCKS4_VERSION => "socks4",
+                _ => "http",
+            };
+            // Checked once the protocol is known, so the refusal is counted under it
+            if let Err(e) = limiter.connect(addr.ip()) {
+                debug!(client = %addr.ip(), listener = %current.bind, protocol, "Connection turned away: {}", e);
+                ListenerMetrics::new(current.bind, protocol).error(Cause::Limited);
+                return;
+            }
+
+            if protocol != "http" {
+                // HTTP clients get a 403 from the proxy service, SOCKS clients can only be dropped
+                if !current.acl.allows(addr.ip()) {
+                    info!(client = %addr.ip(), listener = %current.bind, protocol, "Access denied for client");
+                    ListenerMetrics::new(current.bind, protocol).error(Cause::Denied);
+                    return;
+                }
+                // Failures are logged and counted by handle_socks5_connection
+                let _ = handle_socks5_connection(&mut socket, &pool, &current, &auth, &limiter, &resolver, timeout_duration).await;
+            } else if let Err(e) = serve_connection(socket, addr, pool, current, auth, limiter, resolver, timeout_duration).await {
+                debug!(client = %addr.ip(), "Failed to serve HTTP connection: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Users;
+    use crate::config::Listener;
+    use crate::limit::Limits;
+    use arc_swap::ArcSwap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn dispatches_on_first_byte() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port().to_be_bytes();
+        // A free port for the proxy
+        let bind = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let pool = Arc::new(AddressPool::for_tests(&[], &["127.0.0.0/24"]));
+        let settings = Arc::new(ArcSwap::from_pointee(Listener { bind, ..Listener::for_tests() }));
+        let auth = Arc::new(Auth::new(Users::default()));
+        tokio::spawn(async move {
+            let _ = start_mixed_proxy(pool, settings, auth, Arc::new(Limiter::new(Limits::default(), Limits::default())), Arc::new(Resolver::for_tests()), Duration::from_secs(5)).await;
+        });
+        // The proxy is up once it accepts a connection
+        let mut client = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match TcpStream::connect(bind).await {
+                    Ok(client) => break client,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        // SOCKS5
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0; 10];
+        client.read_exact(&mut buf[..2]).await.unwrap();
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[1], 0x00);
+        target.accept().await.unwrap();
+
+        // SOCKS4
+        let mut client = TcpStream::connect(bind).await.unwrap();
+        client.write_all(&[4, 1, port[0], port[1], 127, 0, 0, 1, 0]).await.unwrap();
+        client.read_exact(&mut buf[..8]).await.unwrap();
+        assert_eq!(buf[1], 90);
+        target.accept().await.unwrap();
+
+        // HTTP CONNECT
+        let mut client = TcpStream::connect(bind).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        client.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr).as_bytes()).await.unwrap();
+        target.accept().await.unwrap();
+        let mut response = vec![0; 12];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200");
+    }
+}
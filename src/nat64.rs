@@ -0,0 +1,93 @@
+use cidr::Ipv6Cidr;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// The well-known prefix of RFC 6052.
+pub const WELL_KNOWN_PREFIX: &str = "64:ff9b::/96";
+
+/// A NAT64 prefix; IPv4 addresses are embedded in it as RFC 6052 section 2.2 describes, which
+/// allows prefix lengths of 32, 40, 48, 56, 64 and 96 bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Nat64Prefix(Ipv6Cidr);
+
+impl FromStr for Nat64Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prefix: Ipv6Cidr = s.trim().parse().map_err(|e| format!("{}", e))?;
+        if ![32, 40, 48, 56, 64, 96].contains(&prefix.network_length()) {
+            return Err("NAT64 prefix must be a /32, /40, /48, /56, /64 or /96".to_string());
+        }
+        Ok(Nat64Prefix(prefix))
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Nat64Prefix {
+    /// The IPv6 address the NAT64 gateway translates to `ip`.
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.0.first_address().octets();
+        for (position, octet) in self.positions().zip(ip.octets()) {
+            octets[position] = octet;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    /// The IPv4 address embedded in `ip`, if it lies inside the prefix.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        if !self.0.contains(&ip) {
+            return None;
+        }
+        let octets = ip.octets();
+        let mut ipv4 = [0; 4];
+        for (octet, position) in ipv4.iter_mut().zip(self.positions()) {
+            *octet = octets[position];
+        }
+        Some(Ipv4Addr::from(ipv4))
+    }
+
+    /// Where the four IPv4 octets go: right after the prefix, skipping bits 64 to 71, which
+    /// must stay zero.
+    fn positions(&self) -> impl Iterator<Item = usize> {
+        let start = self.0.network_length() as usize / 8;
+        (start..16).filter(|position| *position != 8).take(4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6052_examples() {
+        let ip: Ipv4Addr = "192.0.2.33".parse().unwrap();
+        for (prefix, embedded) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            (WELL_KNOWN_PREFIX, "64:ff9b::192.0.2.33"),
+        ] {
+            let prefix: Nat64Prefix = prefix.parse().unwrap();
+            let embedded: Ipv6Addr = embedded.parse().unwrap();
+            assert_eq!(prefix.embed(ip), embedded, "{}", prefix);
+            assert_eq!(prefix.extract(embedded), Some(ip), "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn other_lengths_and_addresses_are_rejected() {
+        assert!("64:ff9b::/80".parse::<Nat64Prefix>().is_err());
+        assert!("10.0.0.0/8".parse::<Nat64Prefix>().is_err());
+        let prefix: Nat64Prefix = WELL_KNOWN_PREFIX.parse().unwrap();
+        assert_eq!(prefix.extract("2001:db8::1".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,128 @@
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use netlink_packet_route::nlas::address::Nla;
+use rtnetlink::Handle;
+use std::error::Error;
+use std::net::IpAddr;
+
+/// Errors from an address backend; `Send + Sync` so they can cross spawned tasks.
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+const EEXIST: i32 = 17;
+const EADDRNOTAVAIL: i32 = 99;
+
+/// Adds and removes single-host addresses (`/128` or `/32`) on the system-route interface.
+///
+/// Both calls return only once the kernel has confirmed the change, so an address is usable
+/// for `bind` as soon as `add` returns.
+#[async_trait]
+pub trait AddressBackend: Send + Sync {
+    async fn add(&self, ip: IpAddr) -> Result<(), BackendError>;
+    async fn remove(&self, ip: IpAddr) -> Result<(), BackendError>;
+    /// Single-host addresses currently on the interface.
+    async fn list(&self) -> Result<Vec<IpAddr>, BackendError>;
+}
+
+/// `AddressBackend` talking rtnetlink to the kernel directly.
+pub struct NetlinkBackend {
+    handle: Handle,
+    index: u32,
+}
+
+impl NetlinkBackend {
+    /// Opens a netlink connection and resolves `interface` to its link index.
+    pub async fn new(interface: &str) -> Result<Self, BackendError> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+
+        let link = handle
+            .link()
+            .get()
+            .match_name(interface.to_string())
+            .execute()
+            .try_next()
+            .await
+            .map_err(|e| format!("Interface {} not found: {}", interface, e))?
+            .ok_or_else(|| format!("Interface {} not found", interface))?;
+
+        Ok(NetlinkBackend {
+            handle,
+            index: link.header.index,
+        })
+    }
+}
+
+#[async_trait]
+impl AddressBackend for NetlinkBackend {
+    async fn add(&self, ip: IpAddr) -> Result<(), BackendError> {
+        match self.handle.address().add(self.index, ip, host_prefix(ip)).execute().await {
+            Ok(()) => Ok(()),
+            // Already present, e.g. a sticky address picked again
+            Err(e) if netlink_errno(&e) == Some(EEXIST) => Ok(()),
+            Err(e) => Err(format!("Failed to add {}: {}", ip, e).into()),
+        }
+    }
+
+    async fn remove(&self, ip: IpAddr) -> Result<(), BackendError> {
+        let mut addresses = self
+            .handle
+            .address()
+            .get()
+            .set_link_index_filter(self.index)
+            .set_address_filter(ip)
+            .execute();
+
+        while let Some(message) = addresses.try_next().await? {
+            let is_host_address = message.header.prefix_len == host_prefix(ip)
+                && message.nlas.iter().any(|nla| matches!(nla, Nla::Address(bytes) if parse_ip(bytes) == Some(ip)));
+            if !is_host_address {
+                continue;
+            }
+            match self.handle.address().del(message).execute().await {
+                Ok(()) => {}
+                Err(e) if netlink_errno(&e) == Some(EADDRNOTAVAIL) => {}
+                Err(e) => return Err(format!("Failed to remove {}: {}", ip, e).into()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<IpAddr>, BackendError> {
+        let mut addresses = self.handle.address().get().set_link_index_filter(self.index).execute();
+
+        let mut found = Vec::new();
+        while let Some(message) = addresses.try_next().await? {
+            let ip = message.nlas.iter().find_map(|nla| match nla {
+                Nla::Address(bytes) => parse_ip(bytes),
+                _ => None,
+            });
+            if let Some(ip) = ip.filter(|ip| message.header.prefix_len == host_prefix(*ip)) {
+                found.push(ip);
+            }
+        }
+        Ok(found)
+    }
+}
+
+fn host_prefix(ip: IpAddr) -> u8 {
+    if ip.is_ipv6() {
+        128
+    } else {
+        32
+    }
+}
+
+fn parse_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+fn netlink_errno(e: &rtnetlink::Error) -> Option<i32> {
+    match e {
+        rtnetlink::Error::NetlinkError(message) => message.code.map(|code| -code.get()),
+        _ => None,
+    }
+}
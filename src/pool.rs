@@ -0,0 +1,733 @@
+use arc_swap::ArcSwap;
+use cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr};
+use rand::random;
+use rand::seq::SliceRandom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::lease::{Lease, LeaseInfo, LeaseTable};
+use crate::netlink::{AddressBackend, BackendError};
+use crate::session::SessionStore;
+use crate::strategy::{AddressDeriver, SelectionKey, Strategy};
+
+/// How often idle leases are checked against their TTL.
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interface settings for system-route mode, where every egress address is added to the
+/// interface before use instead of relying on ndppd.
+pub struct SystemRoute {
+    pub backend: Box<dyn AddressBackend>,
+    pub gateway: String,
+    pub leases: Arc<LeaseTable>,
+}
+
+/// The configured egress subnets, replaced as a whole on reload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subnets {
+    pub ipv6: Vec<Ipv6Cidr>,
+    pub ipv4: Vec<Ipv4Cidr>,
+}
+
+/// The egress address pool shared by the HTTP, SOCKS5 and forward listeners.
+///
+/// It owns the configured subnets, picks addresses according to each listener's strategy,
+/// adds and removes addresses on the interface in system-route mode and keeps statistics.
+pub struct AddressPool {
+    subnets: ArcSwap<Subnets>,
+    sessions: SessionStore,
+    deriver: AddressDeriver,
+    system_route: Option<SystemRoute>,
+    /// Wakes the reclaimer task when the lease table went over its limit
+    over_max: Notify,
+    open_leases: Arc<AtomicUsize>,
+    ipv6_selected: AtomicU64,
+    ipv4_selected: AtomicU64,
+}
+
+/// Point-in-time counters of an `AddressPool`.
+pub struct PoolStats {
+    pub ipv6_subnets: usize,
+    pub ipv4_subnets: usize,
+    pub ipv6_selected: u64,
+    pub ipv4_selected: u64,
+    pub added_addresses: usize,
+    pub active_addresses: usize,
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} IPv6 subnets, {} IPv4 subnets, {} IPv6 and {} IPv4 addresses selected, {} system-route addresses ({} in use)",
+            self.ipv6_subnets,
+            self.ipv4_subnets,
+            self.ipv6_selected,
+            self.ipv4_selected,
+            self.added_addresses,
+            self.active_addresses
+        )
+    }
+}
+
+impl AddressPool {
+    pub fn new(
+        ipv6_subnets: Vec<Ipv6Cidr>,
+        ipv4_subnets: Vec<Ipv4Cidr>,
+        sessions: SessionStore,
+        deriver: AddressDeriver,
+        system_route: Option<SystemRoute>,
+    ) -> Self {
+        AddressPool {
+            subnets: ArcSwap::from_pointee(Subnets { ipv6: ipv6_subnets, ipv4: ipv4_subnets }),
+            sessions,
+            deriver,
+            system_route,
+            over_max: Notify::new(),
+            open_leases: Arc::new(AtomicUsize::new(0)),
+            ipv6_selected: AtomicU64::new(0),
+            ipv4_selected: AtomicU64::new(0),
+        }
+    }
+
+    /// A pool of the given subnets without system-route mode, for tests.
+    #[cfg(test)]
+    pub(crate) fn for_tests(ipv6: &[&str], ipv4: &[&str]) -> Self {
+        AddressPool::new(
+            ipv6.iter().map(|s| s.parse().unwrap()).collect(),
+            ipv4.iter().map(|s| s.parse().unwrap()).collect(),
+            SessionStore::new(Duration::from_secs(60)),
+            AddressDeriver::new("test-secret"),
+            None,
+        )
+    }
+
+    pub fn subnets(&self) -> Arc<Subnets> {
+        self.subnets.load_full()
+    }
+
+    /// Swaps in new subnets; connections that already hold an address keep it.
+    pub fn set_subnets(&self, ipv6: Vec<Ipv6Cidr>, ipv4: Vec<Ipv4Cidr>) {
+        self.subnets.store(Arc::new(Subnets { ipv6, ipv4 }));
+    }
+
+    /// Adds one subnet to the pool. Returns false if it is there already.
+    pub fn add_subnet(&self, subnet: IpCidr) -> bool {
+        self.edit_subnets(|subnets| match subnet {
+            IpCidr::V6(subnet) if !subnets.ipv6.contains(&subnet) => {
+                subnets.ipv6.push(subnet);
+                true
+            }
+            IpCidr::V4(subnet) if !subnets.ipv4.contains(&subnet) => {
+                subnets.ipv4.push(subnet);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    /// Removes one subnet from the pool. Returns false if it isn't in it. Users and routes
+    /// restricted to it keep using it.
+    pub fn remove_subnet(&self, subnet: IpCidr) -> bool {
+        self.edit_subnets(|subnets| {
+            let before = subnets.ipv6.len() + subnets.ipv4.len();
+            match subnet {
+                IpCidr::V6(subnet) => subnets.ipv6.retain(|s| *s != subnet),
+                IpCidr::V4(subnet) => subnets.ipv4.retain(|s| *s != subnet),
+            }
+            subnets.ipv6.len() + subnets.ipv4.len() < before
+        })
+    }
+
+    fn edit_subnets(&self, edit: impl Fn(&mut Subnets) -> bool) -> bool {
+        let mut changed = false;
+        self.subnets.rcu(|current| {
+            let mut subnets = Subnets::clone(current);
+            changed = edit(&mut subnets);
+            subnets
+        });
+        changed
+    }
+
+    /// Picks an egress address of the same family as `target` from `subnets`, or from the whole
+    /// pool if `None`, and, in system-route mode, adds it to the interface. Fails if no subnet
+    /// of that family is configured or the address could not be added. Keep the returned lease
+    /// for as long as the connection uses the address.
+    pub async fn acquire(
+        &self,
+        target: IpAddr,
+        strategy: Strategy,
+        key: &SelectionKey<'_>,
+        subnets: Option<&Subnets>,
+        timeout_duration: Duration,
+    ) -> Result<Lease, BackendError> {
+        let pool_subnets = self.subnets();
+        let subnets = subnets.unwrap_or(&pool_subnets);
+        let ip = match target {
+            IpAddr::V4(_) => self.select_ipv4(strategy, key, subnets).await.ok_or("No IPv4 subnet configured")?,
+            IpAddr::V6(_) => self.select_ipv6(strategy, key, subnets).await.ok_or("No IPv6 subnet configured")?,
+        };
+        self.activate(ip, timeout_duration).await
+    }
+
+    /// Egress IPv6 address for a connection: derived from the key by a hash strategy, else the
+    /// session's pinned address if there is one, otherwise a fresh random address.
+    pub async fn select_ipv6(&self, strategy: Strategy, key: &SelectionKey<'_>, subnets: &Subnets) -> Option<IpAddr> {
+        let ip = match self.deriver.ipv6(strategy, key, &subnets.ipv6) {
+            Some(ip) => Some(ip),
+            None => match key.session {
+                Some(session) => self.sessions.ipv6_addr(session, &subnets.ipv6).await,
+                None => subnets.ipv6.choose(&mut rand::thread_rng()).map(get_rand_ipv6),
+            },
+        };
+        if ip.is_some() {
+            self.ipv6_selected.fetch_add(1, Ordering::Relaxed);
+        }
+        ip
+    }
+
+    /// Egress IPv4 address for a connection, see `select_ipv6`.
+    pub async fn select_ipv4(&self, strategy: Strategy, key: &SelectionKey<'_>, subnets: &Subnets) -> Option<IpAddr> {
+        let ip = match self.deriver.ipv4(strategy, key, &subnets.ipv4) {
+            Some(ip) => Some(ip),
+            None => match key.session {
+                Some(session) => self.sessions.ipv4_addr(session, &subnets.ipv4).await,
+                None => subnets.ipv4.choose(&mut rand::thread_rng()).map(get_rand_ipv4),
+            },
+        };
+        if ip.is_some() {
+            self.ipv4_selected.fetch_add(1, Ordering::Relaxed);
+        }
+        ip
+    }
+
+    /// In system-route mode, leases `ip` and adds it to the interface, waking the reclaimer if
+    /// that puts the table over its limit. Returns an untracked lease otherwise.
+    pub async fn activate(&self, ip: IpAddr, timeout_duration: Duration) -> Result<Lease, BackendError> {
+        let Some(system_route) = &self.system_route else {
+            return Ok(Lease::untracked(ip).counted(&self.open_leases));
+        };
+
+        let (lease, is_new) = system_route.leases.acquire(ip).await;
+        let lease = lease.counted(&self.open_leases);
+        // Added for every connection, not only new leases, so a second connection racing the
+        // first one can't bind before the address exists; the backend ignores duplicates
+        let added = match timeout(timeout_duration, system_route.backend.add(ip)).await {
+            Ok(added) => added,
+            Err(_) => Err(format!("Timed out adding {}", ip).into()),
+        };
+        if let Err(e) = added {
+            drop(lease);
+            if is_new {
+                // Not on the interface, so not worth a place in the table or the lease file
+                system_route.leases.remove_idle(ip);
+            }
+            return Err(e);
+        }
+
+        if is_new && !system_route.gateway.is_empty() {
+            // Some providers only route an address after traffic from it reached the gateway
+            let spawned = Command::new("traceroute")
+                .args(["-m", "10", "-s", &ip.to_string(), &system_route.gateway])
+                .spawn();
+            if let Err(e) = spawned {
+                warn!("Failed to run traceroute from {}: {}", ip, e);
+            }
+        }
+
+        if system_route.leases.is_over_max() {
+            self.over_max.notify_one();
+        }
+        Ok(lease)
+    }
+
+    /// Removes every address whose lease is reclaimable from the interface.
+    pub async fn reclaim(&self) {
+        let Some(system_route) = &self.system_route else {
+            return;
+        };
+        for ip in system_route.leases.reclaimable() {
+            // Connections for `ip` wait in `acquire` until the removal is done
+            let Some(_removal) = system_route.leases.remove_idle(ip) else {
+                continue;
+            };
+            if let Err(e) = system_route.backend.remove(ip).await {
+                warn!("Failed to remove address {}: {}", ip, e);
+            }
+        }
+    }
+
+    /// Removes the addresses a previous run left in the lease file.
+    pub async fn restore_leases(&self) {
+        let Some(system_route) = &self.system_route else {
+            return;
+        };
+        let previous = match system_route.leases.load_previous() {
+            Ok(previous) => previous,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        for ip in &previous {
+            if let Err(e) = system_route.backend.remove(*ip).await {
+                warn!("Failed to remove address {}: {}", ip, e);
+            }
+        }
+        if !previous.is_empty() {
+            info!("Removed {} addresses left by the previous run", previous.len());
+        }
+        system_route.leases.mark_dirty();
+        system_route.leases.flush().await;
+    }
+
+    /// Removes every address this run added, whether connections still use it or not.
+    pub async fn release_all(&self) {
+        let Some(system_route) = &self.system_route else {
+            return;
+        };
+        let addresses = system_route.leases.drain();
+        for ip in &addresses {
+            if let Err(e) = system_route.backend.remove(*ip).await {
+                warn!("Failed to remove address {}: {}", ip, e);
+            }
+        }
+        // The last write, whether or not the writer task caught up
+        system_route.leases.flush().await;
+        info!("Removed {} system-route addresses", addresses.len());
+    }
+
+    /// Finds single-host addresses inside the configured subnets that are on the interface
+    /// but not leased, i.e. left behind by an earlier run, and removes them if `prune` is set.
+    pub async fn reconcile(&self, prune: bool) -> Result<Vec<IpAddr>, BackendError> {
+        let Some(system_route) = &self.system_route else {
+            return Ok(Vec::new());
+        };
+        let stale: Vec<IpAddr> = system_route
+            .backend
+            .list()
+            .await?
+            .into_iter()
+            .filter(|ip| self.contains(*ip) && !system_route.leases.contains(*ip))
+            .collect();
+
+        if prune {
+            for ip in &stale {
+                system_route.backend.remove(*ip).await?;
+            }
+        }
+        Ok(stale)
+    }
+
+    /// Whether `ip` lies in one of the configured subnets.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let subnets = self.subnets.load();
+        match ip {
+            IpAddr::V6(ip) => subnets.ipv6.iter().any(|subnet| subnet.contains(&ip)),
+            IpAddr::V4(ip) => subnets.ipv4.iter().any(|subnet| subnet.contains(&ip)),
+        }
+    }
+
+    /// The addresses added to the interface, `None` unless in system-route mode.
+    pub fn leases(&self) -> Option<Vec<LeaseInfo>> {
+        self.system_route.as_ref().map(|route| route.leases.snapshot())
+    }
+
+    /// Connections currently holding a lease from this pool.
+    pub fn open_connections(&self) -> usize {
+        self.open_leases.load(Ordering::Relaxed)
+    }
+
+    /// Reclaims leases that outlived their TTL periodically, and idle ones as soon as the
+    /// table goes over its limit.
+    pub fn spawn_reclaimer(self: &Arc<Self>) {
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECLAIM_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = pool.over_max.notified() => {}
+                }
+                pool.reclaim().await;
+            }
+        });
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let leases = self.system_route.as_ref().map(|route| &route.leases);
+        let subnets = self.subnets.load();
+        PoolStats {
+            ipv6_subnets: subnets.ipv6.len(),
+            ipv4_subnets: subnets.ipv4.len(),
+            ipv6_selected: self.ipv6_selected.load(Ordering::Relaxed),
+            ipv4_selected: self.ipv4_selected.load(Ordering::Relaxed),
+            added_addresses: leases.map_or(0, |leases| leases.len()),
+            active_addresses: leases.map_or(0, |leases| leases.active()),
+        }
+    }
+}
+
+pub(crate) fn get_rand_ipv4(ipv4_cidr: &Ipv4Cidr) -> IpAddr {
+    IpAddr::V4(ipv4_with_host_bits(ipv4_cidr, random()))
+}
+
+pub(crate) fn get_rand_ipv6(ipv6_cidr: &Ipv6Cidr) -> IpAddr {
+    IpAddr::V6(ipv6_with_host_bits(ipv6_cidr, random()))
+}
+
+/// Keeps the network bits of `cidr` and takes the remaining bits from `host_bits`.
+pub(crate) fn ipv6_with_host_bits(cidr: &Ipv6Cidr, host_bits: u128) -> Ipv6Addr {
+    let host_mask = u128::MAX.checked_shr(cidr.network_length() as u32).unwrap_or(0);
+    ((u128::from(cidr.first_address()) & !host_mask) | (host_bits & host_mask)).into()
+}
+
+/// Keeps the network bits of `cidr` and takes the remaining bits from `host_bits`.
+pub(crate) fn ipv4_with_host_bits(cidr: &Ipv4Cidr, host_bits: u32) -> Ipv4Addr {
+    let host_mask = u32::MAX.checked_shr(cidr.network_length() as u32).unwrap_or(0);
+    ((u32::from(cidr.first_address()) & !host_mask) | (host_bits & host_mask)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+
+    fn pool_with_route(ipv6: &[&str], ipv4: &[&str], system_route: Option<SystemRoute>) -> AddressPool {
+        AddressPool::new(
+            ipv6.iter().map(|s| s.parse().unwrap()).collect(),
+            ipv4.iter().map(|s| s.parse().unwrap()).collect(),
+            SessionStore::new(Duration::from_secs(60)),
+            AddressDeriver::new("test-secret"),
+            system_route,
+        )
+    }
+
+    /// Records calls instead of touching the kernel.
+    #[derive(Default)]
+    struct MockBackend {
+        added: std::sync::Mutex<Vec<IpAddr>>,
+        removed: std::sync::Mutex<Vec<IpAddr>>,
+        present: Vec<IpAddr>,
+        fail: bool,
+        /// `remove` waits for this after recording the call
+        hold_remove: tokio::sync::Mutex<()>,
+    }
+
+    #[async_trait]
+    impl AddressBackend for Arc<MockBackend> {
+        async fn add(&self, ip: IpAddr) -> Result<(), BackendError> {
+            if self.fail {
+                return Err("Operation not permitted".into());
+            }
+            self.added.lock().unwrap().push(ip);
+            Ok(())
+        }
+
+        async fn remove(&self, ip: IpAddr) -> Result<(), BackendError> {
+            self.removed.lock().unwrap().push(ip);
+            let _held = self.hold_remove.lock().await;
+            Ok(())
+        }
+
+        async fn list(&self) -> Result<Vec<IpAddr>, BackendError> {
+            Ok(self.present.clone())
+        }
+    }
+
+    fn mock_route(backend: &Arc<MockBackend>, max_addresses: usize) -> Option<SystemRoute> {
+        Some(SystemRoute {
+            backend: Box::new(Arc::clone(backend)),
+            gateway: String::new(),
+            leases: Arc::new(LeaseTable::new(Duration::from_secs(60), max_addresses, None)),
+        })
+    }
+
+    async fn acquire_v6(pool: &AddressPool) -> Result<Lease, BackendError> {
+        pool.acquire("2001:db8:ffff::1".parse().unwrap(), Strategy::Random, &SelectionKey::default(), None, Duration::from_secs(1))
+            .await
+    }
+
+    fn assert_ipv6_in(cidr: &str, host_bits: u128) -> Ipv6Addr {
+        let cidr: Ipv6Cidr = cidr.parse().unwrap();
+        let ip = ipv6_with_host_bits(&cidr, host_bits);
+        assert!(cidr.contains(&ip), "{} not in {}", ip, cidr);
+        ip
+    }
+
+    #[test]
+    fn ipv6_prefix_0_takes_every_bit_from_host() {
+        assert_eq!(assert_ipv6_in("::/0", u128::MAX), Ipv6Addr::from(u128::MAX));
+        assert_eq!(assert_ipv6_in("::/0", 0), Ipv6Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn ipv6_prefix_48_keeps_network_bits() {
+        let ip = assert_ipv6_in("2001:db8:1::/48", u128::MAX);
+        assert_eq!(ip, "2001:db8:1:ffff:ffff:ffff:ffff:ffff".parse::<Ipv6Addr>().unwrap());
+        let ip = assert_ipv6_in("2001:db8:1::/48", 0);
+        assert_eq!(ip, "2001:db8:1::".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn ipv6_prefix_64_keeps_network_bits() {
+        let ip = assert_ipv6_in("2001:db8:1:2::/64", u128::MAX);
+        assert_eq!(ip, "2001:db8:1:2:ffff:ffff:ffff:ffff".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn ipv6_prefix_127_has_two_addresses() {
+        assert_eq!(assert_ipv6_in("2001:db8::/127", 0), "2001:db8::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(assert_ipv6_in("2001:db8::/127", u128::MAX), "2001:db8::1".parse::<Ipv6Addr>().unwrap());
+    }
+
+    #[test]
+    fn ipv6_prefix_128_is_the_address_itself() {
+        let expected = "2001:db8::42".parse::<Ipv6Addr>().unwrap();
+        assert_eq!(assert_ipv6_in("2001:db8::42/128", u128::MAX), expected);
+        assert_eq!(assert_ipv6_in("2001:db8::42/128", 0), expected);
+    }
+
+    #[test]
+    fn ipv4_prefix_32_is_the_address_itself() {
+        let cidr: Ipv4Cidr = "192.0.2.7/32".parse().unwrap();
+        assert_eq!(ipv4_with_host_bits(&cidr, u32::MAX), Ipv4Addr::new(192, 0, 2, 7));
+        assert_eq!(ipv4_with_host_bits(&cidr, 0), Ipv4Addr::new(192, 0, 2, 7));
+    }
+
+    #[test]
+    fn ipv4_prefix_0_and_24() {
+        let cidr: Ipv4Cidr = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(ipv4_with_host_bits(&cidr, u32::MAX), Ipv4Addr::BROADCAST);
+        let cidr: Ipv4Cidr = "192.0.2.0/24".parse().unwrap();
+        assert_eq!(ipv4_with_host_bits(&cidr, u32::MAX), Ipv4Addr::new(192, 0, 2, 255));
+    }
+
+    #[test]
+    fn random_addresses_stay_in_subnet() {
+        for cidr in ["::/0", "2001:db8:1::/48", "2001:db8:1:2::/64", "2001:db8::/127", "2001:db8::1/128"] {
+            let cidr: Ipv6Cidr = cidr.parse().unwrap();
+            for _ in 0..100 {
+                match get_rand_ipv6(&cidr) {
+                    IpAddr::V6(ip) => assert!(cidr.contains(&ip)),
+                    ip => panic!("unexpected {}", ip),
+                }
+            }
+        }
+        for cidr in ["0.0.0.0/0", "192.0.2.0/24", "192.0.2.7/32"] {
+            let cidr: Ipv4Cidr = cidr.parse().unwrap();
+            for _ in 0..100 {
+                match get_rand_ipv4(&cidr) {
+                    IpAddr::V4(ip) => assert!(cidr.contains(&ip)),
+                    ip => panic!("unexpected {}", ip),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn selection_follows_target_family() {
+        let pool = AddressPool::for_tests(&["2001:db8::/64"], &["192.0.2.0/24"]);
+        let key = SelectionKey::default();
+        let timeout = Duration::from_secs(1);
+
+        let lease = pool.acquire("2001:db8:ffff::1".parse().unwrap(), Strategy::Random, &key, None, timeout).await;
+        assert!(matches!(lease.map(|lease| lease.ip()), Ok(IpAddr::V6(_))));
+        let lease = pool.acquire("198.51.100.1".parse().unwrap(), Strategy::Random, &key, None, timeout).await;
+        assert!(matches!(lease.map(|lease| lease.ip()), Ok(IpAddr::V4(_))));
+
+        let stats = pool.stats();
+        assert_eq!((stats.ipv6_selected, stats.ipv4_selected), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn missing_family_yields_none() {
+        let pool = AddressPool::for_tests(&["2001:db8::/64"], &[]);
+        let ip = pool.select_ipv4(Strategy::Random, &SelectionKey::default(), &pool.subnets()).await;
+        assert_eq!(ip, None);
+    }
+
+    #[tokio::test]
+    async fn hash_strategy_is_stable() {
+        let pool = AddressPool::for_tests(&["2001:db8::/64", "2001:db8:1::/64"], &[]);
+        let key = SelectionKey {
+            client: Some("198.51.100.1".parse().unwrap()),
+            target: Some("example.com"),
+            session: None,
+        };
+        let first = pool.select_ipv6(Strategy::PerClientTarget, &key, &pool.subnets()).await;
+        let second = pool.select_ipv6(Strategy::PerClientTarget, &key, &pool.subnets()).await;
+        assert!(first.is_some());
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn session_is_sticky() {
+        let pool = AddressPool::for_tests(&["2001:db8::/64"], &[]);
+        let key = SelectionKey {
+            session: Some("user-session-abc"),
+            ..Default::default()
+        };
+        let first = pool.select_ipv6(Strategy::Random, &key, &pool.subnets()).await;
+        let second = pool.select_ipv6(Strategy::Random, &key, &pool.subnets()).await;
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn acquire_uses_given_subnets() {
+        let pool = AddressPool::for_tests(&["2001:db8::/48"], &[]);
+        let restricted = Subnets { ipv6: vec!["2001:db8:0:7::/64".parse().unwrap()], ipv4: Vec::new() };
+        let key = SelectionKey::default();
+        for _ in 0..20 {
+            let lease = pool
+                .acquire("2001:db8:ffff::1".parse().unwrap(), Strategy::Random, &key, Some(&restricted), Duration::from_secs(1))
+                .await
+                .unwrap();
+            match lease.ip() {
+                IpAddr::V6(ip) => assert!(restricted.ipv6[0].contains(&ip), "{}", ip),
+                ip => panic!("unexpected {}", ip),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn system_route_adds_address_before_use() {
+        let backend = Arc::new(MockBackend::default());
+        let pool = pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 10));
+
+        let lease = acquire_v6(&pool).await.unwrap();
+        assert_eq!(*backend.added.lock().unwrap(), vec![lease.ip()]);
+        assert_eq!((pool.stats().added_addresses, pool.stats().active_addresses), (1, 1));
+
+        drop(lease);
+        assert_eq!((pool.stats().added_addresses, pool.stats().active_addresses), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn system_route_reclaims_only_idle_addresses() {
+        let backend = Arc::new(MockBackend::default());
+        let pool = pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 2));
+
+        let idle = acquire_v6(&pool).await.unwrap().ip();
+        let _busy = [acquire_v6(&pool).await.unwrap(), acquire_v6(&pool).await.unwrap()];
+        // Left to the reclaimer, not done on the connection's path
+        assert!(backend.removed.lock().unwrap().is_empty());
+        pool.reclaim().await;
+        assert_eq!(*backend.removed.lock().unwrap(), vec![idle]);
+
+        // Everything left is in use, so going over the limit removes nothing
+        let _another = acquire_v6(&pool).await.unwrap();
+        pool.reclaim().await;
+        assert_eq!(backend.removed.lock().unwrap().len(), 1);
+        assert_eq!(pool.stats().added_addresses, 3);
+    }
+
+    #[tokio::test]
+    async fn reclaimer_wakes_up_over_the_limit() {
+        let backend = Arc::new(MockBackend::default());
+        let pool = Arc::new(pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 1)));
+        pool.spawn_reclaimer();
+
+        let idle = acquire_v6(&pool).await.unwrap().ip();
+        let _busy = acquire_v6(&pool).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while backend.removed.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*backend.removed.lock().unwrap(), vec![idle]);
+    }
+
+    #[tokio::test]
+    async fn activate_waits_for_a_removal_in_progress() {
+        let backend = Arc::new(MockBackend::default());
+        let pool = Arc::new(pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 0)));
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        drop(pool.activate(ip, Duration::from_secs(1)).await.unwrap());
+
+        let held = backend.hold_remove.lock().await;
+        let reclaiming = tokio::spawn({
+            let pool = Arc::clone(&pool);
+            async move { pool.reclaim().await }
+        });
+        timeout(Duration::from_secs(5), async {
+            while backend.removed.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let activating = tokio::spawn({
+            let pool = Arc::clone(&pool);
+            async move { pool.activate(ip, Duration::from_secs(1)).await.map(|lease| lease.ip()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!activating.is_finished());
+        assert_eq!(backend.added.lock().unwrap().len(), 1);
+
+        drop(held);
+        reclaiming.await.unwrap();
+        assert_eq!(activating.await.unwrap().unwrap(), ip);
+        assert_eq!(*backend.added.lock().unwrap(), vec![ip, ip]);
+        assert_eq!(pool.stats().added_addresses, 1);
+    }
+
+    #[tokio::test]
+    async fn system_route_failure_fails_acquire() {
+        let backend = Arc::new(MockBackend { fail: true, ..Default::default() });
+        let pool = pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 10));
+
+        assert!(acquire_v6(&pool).await.is_err());
+        assert_eq!((pool.stats().added_addresses, pool.stats().active_addresses), (0, 0));
+        assert_eq!(pool.leases().unwrap().len(), 0);
+        assert_eq!(pool.open_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn open_connections_follow_leases() {
+        let pool = AddressPool::for_tests(&["2001:db8::/64"], &[]);
+        let lease = acquire_v6(&pool).await.unwrap();
+        assert_eq!(pool.open_connections(), 1);
+        drop(lease);
+        assert_eq!(pool.open_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn release_all_removes_addresses_in_use() {
+        let backend = Arc::new(MockBackend::default());
+        let pool = pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 10));
+
+        let lease = acquire_v6(&pool).await.unwrap();
+        pool.release_all().await;
+        assert_eq!(*backend.removed.lock().unwrap(), vec![lease.ip()]);
+        assert_eq!(pool.stats().added_addresses, 0);
+    }
+
+    #[tokio::test]
+    async fn reconcile_finds_unleased_addresses_in_subnets() {
+        let present = ["2001:db8::1", "2001:db8::2", "2001:db8:1::1", "192.0.2.1"];
+        let backend = Arc::new(MockBackend {
+            present: present.iter().map(|s| s.parse().unwrap()).collect(),
+            ..Default::default()
+        });
+        let pool = pool_with_route(&["2001:db8::/64"], &[], mock_route(&backend, 10));
+        let _lease = pool.activate("2001:db8::2".parse().unwrap(), Duration::from_secs(1)).await.unwrap();
+
+        let stale = pool.reconcile(false).await.unwrap();
+        assert_eq!(stale, vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        assert!(backend.removed.lock().unwrap().is_empty());
+
+        pool.reconcile(true).await.unwrap();
+        assert_eq!(*backend.removed.lock().unwrap(), stale);
+    }
+}
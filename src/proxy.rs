@@ -1,78 +1,84 @@
 use hyper::{
-    client::HttpConnector,
-    server::conn::AddrStream,
-    service::{make_service_fn, service_fn},
+    body::HttpBody,
+    client::{connect::dns::Name, HttpConnector},
+    server::conn::{AddrStream, Http},
+    service::{make_service_fn, service_fn, Service},
     Body, Client, Method, Request, Response, Server, StatusCode,
 };
-use rand::{random, Rng};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
-use tokio::{ net::TcpSocket, task};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpStream;
 use std::sync::{Arc};
-use tokio::process::Command;
-use std::collections::{HashMap, VecDeque};
-use std::error::Error;
+use std::io;
+use std::task::{Context, Poll};
+use futures::future::{ready, Ready};
+use futures::TryStreamExt;
 use std::time::Duration;
-use tokio::sync::Mutex;
-use lazy_static::lazy_static;
-use tokio::time::timeout;
+use tokio::time::{timeout, Instant};
+use tracing::{debug, info, warn, Instrument};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 
 use hyper::upgrade::OnUpgrade;
-use cidr::{Ipv4Cidr, Ipv6Cidr};
-use rand::seq::SliceRandom;
 
-const MAX_ADDRESSES: usize = 1000;
-
-lazy_static! {
-    static ref IP_MAP: Mutex<HashMap<String, IpAddr>> = Mutex::new(HashMap::new());
-    static ref GLOBAL_ADDRESS_QUEUE: Arc<Mutex<VecDeque<String>>> = Arc::new(Mutex::new(VecDeque::new()));
-}
+use crate::access_log::Access;
+use crate::auth::{Auth, AuthError, Login};
+use crate::pool::AddressPool;
+use crate::session::split_session;
+use crate::shutdown;
+use crate::config::{Listener, SharedListener};
+use crate::happy_eyeballs::{self, order_addrs};
+use crate::limit::{Direction, Limited, Limiter, Permit, Shaped};
+use crate::logging::connection_span;
+use crate::metrics::{Cause, ListenerMetrics};
+use crate::resolver::Resolver;
+use crate::strategy::SelectionKey;
 
 pub async fn start_proxy(
-    listen_addr: SocketAddr,
-    is_system_route: bool,
-    gateway: String,
-    interface: String,
-    ipv6_subnets: Arc<Vec<Ipv6Cidr>>,
-    ipv4_subnets: Arc<Vec<Ipv4Cidr>>,
-    allowed_ips: Option<Vec<IpAddr>>,
-    username: String,  // 新增用户名参数
-    password: String,  // 新增密码参数
+    pool: Arc<AddressPool>,
+    listener: SharedListener,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    resolver: Arc<Resolver>,
     timeout_duration: Duration, // 新增timeout_duration参数
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let interface_arc = Arc::new(interface);
-    let gateway_arc = Arc::new(gateway);
-    let allowed_ips_arc = allowed_ips.map(Arc::new);
-    let username_arc = Arc::new(username);  // 用 Arc 包装用户名
-    let password_arc = Arc::new(password);  // 用 Arc 包装密码
+    let listen_addr = listener.load().bind;
 
     let make_service = make_service_fn(move |conn: &AddrStream| {
         let remote_addr = conn.remote_addr();
-        let interface_clone = Arc::clone(&interface_arc);
-        let gateway_clone = Arc::clone(&gateway_arc);
-        let ipv6_subnets_clone = Arc::clone(&ipv6_subnets);  // 使用 Arc 克隆引用
-        let ipv4_subnets_clone = Arc::clone(&ipv4_subnets);
-        let allowed_ips_clone = allowed_ips_arc.clone();
-        let username_clone = Arc::clone(&username_arc);  // 克隆用户名
-        let password_clone = Arc::clone(&password_arc);  // 克隆密码
+        let pool_clone = Arc::clone(&pool);
+        let auth = Arc::clone(&auth);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
+        // Each connection keeps the settings it was accepted with, a reload only affects new ones
+        let settings = listener.load_full();
+        let metrics = ListenerMetrics::new(settings.bind, "http");
+        // hyper closes a connection whose service couldn't be made
+        let admitted = limiter.connect(remote_addr.ip());
+        match &admitted {
+            Ok(()) => metrics.connection(),
+            Err(e) => {
+                debug!(client = %remote_addr.ip(), listener = %settings.bind, "Connection turned away: {}", e);
+                metrics.error(Cause::Limited);
+            }
+        }
 
         async move {
+            admitted?;
             let service = service_fn(move |mut req: Request<Body>| {
                 req.extensions_mut().insert(remote_addr);
 
                 Proxy {
-                    ipv6_subnets: Arc::clone(&ipv6_subnets_clone),  // 直接使用 Arc::clone
-                    ipv4_subnets: Arc::clone(&ipv4_subnets_clone),  // 直接使用 Arc::clone
-                    address_queue: GLOBAL_ADDRESS_QUEUE.clone(),
-                    allowed_ips: allowed_ips_clone.clone(),
-                    username: username_clone.clone(),  // 传递用户名
-                    password: password_clone.clone(),  // 传递密码
+                    pool: Arc::clone(&pool_clone),
+                    settings: Arc::clone(&settings),
+                    auth: Arc::clone(&auth),
+                    limiter: Arc::clone(&limiter),
+                    resolver: Arc::clone(&resolver),
+                    metrics: metrics.clone(),
                 }
-                    .proxy(req, is_system_route, (*interface_clone).clone(), (*gateway_clone).clone(), timeout_duration)
+                    .proxy(req, timeout_duration)
             });
 
-            Ok::<_, hyper::Error>(service)
+            Ok::<_, Limited>(service)
         }
     });
 
@@ -84,36 +90,59 @@ pub async fn start_proxy(
         .map_err(|err| err.into())
 }
 
+/// Serves HTTP on one connection accepted elsewhere, as `start_proxy` would. Used by the mixed
+/// listener once it has seen the connection isn't SOCKS.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve_connection(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    pool: Arc<AddressPool>,
+    settings: Arc<Listener>,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    resolver: Arc<Resolver>,
+    timeout_duration: Duration,
+) -> Result<(), hyper::Error> {
+    let metrics = ListenerMetrics::new(settings.bind, "http");
+    metrics.connection();
+    let service = service_fn(move |mut req: Request<Body>| {
+        req.extensions_mut().insert(remote_addr);
+
+        Proxy {
+            pool: Arc::clone(&pool),
+            settings: Arc::clone(&settings),
+            auth: Arc::clone(&auth),
+            limiter: Arc::clone(&limiter),
+            resolver: Arc::clone(&resolver),
+            metrics: metrics.clone(),
+        }
+            .proxy(req, timeout_duration)
+    });
+
+    Http::new()
+        .http1_preserve_header_case(true)
+        .http1_title_case_headers(true)
+        .serve_connection(stream, service)
+        .with_upgrades()
+        .await
+}
+
 #[derive(Clone)]
 pub(crate) struct Proxy {
-    pub ipv6_subnets: Arc<Vec<Ipv6Cidr>>,
-    pub ipv4_subnets: Arc<Vec<Ipv4Cidr>>,
-    address_queue: Arc<Mutex<VecDeque<String>>>,
-    allowed_ips: Option<Arc<Vec<IpAddr>>>,
-    username: Arc<String>,  // 添加用户名字段
-    password: Arc<String>,  // 添加密码字段
+    pool: Arc<AddressPool>,
+    settings: Arc<Listener>,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    resolver: Arc<Resolver>,
+    metrics: ListenerMetrics,
 }
 
 impl Proxy {
     pub(crate) async fn proxy(
         self,
         req: Request<Body>,
-        is_system_route: bool,
-        interface: String,
-        gateway: String,
         timeout_duration: Duration,
     ) -> Result<Response<Body>, hyper::Error> {
-        let auth_enabled = !self.username.is_empty() && !self.password.is_empty();
-        if auth_enabled {
-            if !self.is_authorized(&req) {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header("WWW-Authenticate", r#"Basic realm="User Visible Realm""#)
-                    .body(Body::from("Unauthorized"))
-                    .unwrap());
-            }
-        }
-
         let client_ip: Option<IpAddr> = if let Some(remote_addr) = req.extensions().get::<SocketAddr>() {
             Some(remote_addr.ip())
         } else if let Some(forwarded_for) = req.headers().get("x-forwarded-for") {
@@ -123,44 +152,111 @@ impl Proxy {
         } else {
             None
         };
+        if shutdown::is_draining() {
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Connection", "close")
+                .body(Body::from("Draining"))
+                .unwrap());
+        }
+        let span = connection_span("http", self.settings.bind, client_ip);
+        let access = Access::new("http", self.settings.bind, client_ip, req.method().as_str());
+        let handled = access.killable(self.handle(req, timeout_duration, client_ip, access.clone())).instrument(span.clone()).await;
+        let response = match handled {
+            Some(response) => response?,
+            None => {
+                span.in_scope(|| info!("Closed from the admin endpoint"));
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("Connection", "close")
+                    .body(Body::from("Closed by the administrator"))
+                    .unwrap()
+            }
+        };
+        access.status(response.status().as_u16());
+        // The entry is written once the body has been sent, or, for CONNECT, the tunnel closed
+        Ok(response.map(|body| counted(body, access, |access, len| access.bytes(0, len))))
+    }
 
-        if let Some(client_ip) = client_ip {
-            println!("Client IP: {}", client_ip);
-
-            // 如果设置了 allowed_ips 列表，则检查客户端 IP 是否在列表中
-            if let Some(allowed_ips) = &self.allowed_ips {
-                let ip_allowed = allowed_ips.iter().any(|allowed_ip| match (allowed_ip, client_ip) {
-                    (IpAddr::V4(allowed_ip), IpAddr::V4(client_ip)) => {
-                        // 使用包含关系判断 IPv4 子网
-                        Ipv4Cidr::new(*allowed_ip, 32).unwrap().contains(&client_ip)
+    async fn handle(
+        self,
+        req: Request<Body>,
+        timeout_duration: Duration,
+        client_ip: Option<IpAddr>,
+        access: Access,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(authority) = req.uri().authority() {
+            access.target(authority);
+        }
+        let credentials = proxy_credentials(&req);
+        // Held until the request, or the CONNECT tunnel, is done
+        let login = if self.auth.is_enabled() {
+            let result = match &credentials {
+                Some((username, password)) => self.auth.login(split_session(username).0, password).await,
+                None => Err(AuthError::UnknownUser),
+            };
+            match result {
+                Ok(login) => {
+                    if let Some((username, _)) = &credentials {
+                        access.user(split_session(username).0);
                     }
-                    (IpAddr::V6(allowed_ip), IpAddr::V6(client_ip)) => {
-                        // 使用包含关系判断 IPv6 子网
-                        Ipv6Cidr::new(*allowed_ip, 128).unwrap().contains(&client_ip)
+                    Some(login)
+                }
+                Err(e) => {
+                    if let Some((username, _)) = &credentials {
+                        info!(user = %split_session(username).0, "Authentication failed: {}", e);
                     }
-                    _ => false,
-                });
-
-                if !ip_allowed {
-                    println!("Access denied for IP: {}", client_ip);
-                    return Ok(Response::builder()
-                        .status(StatusCode::FORBIDDEN)
-                        .body(Body::from("Access denied"))
-                        .unwrap());
+                    self.metrics.error(Cause::Auth);
+                    return Ok(auth_error_response(e));
                 }
             }
         } else {
-            println!("Failed to get client IP address");
+            None
+        };
+
+        if let Some(client_ip) = client_ip {
+            // 按顺序检查 allow/deny 规则
+            if !self.settings.acl.allows(client_ip) {
+                info!("Access denied for client");
+                self.metrics.error(Cause::Denied);
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Access denied"))
+                    .unwrap());
+            }
+        } else {
+            debug!("Failed to get client IP address");
         }
 
+        // `user-session-<id>` pins the egress address for the whole session
+        let session = credentials
+            .as_ref()
+            .filter(|(username, _)| split_session(username).1.is_some())
+            .map(|(username, _)| username.clone());
+
 
 
 
+        let is_connect = req.method() == Method::CONNECT;
+        let permit = match self.limiter.admit(client_ip, login.as_ref(), is_connect) {
+            Ok(permit) => permit,
+            Err(e) => {
+                info!("{}", e);
+                self.metrics.error(Cause::Limited);
+                return Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(Body::from("Too Many Requests"))
+                    .unwrap());
+            }
+        };
+
+        let metrics = self.metrics.clone();
+        metrics.request(if is_connect { "connect" } else { "request" });
         match timeout(timeout_duration, async {
-            if req.method() == Method::CONNECT {
-                self.process_connect(req, is_system_route, interface.clone(), gateway.clone(), timeout_duration).await
+            if is_connect {
+                self.process_connect(req, timeout_duration, client_ip, session, login, permit, access).await
             } else {
-                self.process_request(req, is_system_route, interface.clone(), gateway.clone(), timeout_duration).await
+                self.process_request(req, timeout_duration, client_ip, session, login, permit, access).await
             }
         })
             .await
@@ -169,7 +265,8 @@ impl Proxy {
             Ok(Err(e)) => Err(e),
             Err(_) => {
                 // Timeout occurred
-                println!("Request timed out");
+                warn!("Request timed out");
+                metrics.error(Cause::Timeout);
                 Ok(Response::builder()
                     .status(StatusCode::SERVICE_UNAVAILABLE)
                     .body(Body::from("Service Unavailable"))
@@ -178,42 +275,36 @@ impl Proxy {
         }
     }
 
-    fn is_authorized(&self, req: &Request<Body>) -> bool {
-        if let Some(auth_header) = req.headers().get("Proxy-Authorization") {
-            if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str.starts_with("Basic ") {
-                    let encoded_credentials = &auth_str[6..];
-                    if let Ok(decoded_credentials) = STANDARD.decode(encoded_credentials) {
-                        let decoded_str = String::from_utf8(decoded_credentials).unwrap_or_default();
-                        let credentials: Vec<&str> = decoded_str.splitn(2, ':').collect();
-                        if credentials.len() == 2 {
-                            let (username, password) = (credentials[0], credentials[1]);
-                            return username == self.username.as_str() && password == self.password.as_str();
-                        }
-                    }
-                }
-            }
-        }
-        false
-    }
-
+    #[allow(clippy::too_many_arguments)]
     async fn process_connect(
         self,
         mut req: Request<Body>,
-        is_system_route: bool,
-        interface: String,
-        gateway: String,
         timeout_duration: Duration,
+        client_ip: Option<IpAddr>,
+        session: Option<String>,
+        login: Option<Login>,
+        permit: Permit,
+        access: Access,
     ) -> Result<Response<Body>, hyper::Error> {
-        let remote_addr = match req.uri().authority().map(|auth| auth.to_string()) {
-            Some(addr) => addr,
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::from("Missing remote address"))
-                    .unwrap());
-            }
+        let target = req.uri().host().map(str::to_string);
+        let key = SelectionKey {
+            client: client_ip,
+            target: target.as_deref(),
+            session: session.as_deref(),
         };
+        // A user's own subnets take precedence over the routing rules
+        let subnets = login
+            .as_ref()
+            .and_then(Login::subnets)
+            .or_else(|| target.as_deref().and_then(|host| self.settings.routes.subnets_for(host)));
+
+        if req.uri().authority().is_none() {
+            self.metrics.error(Cause::Protocol);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Missing remote address"))
+                .unwrap());
+        }
 
         let client_upgrade = match req.extensions_mut().remove::<OnUpgrade>() {
             Some(upgrade) => upgrade,
@@ -227,10 +318,13 @@ impl Proxy {
 
 
 
-        let addrs = match remote_addr.to_socket_addrs() {
-            Ok(addrs) => addrs.collect::<Vec<_>>(),
+        let pool_subnets = self.pool.subnets();
+        let port = req.uri().port_u16().unwrap_or(443);
+        let addrs = match self.resolver.lookup(target.as_deref().unwrap_or_default(), port, subnets.unwrap_or(&pool_subnets), self.settings.nat64.is_some()).await {
+            Ok(addrs) => addrs,
             Err(e) => {
-                println!("Invalid address: {}: {:?}", remote_addr, e);
+                warn!("Failed to resolve: {}", e);
+                self.metrics.error(Cause::Unreachable);
                 return Ok(Response::builder()
                     .status(StatusCode::SERVICE_UNAVAILABLE)
                     .body(Body::from("Service Unavailable"))
@@ -239,184 +333,149 @@ impl Proxy {
         };
 
         if addrs.is_empty() {
-            println!("No valid addresses resolved");
+            warn!("No valid addresses resolved");
+            self.metrics.error(Cause::Unreachable);
             return Ok(Response::builder()
                 .status(StatusCode::SERVICE_UNAVAILABLE)
                 .body(Body::from("Service Unavailable"))
                 .unwrap());
         }
 
-        let addr = addrs[0];
-        let socket = match addr {
-            SocketAddr::V4(_) => TcpSocket::new_v4().unwrap(),
-            SocketAddr::V6(_) => TcpSocket::new_v6().unwrap(),
-        };
-
-        let bind_addr = match addr {
-            SocketAddr::V4(_) => get_rand_ipv4_socket_addr(&self.ipv4_subnets),
-            SocketAddr::V6(_) => get_rand_ipv6_socket_addr(&self.ipv6_subnets),
-        };
-
-        if is_system_route {
-            let cmd_str = format!(
-                "ip addr add {}/{} dev {}",
-                bind_addr.ip(),
-                if bind_addr.is_ipv6() {
-                    128
-                } else {
-                    32
-                },
-                interface
-            );
-            self.execute_command(cmd_str).await;
-
-            if !gateway.is_empty() {
-                let cmd_traceroute_str = format!("traceroute -m 10 -s {} {}", bind_addr.ip(), gateway);
-                self.execute_command(cmd_traceroute_str).await;
-            }
-
-            {
-                let mut queue = self.address_queue.lock().await;
-                queue.push_back(bind_addr.ip().to_string());
-            }
-
-            self.manage_address_count(&interface,timeout_duration).await;
-        }
-
-        if socket.bind(bind_addr).is_err() {
-            println!("Failed to bind to address");
-            return Ok(Response::builder()
-                .status(StatusCode::SERVICE_UNAVAILABLE)
-                .body(Body::from("Service Unavailable"))
-                .unwrap());
+        let host = target.as_deref().unwrap_or_default();
+        let allowed: Vec<SocketAddr> = addrs.iter().copied().filter(|addr| self.settings.destinations.allows(host, *addr)).collect();
+        if allowed.is_empty() {
+            info!(address = %addrs[0], "Destination not allowed");
+            self.metrics.error(Cause::Denied);
+            return Ok(destination_denied());
         }
 
-        let connect_result = timeout(timeout_duration, socket.connect(addr)).await;
-        let mut server = match connect_result {
-            Ok(Ok(stream)) => stream,
-            Ok(Err(e)) => {
-                println!("Failed to connect to server: {:?}", e);
-                return Ok(Response::builder()
-                    .status(StatusCode::SERVICE_UNAVAILABLE)
-                    .body(Body::from("Service Unavailable"))
-                    .unwrap());
+        let started = Instant::now();
+        let connected = happy_eyeballs::connect(
+            &self.pool,
+            &self.settings,
+            &key,
+            subnets,
+            &allowed,
+            timeout_duration,
+        )
+        .await;
+        let (mut server, lease) = match connected {
+            Ok(connected) => {
+                self.metrics.connected(started.elapsed());
+                connected
             }
-            Err(_) => {
-                println!("Connection to {} timed out", remote_addr);
+            Err(e) => {
+                warn!("Failed to connect: {}", e);
+                self.metrics.error(Cause::from(&e));
                 return Ok(Response::builder()
                     .status(StatusCode::SERVICE_UNAVAILABLE)
                     .body(Body::from("Service Unavailable"))
                     .unwrap());
             }
         };
+        access.egress(lease.ip());
+        let metrics = self.metrics.clone();
         tokio::spawn(async move {
-            match timeout(timeout_duration, tokio::io::copy_bidirectional(&mut client_upgrade.await.unwrap(), &mut server)).await {
-                Ok(Ok((client_bytes, server_bytes))) => {
-                    println!("Client wrote {} bytes, server wrote {} bytes", client_bytes, server_bytes);
-
+            // The address stays leased, and the user's connection and tunnel slots taken, until the tunnel closes
+            let _lease = lease;
+            let _login = login;
+            let mut client = Shaped::new(client_upgrade.await.unwrap(), permit);
+            match timeout(timeout_duration, access.killable(metrics.relay(&mut client, &mut server))).await {
+                Ok(None) => info!(duration_ms = started.elapsed().as_millis() as u64, "Closed from the admin endpoint"),
+                Ok(Some(Ok((client_bytes, server_bytes)))) => {
+                    access.bytes(client_bytes, server_bytes);
+                    info!(client_bytes, server_bytes, duration_ms = started.elapsed().as_millis() as u64, "Tunnel closed");
                 }
-                Ok(Err(err)) => {
-                    println!("Tunnel error: {:?}", err);
-
+                Ok(Some(Err(err))) => {
+                    warn!(duration_ms = started.elapsed().as_millis() as u64, "Tunnel error: {}", err);
+                    metrics.error(Cause::from(&err));
                 }
                 Err(_) => {
-                    println!("Tunnel timed out");
+                    warn!(duration_ms = started.elapsed().as_millis() as u64, "Tunnel timed out");
+                    metrics.error(Cause::Timeout);
                 }
             }
-        });
+        }.in_current_span());
         Ok(Response::new(Body::empty()))
 
     }
 
 
+    #[allow(clippy::too_many_arguments)]
     async fn process_request(
         self,
         req: Request<Body>,
-        is_system_route: bool,
-        interface: String,
-        gateway: String,
         timeout_duration: Duration,
+        client_ip: Option<IpAddr>,
+        session: Option<String>,
+        login: Option<Login>,
+        permit: Permit,
+        access: Access,
     ) -> Result<Response<Body>, hyper::Error> {
+        let target = req.uri().host().map(str::to_string);
+        let key = SelectionKey {
+            client: client_ip,
+            target: target.as_deref(),
+            session: session.as_deref(),
+        };
+        // A user's own subnets take precedence over the routing rules
+        let subnets = login
+            .as_ref()
+            .and_then(Login::subnets)
+            .or_else(|| target.as_deref().and_then(|host| self.settings.routes.subnets_for(host)));
 
 
-        let bind_addr = if let Some(host) = req.uri().host() {
-            let addr_str = format!("{}:{}", host, req.uri().port_u16().unwrap_or(80));
-
-            match tokio::net::lookup_host(addr_str).await {
-                Ok(mut addrs) => {
-                    if let Some(addr) = addrs.next() {
-                        match addr {
-                            SocketAddr::V4(_) => {
-                                // Host resolves to an IPv4 address, select from IPv4 subnets
-                                if let Some(ipv4_cidr) = self.ipv4_subnets.choose(&mut rand::thread_rng()) {
-                                    get_rand_ipv4_socket_addr(std::slice::from_ref(ipv4_cidr)).ip()
-                                } else {
-                                    IpAddr::V4(Ipv4Addr::LOCALHOST) // Fallback to IPv4 loopback address (127.0.0.1)
-                                }
-                            }
-                            SocketAddr::V6(_) => {
-                                // Host resolves to an IPv6 address, select from IPv6 subnets
-                                if let Some(ipv6_cidr) = self.ipv6_subnets.choose(&mut rand::thread_rng()) {
-                                    get_rand_ipv6_socket_addr(std::slice::from_ref(ipv6_cidr)).ip()
-                                } else {
-                                    IpAddr::V6(Ipv6Addr::LOCALHOST) // Fallback to IPv6 loopback address (::1)
-                                }
-                            }
-                        }
-                    } else {
-                        // No valid address found, fallback to loopback
-                        if self.ipv6_subnets.is_empty() {
-                            IpAddr::V4(Ipv4Addr::LOCALHOST) // Default to IPv4 loopback
-                        } else {
-                            IpAddr::V6(Ipv6Addr::LOCALHOST) // Default to IPv6 loopback
-                        }
-                    }
-                }
-                Err(_) => {
-                    // Error during lookup, fallback to loopback
-                    if self.ipv6_subnets.is_empty() {
-                        IpAddr::V4(Ipv4Addr::LOCALHOST) // Default to IPv4 loopback
-                    } else {
-                        IpAddr::V6(Ipv6Addr::LOCALHOST) // Default to IPv6 loopback
-                    }
-                }
+
+        let Some(host) = target.as_deref() else {
+            self.metrics.error(Cause::Protocol);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Missing host"))
+                .unwrap());
+        };
+        let pool_subnets = self.pool.subnets();
+        let families = subnets.unwrap_or(&pool_subnets);
+        let addrs = match self.resolver.lookup(host, req.uri().port_u16().unwrap_or(80), families, self.settings.nat64.is_some()).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("Failed to resolve: {}", e);
+                self.metrics.error(Cause::Unreachable);
+                return Ok(service_unavailable());
             }
-        } else {
-            // Fallback if there is no host in the URI
-            if self.ipv6_subnets.is_empty() {
-                IpAddr::V4(Ipv4Addr::LOCALHOST) // Default to IPv4 loopback
-            } else {
-                IpAddr::V6(Ipv6Addr::LOCALHOST) // Default to IPv6 loopback
+        };
+        let allowed: Vec<SocketAddr> = addrs.iter().copied().filter(|addr| self.settings.destinations.allows(host, *addr)).collect();
+        if allowed.is_empty() {
+            info!(address = %addrs[0], "Destination not allowed");
+            self.metrics.error(Cause::Denied);
+            return Ok(destination_denied());
+        }
+        let ordered = order_addrs(&allowed, families, self.settings.prefer_ipv6, self.settings.nat64);
+        let Some(first) = ordered.first() else {
+            warn!("No egress address family for the target");
+            self.metrics.error(Cause::Unreachable);
+            return Ok(service_unavailable());
+        };
+        // Select from the subnets of the preferred address family
+        let lease = match self.pool.acquire(first.ip(), self.settings.strategy, &key, subnets, timeout_duration).await {
+            Ok(lease) => lease,
+            Err(e) => {
+                warn!(address = %first, "No egress address: {}", e);
+                self.metrics.error(Cause::Pool);
+                return Ok(service_unavailable());
             }
         };
+        // Only addresses the lease can be bound for, so the request can't leave from the host's own address
+        let resolved = Resolved(ordered.into_iter().filter(|addr| addr.is_ipv6() == lease.ip().is_ipv6()).collect());
+        let bind_addr = lease.ip();
 
-        let mut http = HttpConnector::new();
+        let mut http = HttpConnector::new_with_resolver(resolved);
         http.set_local_address(Some(bind_addr));
-        println!("{} via {}", req.uri().host().unwrap_or_default(), bind_addr);
-
-        if is_system_route {
-            let cmd_str = format!(
-                "ip addr add {}/{} dev {}",
-                bind_addr,
-                if bind_addr.is_ipv6() { 128 } else { 32 },
-                interface
-            );
-            self.execute_command(cmd_str).await;
-
-            if !gateway.is_empty() {
-                let cmd_traceroute_str = format!("traceroute -m 10 -s {} {}", bind_addr, gateway);
-                self.execute_command(cmd_traceroute_str).await;
-            }
-
-            {
-                let mut queue = self.address_queue.lock().await;
-                queue.push_back(bind_addr.to_string());
-            }
-
-            self.manage_address_count(&interface, timeout_duration).await;
-        }
+        access.egress(bind_addr);
+        let req = req.map(|body| paced(counted(body, access, |access, len| access.bytes(len, 0)), permit.clone(), Direction::Upload));
 
         // Apply timeout to the HTTP request process
+        let started = Instant::now();
+        let metrics = self.metrics.clone();
         match timeout(timeout_duration, async {
             let client = Client::builder()
                 .http1_title_case_headers(true)
@@ -427,11 +486,21 @@ impl Proxy {
         })
             .await
         {
-            Ok(Ok(res)) => Ok(res),
-            Ok(Err(e)) => Err(e),
+            Ok(Ok(res)) => {
+                info!(status = res.status().as_u16(), duration_ms = started.elapsed().as_millis() as u64, "Request done");
+                // The address stays leased, and the user's connection slot taken, until the
+                // response body has been sent
+                Ok(res.map(|body| holding(paced(body, permit, Direction::Download), (lease, login))))
+            }
+            Ok(Err(e)) => {
+                warn!(duration_ms = started.elapsed().as_millis() as u64, "Request failed: {}", e);
+                metrics.error(Cause::Io);
+                Err(e)
+            }
             Err(_) => {
                 // Timeout occurred
-                println!("Request processing timed out");
+                warn!("Request processing timed out");
+                metrics.error(Cause::Timeout);
                 Ok(Response::builder()
                     .status(StatusCode::SERVICE_UNAVAILABLE)
                     .body(Body::from("Service Unavailable"))
@@ -439,90 +508,103 @@ impl Proxy {
             }
         }
     }
+}
 
-    async fn manage_address_count(&self, interface: &str, timeout_duration: Duration) {
-
-
-        match timeout(timeout_duration, self.address_queue.lock()).await {
-            Ok(mut queue) => {
-                eprintln!("Acquired lock {}", queue.len());
-                if queue.len() > MAX_ADDRESSES {
-                    let addresses_to_remove = queue.len() - MAX_ADDRESSES;
+/// Passes `body` through, adding the size of each chunk to `access`. An empty body is left as
+/// it is, so the message keeps its framing.
+fn counted(body: Body, access: Access, count: fn(&Access, u64)) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    Body::wrap_stream(body.inspect_ok(move |chunk| count(&access, chunk.len() as u64)))
+}
 
-                    for _ in 0..addresses_to_remove {
-                        if let Some(addr) = queue.pop_front() {
-                            let cmd_str = format!("ip addr del {}/{} dev {}", addr, if addr.contains(":") { 128 } else { 32 }, interface);
-                            if let Err(e) = self.execute_command_del(cmd_str.clone()).await {
-                                eprintln!("Failed to execute command {}: {:?}", cmd_str, e);
-                            }
-                        }
-                    }
-                }
-            }
-            Err(_) => {
-                eprintln!("Failed to acquire lock within timeout period");
-            }
+/// Passes `body` through at the byte rates of `permit`.
+fn paced(body: Body, permit: Permit, direction: Direction) -> Body {
+    if body.is_end_stream() || !permit.is_paced() {
+        return body;
+    }
+    Body::wrap_stream(body.and_then(move |chunk| {
+        let permit = permit.clone();
+        async move {
+            permit.pace(direction, chunk.len() as u64).await;
+            Ok(chunk)
         }
+    }))
+}
+
+/// Passes `body` through, keeping `held` alive until it has been sent or dropped.
+fn holding<T: Send + 'static>(body: Body, held: T) -> Body {
+    if body.is_end_stream() {
+        return body;
     }
+    Body::wrap_stream(body.inspect_ok(move |_| {
+        let _ = &held;
+    }))
+}
 
-    async fn execute_command_del(&self, cmd_str: String) -> Result<(), Box<dyn Error>> {
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(cmd_str)
-            .output()
-            .await?;
+fn service_unavailable() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from("Service Unavailable"))
+        .unwrap()
+}
 
-        if output.status.success() {
-            Ok(())
-        } else {
-            Err(format!("Command failed with status: {:?}", output.status).into())
-        }
+/// Hands hyper's connector the addresses the proxy already resolved and checked, instead of
+/// letting it resolve the name again.
+#[derive(Clone)]
+struct Resolved(Vec<SocketAddr>);
+
+impl Service<Name> for Resolved {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Ready<io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 
-    async fn execute_command(&self, cmd_str: String) {
-        println!("{cmd_str} ");
-        task::spawn(async move {
-            let _result = Command::new("sh")
-                .arg("-c")
-                .arg(&cmd_str)
-                .status()
-                .await
-                .map_err(|e| eprintln!("Failed to execute command: {}. Error: {}", cmd_str, e))
-                .ok();
-        });
+    fn call(&mut self, _: Name) -> Self::Future {
+        ready(Ok(self.0.clone().into_iter()))
     }
 }
 
-fn get_rand_ipv4_socket_addr(ipv4_subnets: &[Ipv4Cidr]) -> SocketAddr {
-    let mut rng = rand::thread_rng();
-    let ipv4_cidr = ipv4_subnets.choose(&mut rng).unwrap(); // 从列表中随机选择一个子网
-    SocketAddr::new(get_rand_ipv4(ipv4_cidr), rng.gen::<u16>())
+fn destination_denied() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from("Destination not allowed"))
+        .unwrap()
 }
 
-fn get_rand_ipv6_socket_addr(ipv6_subnets: &[Ipv6Cidr]) -> SocketAddr {
-    let mut rng = rand::thread_rng();
-    let ipv6_cidr = ipv6_subnets.choose(&mut rng).unwrap(); // 从列表中随机选择一个子网
-    SocketAddr::new(get_rand_ipv6(ipv6_cidr), rng.gen::<u16>())
+fn auth_error_response(error: AuthError) -> Response<Body> {
+    match error {
+        AuthError::TooManyConnections => Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(Body::from("Too many connections"))
+            .unwrap(),
+        AuthError::Disabled => Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("User disabled"))
+            .unwrap(),
+        AuthError::UnknownUser | AuthError::BadPassword => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header("WWW-Authenticate", r#"Basic realm="User Visible Realm""#)
+            .body(Body::from("Unauthorized"))
+            .unwrap(),
+    }
 }
 
-fn get_rand_ipv4(ipv4_cidr: &Ipv4Cidr) -> IpAddr {
-    let mut ipv4 = u32::from(ipv4_cidr.first_address());  // 使用 first_address() 获取网络地址
-    if ipv4_cidr.network_length() != 32 {
-        let rand: u32 = random();
-        let net_part = (ipv4 >> (32 - ipv4_cidr.network_length())) << (32 - ipv4_cidr.network_length());
-        let host_part = (rand << ipv4_cidr.network_length()) >> ipv4_cidr.network_length();
-        ipv4 = net_part | host_part;
-    }
-    IpAddr::V4(ipv4.into())
+/// Username and password from the `Proxy-Authorization: Basic` header, if present.
+fn proxy_credentials(req: &Request<Body>) -> Option<(String, String)> {
+    let auth_str = req.headers().get("Proxy-Authorization")?.to_str().ok()?;
+    parse_basic_credentials(auth_str)
 }
 
-fn get_rand_ipv6(ipv6_cidr: &Ipv6Cidr) -> IpAddr {
-    let mut ipv6 = u128::from(ipv6_cidr.first_address());  // 使用 first_address() 获取网络地址
-    if ipv6_cidr.network_length() != 128 {
-        let rand: u128 = random();
-        let net_part = (ipv6 >> (128 - ipv6_cidr.network_length())) << (128 - ipv6_cidr.network_length());
-        let host_part = (rand << ipv6_cidr.network_length()) >> ipv6_cidr.network_length();
-        ipv6 = net_part | host_part;
-    }
-    IpAddr::V6(ipv6.into())
+/// Decodes a `Basic <base64(username:password)>` header value.
+pub(crate) fn parse_basic_credentials(auth_str: &str) -> Option<(String, String)> {
+    let encoded_credentials = auth_str.strip_prefix("Basic ")?;
+    let decoded_credentials = STANDARD.decode(encoded_credentials.trim()).ok()?;
+    let decoded_str = String::from_utf8(decoded_credentials).unwrap_or_default();
+    let (username, password) = decoded_str.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
 }
@@ -0,0 +1,189 @@
+use arc_swap::ArcSwap;
+use getopts::Matches;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::auth::Auth;
+use crate::config::{load_settings, SharedForward, SharedListener};
+use crate::limit::Limiter;
+use crate::pool::AddressPool;
+
+/// Re-reads the configuration on SIGHUP or from the admin endpoint and applies it.
+///
+/// Subnets, allow-lists, users, limits, routes, strategies and forward targets are swapped
+/// atomically and apply to connections accepted afterwards; open tunnels keep what they started
+/// with, except that byte rates apply to them right away. Adding, removing or re-binding
+/// listeners and changing the timeouts, hash secret, DNS, system-route or logging settings still
+/// needs a restart.
+pub struct Reloader {
+    matches: Matches,
+    pool: Arc<AddressPool>,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    http: Vec<SharedListener>,
+    socks5: Vec<SharedListener>,
+    mixed: Vec<SharedListener>,
+    forward: Vec<SharedForward>,
+}
+
+impl Reloader {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        matches: Matches,
+        pool: Arc<AddressPool>,
+        auth: Arc<Auth>,
+        limiter: Arc<Limiter>,
+        http: Vec<SharedListener>,
+        socks5: Vec<SharedListener>,
+        mixed: Vec<SharedListener>,
+        forward: Vec<SharedForward>,
+    ) -> Self {
+        Reloader { matches, pool, auth, limiter, http, socks5, mixed, forward }
+    }
+
+    /// Applies the current configuration and returns a summary. On error nothing is changed.
+    pub fn reload(&self) -> Result<String, String> {
+        let settings = load_settings(&self.matches)?;
+
+        let mut updated = 0;
+        let mut notes = Vec::new();
+        updated += swap_matching("HTTP", &self.http, settings.http, |l| l.bind, &mut notes);
+        updated += swap_matching("SOCKS5", &self.socks5, settings.socks5, |l| l.bind, &mut notes);
+        updated += swap_matching("mixed", &self.mixed, settings.mixed, |l| l.bind, &mut notes);
+        updated += swap_matching("forward", &self.forward, settings.forward, |f| f.mapping.local_addr, &mut notes);
+        self.pool.set_subnets(settings.ipv6_subnets, settings.ipv4_subnets);
+        self.auth.set_users(settings.users);
+        self.limiter.set_limits(settings.limits, settings.client_limits);
+
+        let mut summary = format!(
+            "{} listeners updated, {} users, address pool: {}",
+            updated,
+            self.auth.user_count(),
+            self.pool.stats()
+        );
+        for note in notes {
+            summary.push_str("; ");
+            summary.push_str(&note);
+        }
+        Ok(summary)
+    }
+
+    /// `reload`, with the outcome written to the log.
+    pub fn reload_and_log(&self) -> Result<String, String> {
+        let result = self.reload();
+        match &result {
+            Ok(summary) => info!("Configuration reloaded: {}", summary),
+            Err(e) => warn!("Configuration reload failed, keeping the current one: {}", e),
+        }
+        result
+    }
+
+    /// Reloads on every SIGHUP.
+    pub fn spawn_sighup(self: &Arc<Self>) {
+        let reloader = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!("Failed to install SIGHUP handler: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                let _ = reloader.reload_and_log();
+            }
+        });
+    }
+}
+
+/// Stores each new entry into the running listener bound to the same address and returns
+/// how many were updated. Entries without a running counterpart are reported in `notes`.
+fn swap_matching<T>(
+    kind: &str,
+    running: &[Arc<ArcSwap<T>>],
+    new: Vec<T>,
+    addr: impl Fn(&T) -> SocketAddr,
+    notes: &mut Vec<String>,
+) -> usize {
+    let new_addrs: Vec<SocketAddr> = new.iter().map(&addr).collect();
+    for shared in running {
+        let running_addr = addr(&shared.load());
+        if !new_addrs.contains(&running_addr) {
+            notes.push(format!("{} listener on {} is no longer configured, it stops on restart", kind, running_addr));
+        }
+    }
+
+    let mut updated = 0;
+    for entry in new {
+        let entry_addr = addr(&entry);
+        match running.iter().find(|shared| addr(&shared.load()) == entry_addr) {
+            Some(shared) => {
+                shared.store(Arc::new(entry));
+                updated += 1;
+            }
+            None => notes.push(format!("new {} listener on {} starts on restart", kind, entry_addr)),
+        }
+    }
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Users;
+    use crate::config::options;
+    use crate::limit::Limits;
+    use crate::session::SessionStore;
+    use crate::strategy::{AddressDeriver, Strategy};
+    use std::time::Duration;
+
+    #[test]
+    fn reload_swaps_settings_of_running_listeners() {
+        let path = std::env::temp_dir().join(format!("reload-{}.toml", std::process::id()));
+        let write = |strategy: &str, subnets: &str| {
+            let config = format!(
+                "ipv6_subnets = [{}]\n[[http]]\nbind = \"127.0.0.1:18080\"\nstrategy = \"{}\"\n",
+                subnets, strategy
+            );
+            std::fs::write(&path, config).unwrap();
+        };
+        write("random", "\"2001:db8::/64\"");
+
+        let matches = options().parse(["-c", path.to_str().unwrap()]).unwrap();
+        let settings = load_settings(&matches).unwrap();
+        let pool = Arc::new(AddressPool::new(
+            settings.ipv6_subnets,
+            settings.ipv4_subnets,
+            SessionStore::new(Duration::from_secs(60)),
+            AddressDeriver::new("test-secret"),
+            None,
+        ));
+        let http: Vec<SharedListener> =
+            settings.http.into_iter().map(|l| Arc::new(ArcSwap::from_pointee(l))).collect();
+        let reloader = Reloader::new(
+            matches,
+            Arc::clone(&pool),
+            Arc::new(Auth::new(Users::default())),
+            Arc::new(Limiter::new(Limits::default(), Limits::default())),
+            http.clone(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+
+        write("per-client", "\"2001:db8::/64\", \"2001:db8:1::/64\"");
+        reloader.reload().unwrap();
+        assert_eq!(http[0].load().strategy, Strategy::PerClient);
+        assert_eq!(pool.subnets().ipv6.len(), 2);
+
+        // A broken file leaves everything as it was
+        write("bogus", "\"2001:db8::/64\"");
+        assert!(reloader.reload().is_err());
+        assert_eq!(http[0].load().strategy, Strategy::PerClient);
+        assert_eq!(pool.subnets().ipv6.len(), 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
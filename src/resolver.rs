@@ -0,0 +1,290 @@
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::DnsSettings;
+use crate::lease::Lease;
+use crate::pool::{AddressPool, Subnets};
+use crate::strategy::{SelectionKey, Strategy};
+
+/// One upstream server: `[udp|tcp|tls|https]://<ip>[:port][#<tls name>]`, or a bare address
+/// for plain UDP. DNS over TLS and HTTPS need the server's certificate name after `#`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upstream {
+    pub protocol: Protocol,
+    pub addr: SocketAddr,
+    pub tls_name: Option<String>,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.trim().split_once("://").unwrap_or(("udp", s.trim()));
+        let (protocol, default_port) = match scheme {
+            "udp" => (Protocol::Udp, 53),
+            "tcp" => (Protocol::Tcp, 53),
+            "tls" => (Protocol::Tls, 853),
+            "https" => (Protocol::Https, 443),
+            scheme => return Err(format!("unknown scheme '{}', expected udp, tcp, tls or https", scheme)),
+        };
+        let (addr, tls_name) = match rest.split_once('#') {
+            Some((addr, name)) => (addr, Some(name.to_string())),
+            None => (rest, None),
+        };
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(addr.trim_start_matches('[').trim_end_matches(']').parse().map_err(|e| format!("{}", e))?, default_port),
+        };
+        if matches!(protocol, Protocol::Tls | Protocol::Https) && tls_name.is_none() {
+            return Err(format!("{} needs the server name, e.g. {}://{}#dns.example.com", scheme, scheme, addr));
+        }
+        Ok(Upstream { protocol, addr, tls_name })
+    }
+}
+
+/// Name resolution for the proxies: a caching resolver for the configured upstreams (or the
+/// system's) with a static hosts map in front of it.
+///
+/// Answers are cached for their TTL and failed lookups (NXDOMAIN or no records) for the SOA
+/// minimum, both bounded by the configured limits. With `from_pool`, queries leave from a pool
+/// address of the upstream's family; those addresses are kept until the process exits.
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    _leases: Vec<Lease>,
+}
+
+impl Resolver {
+    pub async fn new(settings: DnsSettings, pool: &AddressPool, timeout_duration: Duration) -> Result<Self, String> {
+        let (config, mut servers) = if settings.servers.is_empty() {
+            let (system, _) = hickory_resolver::system_conf::read_system_conf()
+                .map_err(|e| format!("No DNS servers configured and the system's can't be read: {}", e))?;
+            let servers = system.name_servers().to_vec();
+            (system, servers)
+        } else {
+            let servers = settings
+                .servers
+                .iter()
+                .map(|upstream| NameServerConfig {
+                    tls_dns_name: upstream.tls_name.clone(),
+                    ..NameServerConfig::new(upstream.addr, upstream.protocol)
+                })
+                .collect();
+            (ResolverConfig::new(), servers)
+        };
+
+        let mut leases: Vec<Lease> = Vec::new();
+        if settings.from_pool {
+            let subnets = pool.subnets();
+            for server in &mut servers {
+                let family_lease = leases.iter().find(|lease| lease.ip().is_ipv6() == server.socket_addr.is_ipv6());
+                let ip = match family_lease {
+                    Some(lease) => lease.ip(),
+                    None => {
+                        let key = SelectionKey::default();
+                        let selected = match server.socket_addr {
+                            SocketAddr::V6(_) => pool.select_ipv6(Strategy::Random, &key, &subnets).await,
+                            SocketAddr::V4(_) => pool.select_ipv4(Strategy::Random, &key, &subnets).await,
+                        };
+                        let Some(ip) = selected else {
+                            warn!("No pool subnet of the family of DNS server {}, its queries use the default address", server.socket_addr);
+                            continue;
+                        };
+                        let lease = pool.activate(ip, timeout_duration).await.map_err(|e| format!("DNS egress address {}: {}", ip, e))?;
+                        leases.push(lease);
+                        ip
+                    }
+                };
+                server.bind_addr = Some(SocketAddr::new(ip, 0));
+            }
+        }
+
+        let config = ResolverConfig::from_parts(config.domain().cloned(), config.search().to_vec(), servers);
+        let mut opts = ResolverOpts::default();
+        opts.cache_size = settings.cache_size;
+        opts.positive_min_ttl = settings.min_ttl;
+        opts.positive_max_ttl = settings.max_ttl;
+        opts.negative_min_ttl = settings.negative_ttl;
+        opts.negative_max_ttl = settings.negative_ttl;
+        Ok(Resolver {
+            inner: TokioAsyncResolver::tokio(config, opts),
+            hosts: settings.hosts,
+            _leases: leases,
+        })
+    }
+
+    /// A resolver without upstreams, for tests that only use addresses.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        Resolver {
+            inner: TokioAsyncResolver::tokio(ResolverConfig::new(), ResolverOpts::default()),
+            hosts: HashMap::new(),
+            _leases: Vec::new(),
+        }
+    }
+
+    /// Resolves `host` to addresses with `port`, IPv4 first. IP literals are returned as they
+    /// are and the hosts map wins over DNS; otherwise only the record types of the families
+    /// `subnets` can reach are asked for, A and AAAA in parallel, and A as well with `nat64`
+    /// since NAT64 reaches IPv4 from IPv6. Fails if nothing is found.
+    pub async fn lookup(&self, host: &str, port: u16, subnets: &Subnets, nat64: bool) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        if let Some(ips) = self.hosts.get(&host.trim_end_matches('.').to_ascii_lowercase()) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+
+        let ipv4 = async {
+            match subnets.ipv4.is_empty() && !nat64 {
+                true => Ok(Vec::new()),
+                false => self.inner.ipv4_lookup(host).await.map(|found| found.iter().map(|a| IpAddr::V4(a.0)).collect()),
+            }
+        };
+        let ipv6 = async {
+            match subnets.ipv6.is_empty() {
+                true => Ok(Vec::new()),
+                false => self.inner.ipv6_lookup(host).await.map(|found| found.iter().map(|aaaa| IpAddr::V6(aaaa.0)).collect()),
+            }
+        };
+        let (ipv4, ipv6): (Result<Vec<IpAddr>, ResolveError>, Result<Vec<IpAddr>, ResolveError>) = tokio::join!(ipv4, ipv6);
+
+        let mut addrs = Vec::new();
+        let mut error = None;
+        for result in [ipv4, ipv6] {
+            match result {
+                Ok(ips) => addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port))),
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+        match (addrs.is_empty(), error) {
+            (false, _) => Ok(addrs),
+            (true, Some(e)) => Err(io::Error::other(e.to_string())),
+            (true, None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", host))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::{A, AAAA, SOA};
+    use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+
+    /// A UDP DNS server answering `stub.test` with 192.0.2.10 and 2001:db8::10 and NXDOMAIN
+    /// for anything else. Returns its address and the number of queries it got.
+    async fn stub_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let question = query.queries()[0].clone();
+                let mut response = Message::new();
+                response.set_id(query.id()).set_message_type(MessageType::Response).set_recursion_available(true);
+                response.add_query(question.clone());
+                let name = question.name().clone();
+                if name == Name::from_ascii("stub.test.").unwrap() {
+                    let rdata = match question.query_type() {
+                        RecordType::A => RData::A(A::new(192, 0, 2, 10)),
+                        _ => RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x10)),
+                    };
+                    response.add_answer(Record::from_rdata(name, 300, rdata));
+                } else {
+                    let soa = SOA::new(Name::from_ascii("test.").unwrap(), Name::from_ascii("admin.test.").unwrap(), 1, 60, 60, 60, 300);
+                    response.set_response_code(ResponseCode::NXDomain);
+                    response.add_name_server(Record::from_rdata(Name::from_ascii("test.").unwrap(), 300, RData::SOA(soa)));
+                }
+                socket.send_to(&response.to_vec().unwrap(), peer).await.unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    async fn resolver(server: SocketAddr) -> Resolver {
+        let pool = AddressPool::for_tests(&[], &[]);
+        let settings = DnsSettings {
+            servers: vec![Upstream { protocol: Protocol::Udp, addr: server, tls_name: None }],
+            cache_size: 64,
+            min_ttl: None,
+            max_ttl: None,
+            negative_ttl: None,
+            from_pool: false,
+            hosts: HashMap::from([("pinned.test".to_string(), vec!["198.51.100.7".parse().unwrap()])]),
+        };
+        Resolver::new(settings, &pool, Duration::from_secs(5)).await.unwrap()
+    }
+
+    fn subnets(ipv6: &[&str], ipv4: &[&str]) -> Subnets {
+        Subnets {
+            ipv6: ipv6.iter().map(|s| s.parse().unwrap()).collect(),
+            ipv4: ipv4.iter().map(|s| s.parse().unwrap()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_and_failures_are_cached() {
+        let (server, queries) = stub_server().await;
+        let resolver = resolver(server).await;
+        let both = subnets(&["2001:db8:1::/48"], &["198.51.100.0/24"]);
+
+        let addrs = resolver.lookup("stub.test", 443, &both, false).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.10:443".parse().unwrap(), "[2001:db8::10]:443".parse().unwrap()]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        resolver.lookup("stub.test", 80, &both, false).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        let ipv4_only = subnets(&[], &["198.51.100.0/24"]);
+        assert!(resolver.lookup("missing.test", 80, &ipv4_only, false).await.is_err());
+        assert!(resolver.lookup("missing.test", 80, &ipv4_only, false).await.is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn hosts_and_literals_skip_dns_and_families_are_filtered() {
+        let (server, queries) = stub_server().await;
+        let resolver = resolver(server).await;
+        let both = subnets(&["2001:db8:1::/48"], &["198.51.100.0/24"]);
+
+        assert_eq!(resolver.lookup("Pinned.Test.", 80, &both, false).await.unwrap(), vec!["198.51.100.7:80".parse().unwrap()]);
+        assert_eq!(resolver.lookup("[2001:db8::1]", 80, &both, false).await.unwrap(), vec!["[2001:db8::1]:80".parse().unwrap()]);
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+
+        let ipv6_only = subnets(&["2001:db8:1::/48"], &[]);
+        assert_eq!(resolver.lookup("stub.test", 80, &ipv6_only, false).await.unwrap(), vec!["[2001:db8::10]:80".parse().unwrap()]);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        // NAT64 needs the A records too
+        assert_eq!(resolver.lookup("stub.test", 80, &ipv6_only, true).await.unwrap().len(), 2);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn upstreams_are_parsed() {
+        let upstream: Upstream = "1.1.1.1".parse().unwrap();
+        assert_eq!((upstream.protocol, upstream.addr), (Protocol::Udp, "1.1.1.1:53".parse().unwrap()));
+        let upstream: Upstream = "tls://[2606:4700::1111]#cloudflare-dns.com".parse().unwrap();
+        assert_eq!((upstream.protocol, upstream.addr), (Protocol::Tls, "[2606:4700::1111]:853".parse().unwrap()));
+        assert_eq!(upstream.tls_name.as_deref(), Some("cloudflare-dns.com"));
+        assert_eq!("tcp://9.9.9.9:5353".parse::<Upstream>().unwrap().addr, "9.9.9.9:5353".parse().unwrap());
+        assert!("https://1.1.1.1".parse::<Upstream>().is_err());
+        assert!("quic://1.1.1.1".parse::<Upstream>().is_err());
+        assert!("dns.example.com".parse::<Upstream>().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::str::FromStr;
+
+use crate::acl::glob_match;
+use crate::pool::Subnets;
+
+/// A destination host pattern: `example.com` matches that name only, `.example.com` the name
+/// and everything under it, and a glob such as `api-*.example.com` what it spells out, `*`
+/// matching any run of characters. `*` alone matches every host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostPattern {
+    Suffix(String),
+    /// Lowercase, exact names are globs without `*`
+    Glob(String),
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '*')) {
+            return Err(format!("invalid host pattern '{}'", s));
+        }
+        match s.strip_prefix('.') {
+            Some(suffix) if !suffix.is_empty() && !suffix.contains('*') => Ok(HostPattern::Suffix(suffix.to_string())),
+            Some(_) => Err(format!("invalid host pattern '{}'", s)),
+            None => Ok(HostPattern::Glob(s)),
+        }
+    }
+}
+
+impl HostPattern {
+    /// `host` is lowercase without the trailing dot.
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Suffix(suffix) => host.strip_suffix(suffix.as_str()).is_some_and(|rest| rest.is_empty() || rest.ends_with('.')),
+            HostPattern::Glob(glob) => glob_match(glob.as_bytes(), host.as_bytes()),
+        }
+    }
+}
+
+/// Sends the destinations matching any of `hosts` out of `subnets`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub hosts: Vec<HostPattern>,
+    pub subnets: Subnets,
+}
+
+/// Ordered routing rules from destination host to egress subnets; the first route that
+/// matches decides, and unmatched destinations use the whole pool. A route for `*` at the
+/// end sets a different default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Routes { routes }
+    }
+
+    /// The subnets for `host`, the name the client asked for (or the address, if it gave one),
+    /// or `None` for the whole pool.
+    pub fn subnets_for(&self, host: &str) -> Option<&Subnets> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.routes
+            .iter()
+            .find(|route| route.hosts.iter().any(|pattern| pattern.matches(&host)))
+            .map(|route| &route.subnets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(hosts: &[&str], ipv6: &str) -> Route {
+        Route {
+            hosts: hosts.iter().map(|host| host.parse().unwrap()).collect(),
+            subnets: Subnets { ipv6: vec![ipv6.parse().unwrap()], ipv4: Vec::new() },
+        }
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let routes = Routes::new(vec![
+            route(&["api.example.com"], "2001:db8:0:1::/64"),
+            route(&[".example.com", "cdn-*.example.net"], "2001:db8:0:2::/64"),
+        ]);
+        let ipv6 = |host: &str| routes.subnets_for(host).map(|subnets| subnets.ipv6[0].to_string());
+        assert_eq!(ipv6("API.example.com.").as_deref(), Some("2001:db8:0:1::/64"));
+        assert_eq!(ipv6("example.com").as_deref(), Some("2001:db8:0:2::/64"));
+        assert_eq!(ipv6("www.example.com").as_deref(), Some("2001:db8:0:2::/64"));
+        assert_eq!(ipv6("cdn-3.example.net").as_deref(), Some("2001:db8:0:2::/64"));
+        assert_eq!(ipv6("notexample.com"), None);
+        assert_eq!(ipv6("example.net"), None);
+
+        let with_default = Routes::new(vec![route(&["*"], "2001:db8:0:3::/64")]);
+        assert!(with_default.subnets_for("anything.test").is_some());
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for pattern in ["", ".", ".*.example.com", "exa/mple.com"] {
+            assert!(pattern.parse::<HostPattern>().is_err(), "{}", pattern);
+        }
+    }
+}
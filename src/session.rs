@@ -0,0 +1,85 @@
+use cidr::{Ipv4Cidr, Ipv6Cidr};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::pool::{get_rand_ipv4, get_rand_ipv6};
+
+/// Username suffix that asks for a sticky egress address, e.g. `user-session-abc123`.
+const SESSION_MARKER: &str = "-session-";
+
+/// Splits a username of the form `user-session-<id>` into the real username and the session id.
+pub fn split_session(username: &str) -> (&str, Option<&str>) {
+    match username.find(SESSION_MARKER) {
+        Some(idx) if idx + SESSION_MARKER.len() < username.len() => {
+            (&username[..idx], Some(&username[idx + SESSION_MARKER.len()..]))
+        }
+        _ => (username, None),
+    }
+}
+
+struct SessionEntry {
+    ipv6: Option<IpAddr>,
+    ipv4: Option<IpAddr>,
+    last_used: Instant,
+}
+
+/// Remembers the egress address handed out for each session until it has been idle for `ttl`.
+pub struct SessionStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, SessionEntry>>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        SessionStore {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the IPv6 address pinned to `session`, picking a new one if it expired or
+    /// no longer belongs to `subnets`.
+    pub async fn ipv6_addr(&self, session: &str, subnets: &[Ipv6Cidr]) -> Option<IpAddr> {
+        let mut entries = self.entries.lock().await;
+        let entry = self.entry(&mut entries, session);
+
+        let still_valid = matches!(entry.ipv6, Some(IpAddr::V6(ip)) if subnets.iter().any(|s| s.contains(&ip)));
+        if !still_valid {
+            entry.ipv6 = subnets.choose(&mut rand::thread_rng()).map(get_rand_ipv6);
+        }
+        entry.ipv6
+    }
+
+    /// Returns the IPv4 address pinned to `session`, picking a new one if it expired or
+    /// no longer belongs to `subnets`.
+    pub async fn ipv4_addr(&self, session: &str, subnets: &[Ipv4Cidr]) -> Option<IpAddr> {
+        let mut entries = self.entries.lock().await;
+        let entry = self.entry(&mut entries, session);
+
+        let still_valid = matches!(entry.ipv4, Some(IpAddr::V4(ip)) if subnets.iter().any(|s| s.contains(&ip)));
+        if !still_valid {
+            entry.ipv4 = subnets.choose(&mut rand::thread_rng()).map(get_rand_ipv4);
+        }
+        entry.ipv4
+    }
+
+    fn entry<'a>(
+        &self,
+        entries: &'a mut HashMap<String, SessionEntry>,
+        session: &str,
+    ) -> &'a mut SessionEntry {
+        let now = Instant::now();
+        entries.retain(|_, entry| now.duration_since(entry.last_used) < self.ttl);
+
+        let entry = entries.entry(session.to_string()).or_insert(SessionEntry {
+            ipv6: None,
+            ipv4: None,
+            last_used: now,
+        });
+        entry.last_used = now;
+        entry
+    }
+}
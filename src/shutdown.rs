@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+use crate::pool::AddressPool;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Turns drain mode on or off. While draining, the listeners turn new connections and
+/// requests away and those already open carry on, e.g. before taking the host out of service.
+pub fn set_draining(draining: bool) {
+    DRAINING.store(draining, Ordering::Relaxed);
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Resolves on SIGINT or SIGTERM.
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// Waits up to `deadline` for open tunnels to finish, then removes every address the pool
+/// added to the interface. Call once the listeners have stopped accepting.
+pub async fn drain(pool: &AddressPool, deadline: Duration) {
+    let give_up = Instant::now() + deadline;
+    while pool.open_connections() > 0 && Instant::now() < give_up {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let open = pool.open_connections();
+    if open > 0 {
+        warn!("Shutdown deadline reached with {} connections still open", open);
+    }
+    pool.release_all().await;
+}
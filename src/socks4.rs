@@ -0,0 +1,252 @@
+use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::time::{timeout, Duration, Instant};
+use tracing::info;
+
+use crate::access_log::Access;
+use crate::auth::{Auth, Login};
+use crate::config::Listener;
+use crate::happy_eyeballs;
+use crate::limit::{Limiter, Shaped};
+use crate::metrics::{Cause, Failure, ListenerMetrics};
+use crate::pool::{AddressPool, Subnets};
+use crate::resolver::Resolver;
+use crate::session::split_session;
+use crate::strategy::SelectionKey;
+
+pub(crate) const SOCKS4_VERSION: u8 = 0x04;
+const REPLY_VERSION: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+
+const REQUEST_GRANTED: u8 = 90;
+const REQUEST_REJECTED: u8 = 91;
+/// "The client program and identd report different user-ids", used for failed logins
+const REQUEST_BAD_USERID: u8 = 93;
+
+/// USERID and SOCKS4a host names are NUL-terminated, this bounds how much is read for them
+const MAX_FIELD_LEN: usize = 255;
+
+/// Serves one SOCKS4 or SOCKS4a CONNECT after the version byte has been read.
+///
+/// SOCKS4 has no password field, so with authentication on the USERID carries `user:password`;
+/// the `-session-<id>` suffix works as in SOCKS5. The reply can only describe IPv4, so targets
+/// are reached over IPv4 and the egress address comes from the pool's IPv4 subnets, or through
+/// NAT64 from its IPv6 subnets if the listener has a NAT64 prefix.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_socks4_connection(
+    socket: &mut TcpStream,
+    pool: &AddressPool,
+    settings: &Listener,
+    auth: &Auth,
+    limiter: &Limiter,
+    resolver: &Resolver,
+    metrics: &ListenerMetrics,
+    access: &Access,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let client_ip = socket.peer_addr().ok().map(|addr| addr.ip());
+
+    let mut buf = [0; 7];
+    timeout(timeout_duration, socket.read_exact(&mut buf)).await??;
+    let command = buf[0];
+    let port = u16::from_be_bytes([buf[1], buf[2]]);
+    let ip = Ipv4Addr::new(buf[3], buf[4], buf[5], buf[6]);
+    let userid = timeout(timeout_duration, read_field(socket)).await??;
+    // SOCKS4a: 0.0.0.x with x != 0 means a host name follows the USERID
+    let domain = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => Some(timeout(timeout_duration, read_field(socket)).await??),
+        _ => None,
+    };
+
+    if command != CMD_CONNECT {
+        return reject(socket, REQUEST_REJECTED, Cause::Unsupported, format!("Unsupported SOCKS4 command {:#04x}", command), timeout_duration).await;
+    }
+    metrics.request("connect");
+    access.command("CONNECT");
+
+    let (username, password) = userid.split_once(':').unwrap_or((&userid, ""));
+    // Held until the relay below finishes
+    let mut login: Option<Login> = None;
+    if auth.is_enabled() {
+        match auth.login(split_session(username).0, password).await {
+            Ok(user) => {
+                access.user(split_session(username).0);
+                login = Some(user);
+            }
+            Err(e) => return reject(socket, REQUEST_BAD_USERID, Cause::Auth, format!("Authentication failed for {}: {}", username, e), timeout_duration).await,
+        }
+    }
+    let permit = match limiter.admit(client_ip, login.as_ref(), true) {
+        Ok(permit) => permit,
+        Err(e) => return reject(socket, REQUEST_REJECTED, Cause::Limited, e.to_string(), timeout_duration).await,
+    };
+    // `user-session-<id>` pins the egress address for the whole session
+    let session = split_session(username).1.map(|_| username);
+    // A user's own subnets take precedence over the routing rules
+    let route_host = domain.clone().unwrap_or_else(|| ip.to_string());
+    access.target(format_args!("{}:{}", route_host, port));
+    let subnets = login.as_ref().and_then(Login::subnets).or_else(|| settings.routes.subnets_for(&route_host));
+
+    let pool_subnets = pool.subnets();
+    // Only A records are asked for, SOCKS4 targets are IPv4
+    let ipv4_only = Subnets { ipv6: Vec::new(), ipv4: subnets.unwrap_or(&pool_subnets).ipv4.clone() };
+    let (target, addrs) = match domain {
+        None => (ip.to_string(), vec![SocketAddr::V4(SocketAddrV4::new(ip, port))]),
+        Some(domain) => match resolver.lookup(&domain, port, &ipv4_only, settings.nat64.is_some()).await.map(|addrs| addrs.into_iter().filter(SocketAddr::is_ipv4).collect::<Vec<_>>()) {
+            Ok(addrs) => (domain, addrs),
+            Err(e) => return reject(socket, REQUEST_REJECTED, Cause::Unreachable, format!("Failed to resolve {}: {}", domain, e), timeout_duration).await,
+        },
+    };
+    if addrs.is_empty() {
+        return reject(socket, REQUEST_REJECTED, Cause::Unreachable, format!("No IPv4 address for {}", target), timeout_duration).await;
+    }
+
+    let allowed: Vec<SocketAddr> = addrs.iter().copied().filter(|addr| settings.destinations.allows(&target, *addr)).collect();
+    if allowed.is_empty() {
+        return reject(socket, REQUEST_REJECTED, Cause::Denied, format!("Destination {} ({}) not allowed", target, addrs[0]), timeout_duration).await;
+    }
+
+    let key = SelectionKey { client: client_ip, target: Some(&target), session };
+    let started = Instant::now();
+    // The lease is held until the relay below finishes
+    let (mut remote, _lease) = match happy_eyeballs::connect(pool, settings, &key, subnets, &allowed, timeout_duration).await {
+        Ok(connected) => {
+            metrics.connected(started.elapsed());
+            connected
+        }
+        Err(e) => return reject(socket, REQUEST_REJECTED, Cause::from(&e), format!("Failed to connect to {}: {}", target, e), timeout_duration).await,
+    };
+
+    let local_addr = remote.local_addr()?;
+    access.egress(local_addr.ip());
+    timeout(timeout_duration, socket.write_all(&reply(REQUEST_GRANTED, local_addr))).await??;
+    access.status(REQUEST_GRANTED as u16);
+    let (client_bytes, server_bytes) = timeout(timeout_duration, metrics.relay(&mut Shaped::new(socket, permit), &mut remote)).await??;
+    access.bytes(client_bytes, server_bytes);
+    info!(client_bytes, server_bytes, duration_ms = started.elapsed().as_millis() as u64, "Tunnel closed");
+    Ok(())
+}
+
+/// A reply with DSTPORT and DSTIP set to `addr`, or zeros if it isn't IPv4.
+fn reply(code: u8, addr: SocketAddr) -> [u8; 8] {
+    let (ip, port) = match addr {
+        SocketAddr::V4(addr) => (addr.ip().octets(), addr.port()),
+        SocketAddr::V6(_) => ([0; 4], 0),
+    };
+    let port = port.to_be_bytes();
+    [REPLY_VERSION, code, port[0], port[1], ip[0], ip[1], ip[2], ip[3]]
+}
+
+/// Sends a failure reply, then returns `error` with its cause so the caller can log and count
+/// it and close.
+async fn reject(
+    socket: &mut TcpStream,
+    code: u8,
+    cause: Cause,
+    error: String,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let _ = timeout(timeout_duration, socket.write_all(&reply(code, SocketAddr::from(([0, 0, 0, 0], 0))))).await;
+    Err(Failure::new(cause, error).replied(code).into())
+}
+
+/// Reads a NUL-terminated string of at most `MAX_FIELD_LEN` bytes.
+async fn read_field(socket: &mut TcpStream) -> Result<String, Box<dyn Error>> {
+    let mut field = Vec::new();
+    loop {
+        let byte = socket.read_u8().await?;
+        if byte == 0 {
+            return Ok(String::from_utf8(field)?);
+        }
+        if field.len() == MAX_FIELD_LEN {
+            return Err("SOCKS4 field too long".into());
+        }
+        field.push(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Credential, User, Users};
+    use crate::limit::Limits;
+    use tokio::net::TcpListener;
+
+    /// Runs one SOCKS4 connection against a pool of `127.0.0.0/24` and returns the client end.
+    async fn connect_proxy(users: Vec<User>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let pool = AddressPool::for_tests(&[], &["127.0.0.0/24"]);
+            let settings = Listener::for_tests();
+            let auth = Auth::new(Users::new(users).unwrap());
+            let metrics = ListenerMetrics::new(settings.bind, "socks4");
+            let mut version = [0; 1];
+            socket.read_exact(&mut version).await.unwrap();
+            let _ = handle_socks4_connection(&mut socket, &pool, &settings, &auth, &Limiter::new(Limits::default(), Limits::default()), &Resolver::for_tests(), &metrics, &Access::new("socks4", settings.bind, None, "-"), Duration::from_secs(5)).await;
+        });
+        client
+    }
+
+    fn request(port: u16, ip: [u8; 4], userid: &str, domain: Option<&str>) -> Vec<u8> {
+        let mut buf = vec![SOCKS4_VERSION, CMD_CONNECT];
+        buf.extend_from_slice(&port.to_be_bytes());
+        buf.extend_from_slice(&ip);
+        buf.extend_from_slice(userid.as_bytes());
+        buf.push(0);
+        if let Some(domain) = domain {
+            buf.extend_from_slice(domain.as_bytes());
+            buf.push(0);
+        }
+        buf
+    }
+
+    async fn read_reply(client: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], REPLY_VERSION);
+        let addr = SocketAddr::from(([reply[4], reply[5], reply[6], reply[7]], u16::from_be_bytes([reply[2], reply[3]])));
+        (reply[1], addr)
+    }
+
+    #[tokio::test]
+    async fn socks4_and_socks4a_connect() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+
+        let mut client = connect_proxy(Vec::new()).await;
+        client.write_all(&request(port, [127, 0, 0, 1], "", None)).await.unwrap();
+        let (status, bound) = read_reply(&mut client).await;
+        assert_eq!(status, REQUEST_GRANTED);
+        let (mut peer, from) = target.accept().await.unwrap();
+        assert_eq!(bound, from);
+        peer.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+
+        let mut client = connect_proxy(Vec::new()).await;
+        client.write_all(&request(port, [0, 0, 0, 1], "legacy", Some("localhost"))).await.unwrap();
+        let (status, _) = read_reply(&mut client).await;
+        assert_eq!(status, REQUEST_GRANTED);
+        target.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn userid_carries_credentials() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let users = || vec![User::new("alice", Credential::Plain("secret".into()))];
+
+        let mut client = connect_proxy(users()).await;
+        client.write_all(&request(port, [127, 0, 0, 1], "alice:wrong", None)).await.unwrap();
+        assert_eq!(read_reply(&mut client).await.0, REQUEST_BAD_USERID);
+
+        let mut client = connect_proxy(users()).await;
+        client.write_all(&request(port, [127, 0, 0, 1], "alice-session-1:secret", None)).await.unwrap();
+        assert_eq!(read_reply(&mut client).await.0, REQUEST_GRANTED);
+    }
+}
@@ -1,103 +1,163 @@
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use std::error::Error;
-use std::net::{SocketAddr, IpAddr};
-use rand::random;
-use rand::seq::SliceRandom;
-use std::collections::VecDeque;
+use std::fmt;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use lazy_static::lazy_static;
 use std::io;
-use tokio::time::{timeout, Duration};
-use cidr::{Ipv4Cidr, Ipv6Cidr};
-
-lazy_static! {
-    static ref SOCKS5_ADDRESS_QUEUE: Arc<Mutex<VecDeque<String>>> = Arc::new(Mutex::new(VecDeque::new()));
-}
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::access_log::Access;
+use crate::auth::{Auth, Login};
+use crate::config::{Listener, SharedListener};
+use crate::happy_eyeballs::{self, order_addrs, ConnectError};
+use crate::lease::Lease;
+use crate::limit::{Limiter, Permit, Shaped};
+use crate::logging::connection_span;
+use crate::metrics::{Cause, Failure, ListenerMetrics};
+use crate::pool::AddressPool;
+use crate::resolver::Resolver;
+use crate::session::split_session;
+use crate::shutdown;
+use crate::socks4::{handle_socks4_connection, SOCKS4_VERSION};
+use crate::socks5_udp;
+use crate::strategy::SelectionKey;
 
 const SOCKS_VERSION: u8 = 0x05;
 const RESERVED: u8 = 0x00;
 const METHOD_NO_AUTH: u8 = 0x00;
 const METHOD_USERNAME_PASSWORD: u8 = 0x02;
 
+const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
 const AUTH_VERSION: u8 = 0x01;
 const AUTH_SUCCESS: u8 = 0x00;
 const AUTH_FAILURE: u8 = 0x01;
 
 pub async fn start_socks5_proxy(
-    listen_addr: SocketAddr,
-    ipv6_subnets: Arc<Vec<Ipv6Cidr>>,
-    ipv4_subnets: Arc<Vec<Ipv4Cidr>>,
-    allowed_ips: Option<Vec<IpAddr>>,
-    username: String,
-    password: String,
+    pool: Arc<AddressPool>,
+    settings: SharedListener,
+    auth: Arc<Auth>,
+    limiter: Arc<Limiter>,
+    resolver: Arc<Resolver>,
     timeout_duration: Duration, // 新增 timeout 参数
 ) -> Result<(), Box<dyn Error>> {
+    let listen_addr = settings.load().bind;
     let listener = TcpListener::bind(listen_addr).await?;
-    println!("SOCKS5 proxy listening on {}", listen_addr);
-
-    let auth_enabled = !username.is_empty() && !password.is_empty();
+    info!("SOCKS5 proxy listening on {}", listen_addr);
 
     loop {
         let (mut socket, addr) = listener.accept().await?;
+        // Settings are read per connection so a reload applies to the next one
+        let current = settings.load_full();
 
-        if let Some(ref allowed_ips) = allowed_ips {
-            let ip_allowed = allowed_ips.iter().any(|allowed_ip| match (allowed_ip, addr.ip()) {
-                (IpAddr::V4(allowed_ip), IpAddr::V4(client_ip)) => {
-                    Ipv4Cidr::new(*allowed_ip, 32).unwrap().contains(&client_ip)
-                }
-                (IpAddr::V6(allowed_ip), IpAddr::V6(client_ip)) => {
-                    Ipv6Cidr::new(*allowed_ip, 128).unwrap().contains(&client_ip)
-                }
-                _ => false,
-            });
-
-            if !ip_allowed {
-                eprintln!("Access denied for IP: {}", addr.ip());
-                continue;
-            }
+        if shutdown::is_draining() {
+            debug!(client = %addr.ip(), listener = %listen_addr, "Draining, connection turned away");
+            continue;
+        }
+        if !current.acl.allows(addr.ip()) {
+            info!(client = %addr.ip(), listener = %listen_addr, "Access denied for client");
+            ListenerMetrics::new(listen_addr, "socks5").error(Cause::Denied);
+            continue;
+        }
+        if let Err(e) = limiter.connect(addr.ip()) {
+            debug!(client = %addr.ip(), listener = %listen_addr, "Connection turned away: {}", e);
+            ListenerMetrics::new(listen_addr, "socks5").error(Cause::Limited);
+            continue;
         }
 
-        let ipv6_subnets = Arc::clone(&ipv6_subnets);
-        let ipv4_subnets = Arc::clone(&ipv4_subnets);
-
-        let username = username.clone();
-        let password = password.clone();
+        let pool = Arc::clone(&pool);
+        let auth = Arc::clone(&auth);
+        let limiter = Arc::clone(&limiter);
+        let resolver = Arc::clone(&resolver);
 
         tokio::spawn(async move {
-            if let Err(e) = handle_socks5_connection(
+            // Failures are logged and counted by handle_socks5_connection
+            let _ = handle_socks5_connection(
                 &mut socket,
-                &ipv6_subnets,
-                &ipv4_subnets,
-                &username,
-                &password,
-                auth_enabled,
-                timeout_duration // 传递 timeout 参数
-            ).await {
-                eprintln!("Failed to handle SOCKS5 connection: {}", e);
-            }
+                &pool,
+                &current,
+                &auth,
+                &limiter,
+                &resolver,
+                timeout_duration, // 传递 timeout 参数
+            ).await;
         });
     }
 }
 
-async fn handle_socks5_connection(
+pub(crate) async fn handle_socks5_connection(
     socket: &mut TcpStream,
-    ipv6_subnets: &[Ipv6Cidr],
-    ipv4_subnets: &[Ipv4Cidr],
-    expected_username: &str,
-    expected_password: &str,
-    auth_enabled: bool,
+    pool: &AddressPool,
+    settings: &Listener,
+    auth: &Auth,
+    limiter: &Limiter,
+    resolver: &Resolver,
     timeout_duration: Duration, // 新增 timeout 参数
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = [0; 2];
-    timeout(timeout_duration, socket.read_exact(&mut buf)).await??;
-
-    if buf[0] != SOCKS_VERSION {
-        return Err("Unsupported SOCKS version".into());
+    let started = Instant::now();
+    let client = socket.peer_addr().ok().map(|addr| addr.ip());
+    let version = timeout(timeout_duration, socket.read_u8()).await??;
+    let protocol = match version {
+        SOCKS4_VERSION => "socks4",
+        SOCKS_VERSION => "socks5",
+        _ => {
+            warn!(client = ?client, listener = %settings.bind, "Unsupported SOCKS version {:#04x}", version);
+            return Err("Unsupported SOCKS version".into());
+        }
+    };
+    let metrics = ListenerMetrics::new(settings.bind, protocol);
+    metrics.connection();
+    let span = connection_span(protocol, settings.bind, client);
+    // The command is set once the request has been read
+    let access = Access::new(protocol, settings.bind, client, "-");
+    let served = access
+        .killable(async {
+            match version {
+                SOCKS4_VERSION => handle_socks4_connection(socket, pool, settings, auth, limiter, resolver, &metrics, &access, timeout_duration).await,
+                _ => serve_socks5(socket, pool, settings, auth, limiter, resolver, &metrics, &access, timeout_duration).await,
+            }
+        })
+        .instrument(span.clone())
+        .await;
+    let Some(result) = served else {
+        span.in_scope(|| info!(duration_ms = started.elapsed().as_millis() as u64, "Closed from the admin endpoint"));
+        return Ok(());
+    };
+    if let Err(e) = &result {
+        if let Some(code) = e.downcast_ref::<Failure>().and_then(|failure| failure.reply) {
+            access.status(code as u16);
+        }
+        metrics.error(Cause::of(&**e));
+        span.in_scope(|| warn!(duration_ms = started.elapsed().as_millis() as u64, "{}", e));
     }
+    result
+}
+
+/// Serves one SOCKS5 connection after the version byte has been read.
+#[allow(clippy::too_many_arguments)]
+async fn serve_socks5(
+    socket: &mut TcpStream,
+    pool: &AddressPool,
+    settings: &Listener,
+    auth: &Auth,
+    limiter: &Limiter,
+    resolver: &Resolver,
+    metrics: &ListenerMetrics,
+    access: &Access,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let client_ip = socket.peer_addr().ok().map(|addr| addr.ip());
+    let auth_enabled = auth.is_enabled();
 
-    let nmethods = buf[1] as usize;
+    let nmethods = timeout(timeout_duration, socket.read_u8()).await?? as usize;
     let mut methods = vec![0; nmethods];
     timeout(timeout_duration, socket.read_exact(&mut methods)).await??;
 
@@ -109,83 +169,229 @@ async fn handle_socks5_connection(
         }
     } else if methods.contains(&METHOD_NO_AUTH) {
         METHOD_NO_AUTH
+    } else if methods.contains(&METHOD_USERNAME_PASSWORD) {
+        // Any credentials are accepted, the username may still carry a session id
+        METHOD_USERNAME_PASSWORD
     } else {
         0xFF  // No acceptable method if only no auth is supported but no auth method is provided
     };
 
     timeout(timeout_duration, socket.write_all(&[SOCKS_VERSION, selected_method])).await??;
 
+    let mut session = None;
+    // Held until the relay below finishes
+    let mut login: Option<Login> = None;
     if selected_method == METHOD_USERNAME_PASSWORD {
-        if !timeout(timeout_duration, authenticate(socket, expected_username, expected_password)).await?? {
-            timeout(timeout_duration, socket.write_all(&[AUTH_VERSION, AUTH_FAILURE])).await??;
-            return Err("Authentication failed".into());
+        let (username, password) = timeout(timeout_duration, authenticate(socket)).await??;
+        if auth_enabled {
+            match auth.login(split_session(&username).0, &password).await {
+                Ok(user) => {
+                    access.user(split_session(&username).0);
+                    login = Some(user);
+                }
+                Err(e) => {
+                    timeout(timeout_duration, socket.write_all(&[AUTH_VERSION, AUTH_FAILURE])).await??;
+                    return Err(Failure::new(Cause::Auth, format!("Authentication failed for {}: {}", username, e)).into());
+                }
+            }
         }
         timeout(timeout_duration, socket.write_all(&[AUTH_VERSION, AUTH_SUCCESS])).await??;
+
+        // `user-session-<id>` pins the egress address for the whole session
+        if split_session(&username).1.is_some() {
+            session = Some(username);
+        }
     } else if selected_method == 0xFF {
-        return Err("No acceptable authentication method".into());
+        return Err(Failure::new(Cause::Auth, "No acceptable authentication method".to_string()).into());
     }
 
     let mut buf = [0; 4];
     timeout(timeout_duration, socket.read_exact(&mut buf)).await??;
+    let (command, atyp) = (buf[1], buf[3]);
+    if ![ATYP_IPV4, ATYP_DOMAIN, ATYP_IPV6].contains(&atyp) {
+        // The address length is unknown, so the request can't be read any further
+        return reply_error(socket, ResponseCode::AddressTypeNotSupported, format!("Unsupported address type {:#04x}", atyp), timeout_duration).await;
+    }
+    let request = timeout(timeout_duration, TargetAddr::read(socket, atyp)).await??;
+    access.target(&request);
+    let subnets = login.as_ref().and_then(Login::subnets);
+    // Every command holds a tunnel slot, until the relay, the BIND or the association ends
+    let permit = match limiter.admit(client_ip, login.as_ref(), true) {
+        Ok(permit) => permit,
+        Err(e) => return reply_failure(socket, ResponseCode::ConnectionNotAllowed, Cause::Limited, e.to_string(), timeout_duration).await,
+    };
 
-    let (addr, bind_addr) = match buf[3] {
-        0x01 => {
-            let mut ipv4 = [0; 4];
-            timeout(timeout_duration, socket.read_exact(&mut ipv4)).await??;
-            let port = timeout(timeout_duration, read_port(socket)).await??;
-            let addr = SocketAddr::new(IpAddr::V4(ipv4.into()), port);
-            let bind_addr = get_rand_ipv4_socket_addr(ipv4_subnets);
-            (addr, bind_addr)
+    match command {
+        CMD_CONNECT => {
+            metrics.request("connect");
+            access.command("CONNECT");
         }
-        0x03 => {
-            let mut domain_len = [0; 1];
-            timeout(timeout_duration, socket.read_exact(&mut domain_len)).await??;
-            let mut domain = vec![0; domain_len[0] as usize];
-            timeout(timeout_duration, socket.read_exact(&mut domain)).await??;
-            let port = timeout(timeout_duration, read_port(socket)).await??;
-            let domain = String::from_utf8(domain)?;
-            let addr_str = format!("{}:{}", domain, port);
-
-            let addr = tokio::net::lookup_host(addr_str).await?.next().ok_or("Invalid domain name")?;
-
-            let bind_addr = match addr {
-                SocketAddr::V4(_) => get_rand_ipv4_socket_addr(ipv4_subnets),
-                SocketAddr::V6(_) => get_rand_ipv6_socket_addr(ipv6_subnets),
+        CMD_BIND => {
+            metrics.request("bind");
+            access.command("BIND");
+        }
+        CMD_UDP_ASSOCIATE => {
+            metrics.request("udp_associate");
+            access.command("UDP_ASSOCIATE");
+            let key = SelectionKey { client: client_ip, target: None, session: session.as_deref() };
+            // DST.ADDR is where the client will send from, if it knows
+            let expected = match request {
+                TargetAddr::Ip(addr) => Some(addr),
+                TargetAddr::Domain(..) => None,
             };
-            (addr, bind_addr)
+            return socks5_udp::associate(socket, pool, settings, resolver, subnets, &key, expected, &permit, access, timeout_duration).await;
         }
-        0x04 => {
-            let mut ipv6 = [0; 16];
-            timeout(timeout_duration, socket.read_exact(&mut ipv6)).await??;
-            let port = timeout(timeout_duration, read_port(socket)).await??;
-            let addr = SocketAddr::new(IpAddr::V6(ipv6.into()), port);
-            let bind_addr = get_rand_ipv6_socket_addr(ipv6_subnets);
-            (addr, bind_addr)
+        command => {
+            return reply_error(socket, ResponseCode::CommandNotSupported, format!("Unsupported command {:#04x}", command), timeout_duration).await;
         }
-        _ => return Err("Unsupported address type".into()),
-    };
+    }
 
-    let socket_type = match addr {
-        SocketAddr::V4(_) => TcpSocket::new_v4()?,
-        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    let target = request.host();
+    // A user's own subnets take precedence over the routing rules
+    let subnets = subnets.or_else(|| settings.routes.subnets_for(&target));
+    let addrs: Vec<SocketAddr> = match &request {
+        TargetAddr::Ip(addr) => vec![*addr],
+        TargetAddr::Domain(domain, port) => match resolver.lookup(domain, *port, subnets.unwrap_or(&pool.subnets()), settings.nat64.is_some()).await {
+            Ok(addrs) => addrs,
+            Err(e) => return reply_error(socket, ResponseCode::HostUnreachable, format!("Failed to resolve {}: {}", domain, e), timeout_duration).await,
+        },
     };
+    if addrs.is_empty() {
+        return reply_error(socket, ResponseCode::HostUnreachable, format!("No address for {}", target), timeout_duration).await;
+    }
 
-    socket_type.bind(bind_addr)?;
+    // A BIND request may leave the expected peer unspecified, the peer is checked on accept
+    let allowed: Vec<SocketAddr> = addrs
+        .iter()
+        .copied()
+        .filter(|addr| (command == CMD_BIND && addr.ip().is_unspecified()) || settings.destinations.allows(&target, *addr))
+        .collect();
+    if allowed.is_empty() {
+        return reply_error(socket, ResponseCode::ConnectionNotAllowed, format!("Destination {} ({}) not allowed", target, addrs[0]), timeout_duration).await;
+    }
+
+    let key = SelectionKey { client: client_ip, target: Some(&target), session: session.as_deref() };
+    if command == CMD_BIND {
+        let pool_subnets = pool.subnets();
+        let families = subnets.unwrap_or(&pool_subnets);
+        let listen_on = if allowed[0].ip().is_unspecified() {
+            // Any peer may connect, so listen on a family the pool has rather than DST's, which
+            // might be missing from the pool or, with NAT64, turn into the prefix
+            let ipv6 = !families.ipv6.is_empty() && (settings.prefer_ipv6 || families.ipv4.is_empty());
+            let family = if ipv6 { IpAddr::V6(Ipv6Addr::UNSPECIFIED) } else { IpAddr::V4(Ipv4Addr::UNSPECIFIED) };
+            (!families.ipv6.is_empty() || !families.ipv4.is_empty()).then_some((family, allowed[0]))
+        } else {
+            order_addrs(&allowed, families, settings.prefer_ipv6, settings.nat64).first().map(|addr| (addr.ip(), *addr))
+        };
+        let Some((family, expected)) = listen_on else {
+            return reply_error(socket, ResponseCode::NetworkUnreachable, format!("No egress address family for {}", target), timeout_duration).await;
+        };
+        let lease = match pool.acquire(family, settings.strategy, &key, subnets, timeout_duration).await {
+            Ok(lease) => lease,
+            Err(e) => return reply_failure(socket, ResponseCode::GeneralFailure, Cause::Pool, format!("No egress address for {}: {}", expected, e), timeout_duration).await,
+        };
+        access.egress(lease.ip());
+        return accept_bind(socket, settings, metrics, &permit, access, lease, expected, timeout_duration).await;
+    }
 
-    let mut remote = timeout(timeout_duration, socket_type.connect(addr)).await??;
+    let started = Instant::now();
+    // The lease is held until the relay below finishes
+    let (mut remote, _lease) = match happy_eyeballs::connect(pool, settings, &key, subnets, &allowed, timeout_duration).await {
+        Ok(connected) => {
+            metrics.connected(started.elapsed());
+            connected
+        }
+        Err(e) => return reply_failure(socket, ResponseCode::from(&e), Cause::from(&e), format!("Failed to connect to {}: {}", target, e), timeout_duration).await,
+    };
 
-    let reply = SocksReply::new(ResponseCode::Success);
+    // BND.ADDR tells the client which pool address the connection leaves from
+    let local_addr = remote.local_addr()?;
+    access.egress(local_addr.ip());
+    let reply = SocksReply::with_addr(ResponseCode::Success, local_addr);
     timeout(timeout_duration, reply.send(socket)).await??;
+    access.status(ResponseCode::Success as u16);
 
-    timeout(timeout_duration, tokio::io::copy_bidirectional(socket, &mut remote)).await??;
+    let (client_bytes, server_bytes) = timeout(timeout_duration, metrics.relay(&mut Shaped::new(socket, permit), &mut remote)).await??;
+    access.bytes(client_bytes, server_bytes);
+    info!(client_bytes, server_bytes, duration_ms = started.elapsed().as_millis() as u64, "Tunnel closed");
     Ok(())
 }
 
-async fn authenticate(socket: &mut TcpStream, expected_username: &str, expected_password: &str) -> Result<bool, Box<dyn Error>> {
+/// Sends a failure reply, then returns `error` so the caller can log it and close.
+pub(crate) async fn reply_error(
+    socket: &mut TcpStream,
+    code: ResponseCode,
+    error: String,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    reply_failure(socket, code, Cause::from(code), error, timeout_duration).await
+}
+
+/// `reply_error` for failures the reply code doesn't tell apart, e.g. an empty pool behind a
+/// general failure.
+async fn reply_failure(
+    socket: &mut TcpStream,
+    code: ResponseCode,
+    cause: Cause,
+    error: String,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let _ = timeout(timeout_duration, SocksReply::new(code).send(socket)).await;
+    Err(Failure::new(cause, error).replied(code as u8).into())
+}
+
+/// The BIND command: listens on the leased pool address, reports it in the first reply, and
+/// relays the first incoming connection from `expected` (any peer if unspecified) that the
+/// destination rules allow, reporting the peer in the second reply.
+#[allow(clippy::too_many_arguments)]
+async fn accept_bind(
+    socket: &mut TcpStream,
+    settings: &Listener,
+    metrics: &ListenerMetrics,
+    permit: &Permit,
+    access: &Access,
+    lease: Lease,
+    expected: SocketAddr,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let listener = match TcpListener::bind(SocketAddr::new(lease.ip(), 0)).await {
+        Ok(listener) => listener,
+        Err(e) => return reply_error(socket, ResponseCode::from(&e), format!("Failed to listen on {}: {}", lease.ip(), e), timeout_duration).await,
+    };
+    let bound = listener.local_addr()?;
+    timeout(timeout_duration, SocksReply::with_addr(ResponseCode::Success, bound).send(socket)).await??;
+
+    let deadline = Instant::now() + timeout_duration;
+    let (mut remote, peer) = loop {
+        let (remote, peer) = match timeout_at(deadline, listener.accept()).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => return reply_error(socket, ResponseCode::from(&e), format!("BIND on {} failed: {}", bound, e), timeout_duration).await,
+            Err(_) => return reply_error(socket, ResponseCode::TtlExpired, format!("No connection to BIND on {}", bound), timeout_duration).await,
+        };
+        let unexpected = !expected.ip().is_unspecified() && peer.ip().to_canonical() != expected.ip().to_canonical();
+        if unexpected || !settings.destinations.allows(&peer.ip().to_string(), peer) {
+            info!(%bound, %peer, "BIND rejected an unexpected connection");
+            continue;
+        }
+        break (remote, peer);
+    };
+    drop(listener);
+
+    timeout(timeout_duration, SocksReply::with_addr(ResponseCode::Success, peer).send(socket)).await??;
+    access.status(ResponseCode::Success as u16);
+    let started = Instant::now();
+    let (client_bytes, server_bytes) = timeout(timeout_duration, metrics.relay(&mut Shaped::new(socket, permit.clone()), &mut remote)).await??;
+    access.bytes(client_bytes, server_bytes);
+    info!(%peer, client_bytes, server_bytes, duration_ms = started.elapsed().as_millis() as u64, "Tunnel closed");
+    Ok(())
+}
+
+/// Reads the RFC 1929 username/password sub-negotiation and returns the credentials.
+async fn authenticate(socket: &mut TcpStream) -> Result<(String, String), Box<dyn Error>> {
     let mut version = [0; 1];
     socket.read_exact(&mut version).await?;
     if version[0] != AUTH_VERSION {
-        return Ok(false);
+        return Err("Unsupported authentication version".into());
     }
 
     let mut ulen = [0; 1];
@@ -198,69 +404,119 @@ async fn authenticate(socket: &mut TcpStream, expected_username: &str, expected_
     let mut passwd = vec![0; plen[0] as usize];
     socket.read_exact(&mut passwd).await?;
 
-    if &uname == expected_username.as_bytes() && &passwd == expected_password.as_bytes() {
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+    Ok((
+        String::from_utf8_lossy(&uname).into_owned(),
+        String::from_utf8_lossy(&passwd).into_owned(),
+    ))
 }
 
-async fn read_port(socket: &mut TcpStream) -> Result<u16, Box<dyn Error>> {
-    let mut buf = [0; 2];
-    socket.read_exact(&mut buf).await?;
-    Ok(u16::from_be_bytes(buf))
+/// DST.ADDR and DST.PORT of a request or a UDP datagram.
+#[derive(Debug, PartialEq)]
+pub(crate) enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
 }
 
-fn get_rand_ipv4_socket_addr(ipv4_subnets: &[Ipv4Cidr]) -> SocketAddr {
-    let mut rng = rand::thread_rng();
-    let ipv4_cidr = ipv4_subnets.choose(&mut rng).unwrap();
-    let ip_addr = get_rand_ipv4(ipv4_cidr);
-    SocketAddr::new(ip_addr, random::<u16>())
-}
+impl TargetAddr {
+    /// Reads the address and the port of a request with address type `atyp`.
+    async fn read(socket: &mut TcpStream, atyp: u8) -> Result<Self, Box<dyn Error>> {
+        let addr = match atyp {
+            ATYP_IPV4 => {
+                let mut ipv4 = [0; 4];
+                socket.read_exact(&mut ipv4).await?;
+                TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ipv4.into()), read_port(socket).await?))
+            }
+            ATYP_DOMAIN => {
+                let mut domain_len = [0; 1];
+                socket.read_exact(&mut domain_len).await?;
+                let mut domain = vec![0; domain_len[0] as usize];
+                socket.read_exact(&mut domain).await?;
+                TargetAddr::Domain(String::from_utf8(domain)?, read_port(socket).await?)
+            }
+            ATYP_IPV6 => {
+                let mut ipv6 = [0; 16];
+                socket.read_exact(&mut ipv6).await?;
+                TargetAddr::Ip(SocketAddr::new(IpAddr::V6(ipv6.into()), read_port(socket).await?))
+            }
+            _ => return Err("Unsupported address type".into()),
+        };
+        Ok(addr)
+    }
+
+    /// Parses ATYP, the address and the port at the start of `buf` and returns how many bytes
+    /// they took.
+    pub(crate) fn parse(buf: &[u8]) -> Option<(Self, usize)> {
+        let port = |at: usize| buf.get(at..at + 2).map(|port| u16::from_be_bytes([port[0], port[1]]));
+        match *buf.first()? {
+            ATYP_IPV4 => {
+                let ip: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+                Some((TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ip.into()), port(5)?)), 7))
+            }
+            ATYP_DOMAIN => {
+                let len = *buf.get(1)? as usize;
+                let domain = String::from_utf8(buf.get(2..2 + len)?.to_vec()).ok()?;
+                Some((TargetAddr::Domain(domain, port(2 + len)?), 4 + len))
+            }
+            ATYP_IPV6 => {
+                let ip: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+                Some((TargetAddr::Ip(SocketAddr::new(IpAddr::V6(ip.into()), port(17)?)), 19))
+            }
+            _ => None,
+        }
+    }
 
-fn get_rand_ipv6_socket_addr(ipv6_subnets: &[Ipv6Cidr]) -> SocketAddr {
-    let mut rng = rand::thread_rng();
-    let ipv6_cidr = ipv6_subnets.choose(&mut rng).unwrap();
-    let ip_addr = get_rand_ipv6(ipv6_cidr);
-    SocketAddr::new(ip_addr, random::<u16>())
+    /// The host as the client named it, for destination rules and address selection.
+    pub(crate) fn host(&self) -> String {
+        match self {
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+            TargetAddr::Domain(domain, _) => domain.clone(),
+        }
+    }
 }
 
-fn get_rand_ipv4(ipv4_cidr: &Ipv4Cidr) -> IpAddr {
-    let mut ipv4 = u32::from(ipv4_cidr.first_address());
-    if ipv4_cidr.network_length() != 32 {
-        let rand: u32 = random();
-        let net_part = (ipv4 >> (32 - ipv4_cidr.network_length())) << (32 - ipv4_cidr.network_length());
-        let host_part = (rand << ipv4_cidr.network_length()) >> ipv4_cidr.network_length();
-        ipv4 = net_part | host_part;
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
     }
-    IpAddr::V4(ipv4.into())
 }
 
-fn get_rand_ipv6(ipv6_cidr: &Ipv6Cidr) -> IpAddr {
-    let mut ipv6 = u128::from(ipv6_cidr.first_address());
-    if ipv6_cidr.network_length() != 128 {
-        let rand: u128 = random();
-        let net_part = (ipv6 >> (128 - ipv6_cidr.network_length())) << (128 - ipv6_cidr.network_length());
-        let host_part = (rand << ipv6_cidr.network_length()) >> ipv6_cidr.network_length();
-        ipv6 = net_part | host_part;
+/// Appends ATYP, the address and the port of `addr`.
+pub(crate) fn encode_addr(addr: SocketAddr, out: &mut Vec<u8>) {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
     }
-    IpAddr::V6(ipv6.into())
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_port(socket: &mut TcpStream) -> Result<u16, Box<dyn Error>> {
+    let mut buf = [0; 2];
+    socket.read_exact(&mut buf).await?;
+    Ok(u16::from_be_bytes(buf))
 }
 
-struct SocksReply {
-    buf: [u8; 10],
+pub(crate) struct SocksReply {
+    buf: Vec<u8>,
 }
 
 impl SocksReply {
     pub fn new(status: ResponseCode) -> Self {
-        let buf = [
-            SOCKS_VERSION,
-            status as u8,
-            RESERVED,
-            0x01,
-            0, 0, 0, 0,
-            0, 0,
-        ];
+        Self::with_addr(status, SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+
+    /// A reply carrying `addr` as BND.ADDR and BND.PORT.
+    pub fn with_addr(status: ResponseCode, addr: SocketAddr) -> Self {
+        let mut buf = vec![SOCKS_VERSION, status as u8, RESERVED];
+        encode_addr(addr, &mut buf);
         Self { buf }
     }
 
@@ -273,7 +529,190 @@ impl SocksReply {
     }
 }
 
-#[derive(Debug)]
-enum ResponseCode {
+/// REP values of RFC 1928.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ResponseCode {
     Success = 0x00,
+    GeneralFailure = 0x01,
+    ConnectionNotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl From<&ConnectError> for ResponseCode {
+    fn from(error: &ConnectError) -> Self {
+        match error {
+            ConnectError::NoUsableAddress => ResponseCode::NetworkUnreachable,
+            ConnectError::Pool(_) => ResponseCode::GeneralFailure,
+            ConnectError::Io(e) => ResponseCode::from(e),
+        }
+    }
+}
+
+impl From<ResponseCode> for Cause {
+    fn from(code: ResponseCode) -> Self {
+        match code {
+            ResponseCode::ConnectionNotAllowed => Cause::Denied,
+            ResponseCode::NetworkUnreachable | ResponseCode::HostUnreachable => Cause::Unreachable,
+            ResponseCode::ConnectionRefused => Cause::Refused,
+            ResponseCode::TtlExpired => Cause::Timeout,
+            ResponseCode::CommandNotSupported | ResponseCode::AddressTypeNotSupported => Cause::Unsupported,
+            ResponseCode::Success | ResponseCode::GeneralFailure => Cause::Io,
+        }
+    }
+}
+
+impl From<&io::Error> for ResponseCode {
+    fn from(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => ResponseCode::ConnectionRefused,
+            io::ErrorKind::NetworkUnreachable => ResponseCode::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => ResponseCode::HostUnreachable,
+            io::ErrorKind::TimedOut => ResponseCode::TtlExpired,
+            io::ErrorKind::PermissionDenied => ResponseCode::ConnectionNotAllowed,
+            _ => ResponseCode::GeneralFailure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Users;
+    use crate::limit::Limits;
+
+    /// Runs one SOCKS5 connection against a pool of `127.0.0.0/24` and returns the client end.
+    async fn connect_proxy() -> TcpStream {
+        connect_proxy_with(AddressPool::for_tests(&[], &["127.0.0.0/24"]), Listener::for_tests()).await
+    }
+
+    async fn connect_proxy_with(pool: AddressPool, settings: Listener) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let auth = Auth::new(Users::default());
+            let _ = handle_socks5_connection(&mut socket, &pool, &settings, &auth, &Limiter::new(Limits::default(), Limits::default()), &Resolver::for_tests(), Duration::from_secs(5)).await;
+        });
+        client
+    }
+
+    async fn read_reply(client: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut reply = vec![0; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        let len = if reply[3] == ATYP_IPV6 { 18 } else { 6 };
+        reply.resize(4 + len, 0);
+        client.read_exact(&mut reply[4..]).await.unwrap();
+        let (addr, _) = TargetAddr::parse(&reply[3..]).unwrap();
+        match addr {
+            TargetAddr::Ip(addr) => (reply[1], addr),
+            addr => panic!("unexpected {:?}", addr),
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_relays_incoming_connection() {
+        let mut client = connect_proxy().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, CMD_BIND, RESERVED, ATYP_IPV4, 127, 0, 0, 1, 0, 0]).await.unwrap();
+
+        let (status, bound) = read_reply(&mut client).await;
+        assert_eq!(status, ResponseCode::Success as u8);
+        assert!(bound.ip().to_string().starts_with("127.0.0."), "{}", bound);
+
+        let mut peer = TcpStream::connect(bound).await.unwrap();
+        let (status, reported) = read_reply(&mut client).await;
+        assert_eq!(status, ResponseCode::Success as u8);
+        assert_eq!(reported, peer.local_addr().unwrap());
+
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn bind_to_any_peer_listens_on_the_pool_family() {
+        // DST 0.0.0.0 with an IPv6-only pool, where NAT64 would make it `64:ff9b::`
+        let settings = Listener { nat64: Some(crate::nat64::WELL_KNOWN_PREFIX.parse().unwrap()), ..Listener::for_tests() };
+        let mut client = connect_proxy_with(AddressPool::for_tests(&["::1/128"], &[]), settings).await;
+        let (status, bound) = request(&mut client, &[SOCKS_VERSION, CMD_BIND, RESERVED, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await;
+        assert_eq!(status, ResponseCode::Success as u8);
+        assert_eq!(bound.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+        let peer = TcpStream::connect(bound).await.unwrap();
+        let (status, reported) = read_reply(&mut client).await;
+        assert_eq!(status, ResponseCode::Success as u8);
+        assert_eq!(reported, peer.local_addr().unwrap());
+    }
+
+    async fn request(client: &mut TcpStream, request: &[u8]) -> (u8, SocketAddr) {
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(request).await.unwrap();
+        read_reply(client).await
+    }
+
+    #[test]
+    fn io_errors_map_to_reply_codes() {
+        let code = |kind| ResponseCode::from(&io::Error::from(kind));
+        assert_eq!(code(io::ErrorKind::ConnectionRefused), ResponseCode::ConnectionRefused);
+        assert_eq!(code(io::ErrorKind::NetworkUnreachable), ResponseCode::NetworkUnreachable);
+        assert_eq!(code(io::ErrorKind::HostUnreachable), ResponseCode::HostUnreachable);
+        assert_eq!(code(io::ErrorKind::TimedOut), ResponseCode::TtlExpired);
+        assert_eq!(code(io::ErrorKind::Other), ResponseCode::GeneralFailure);
+        // ECONNREFUSED as the OS reports it
+        assert_eq!(ResponseCode::from(&io::Error::from_raw_os_error(111)), ResponseCode::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn connect_reports_egress_address() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port().to_be_bytes();
+        let mut client = connect_proxy().await;
+        let (status, bound) = request(&mut client, &[SOCKS_VERSION, CMD_CONNECT, RESERVED, ATYP_IPV4, 127, 0, 0, 1, port[0], port[1]]).await;
+        assert_eq!(status, ResponseCode::Success as u8);
+
+        let (_, peer) = target.accept().await.unwrap();
+        assert_eq!(bound, peer);
+    }
+
+    #[tokio::test]
+    async fn connect_failures_get_their_reply_code() {
+        // Bound and dropped, so nothing listens there
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port().to_be_bytes();
+        let mut client = connect_proxy().await;
+        let (status, _) = request(&mut client, &[SOCKS_VERSION, CMD_CONNECT, RESERVED, ATYP_IPV4, 127, 0, 0, 1, closed[0], closed[1]]).await;
+        assert_eq!(status, ResponseCode::ConnectionRefused as u8);
+
+        let mut client = connect_proxy().await;
+        let (status, _) = request(&mut client, &[SOCKS_VERSION, CMD_CONNECT, RESERVED, 0x05, 0, 0]).await;
+        assert_eq!(status, ResponseCode::AddressTypeNotSupported as u8);
+
+        // The pool has no IPv6 subnet, so there is no path to an IPv6-only target
+        let mut client = connect_proxy().await;
+        let mut ipv6 = vec![SOCKS_VERSION, CMD_CONNECT, RESERVED, ATYP_IPV6];
+        ipv6.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&[0, 80]);
+        let (status, _) = request(&mut client, &ipv6).await;
+        assert_eq!(status, ResponseCode::NetworkUnreachable as u8);
+    }
+
+    #[tokio::test]
+    async fn unknown_command_is_refused() {
+        let mut client = connect_proxy().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 0x09, RESERVED, ATYP_IPV4, 127, 0, 0, 1, 0, 80]).await.unwrap();
+        let (status, _) = read_reply(&mut client).await;
+        assert_eq!(status, ResponseCode::CommandNotSupported as u8);
+    }
 }
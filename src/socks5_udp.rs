@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::pending;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use tracing::{debug, info};
+
+use crate::access_log::Access;
+use crate::config::Listener;
+use crate::happy_eyeballs::order_addrs;
+use crate::lease::Lease;
+use crate::limit::{Direction, Permit};
+use crate::pool::{AddressPool, Subnets};
+use crate::resolver::Resolver;
+use crate::socks5::{encode_addr, reply_error, ResponseCode, SocksReply, TargetAddr};
+use crate::strategy::SelectionKey;
+
+/// Largest datagram the relay handles, the UDP maximum.
+const MAX_DATAGRAM: usize = 65535;
+
+/// An egress socket on a pool address, kept for the whole association.
+struct Egress {
+    socket: UdpSocket,
+    _lease: Lease,
+}
+
+/// Decides which datagrams on the relay socket come from the client.
+///
+/// Only the control connection's IP is accepted, and only the port from the request if the
+/// client gave one. The first accepted datagram pins the exact address for the rest of the
+/// association, replies go there.
+struct ClientFilter {
+    ip: IpAddr,
+    port: Option<u16>,
+    pinned: Option<SocketAddr>,
+}
+
+impl ClientFilter {
+    fn accept(&mut self, from: SocketAddr) -> bool {
+        if let Some(pinned) = self.pinned {
+            return pinned == from;
+        }
+        if from.ip().to_canonical() != self.ip || self.port.is_some_and(|port| port != from.port()) {
+            return false;
+        }
+        self.pinned = Some(from);
+        true
+    }
+}
+
+enum Event {
+    ControlClosed,
+    Client(io::Result<(usize, SocketAddr)>),
+    Remote(usize, io::Result<(usize, SocketAddr)>),
+    Idle,
+}
+
+/// Handles UDP ASSOCIATE: replies with a relay socket next to the control connection and
+/// forwards datagrams until the control connection closes or nothing was relayed for the
+/// listener's `udp_idle_timeout`.
+///
+/// Each association takes its own egress address per family from the pool on first use.
+/// Fragmented datagrams are dropped, destination rules apply to every datagram, and only
+/// replies from destinations the client has sent to are passed back.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn associate(
+    control: &mut TcpStream,
+    pool: &AddressPool,
+    settings: &Listener,
+    resolver: &Resolver,
+    subnets: Option<&Subnets>,
+    key: &SelectionKey<'_>,
+    expected: Option<SocketAddr>,
+    permit: &Permit,
+    access: &Access,
+    timeout_duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let client = control.peer_addr()?;
+    let pool_subnets = pool.subnets();
+    let families = subnets.unwrap_or(&pool_subnets);
+    let relay = match UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            return reply_error(control, ResponseCode::from(&e), format!("Failed to open UDP relay: {}", e), timeout_duration).await;
+        }
+    };
+    let relay_addr = relay.local_addr()?;
+    timeout(timeout_duration, SocksReply::with_addr(ResponseCode::Success, relay_addr).send(control)).await??;
+    access.status(ResponseCode::Success as u16);
+    info!(relay = %relay_addr, "UDP association opened");
+    let started = Instant::now();
+
+    let mut filter = ClientFilter {
+        ip: client.ip().to_canonical(),
+        port: expected.map(|addr| addr.port()).filter(|port| *port != 0),
+        pinned: None,
+    };
+    // Indexed by family, IPv4 first
+    let mut egress: [Option<Egress>; 2] = [None, None];
+    let mut peers: HashSet<SocketAddr> = HashSet::new();
+    let mut resolved: HashMap<(String, u16), Vec<SocketAddr>> = HashMap::new();
+    let mut client_buf = vec![0; MAX_DATAGRAM];
+    let mut remote_bufs = [vec![0; MAX_DATAGRAM], vec![0; MAX_DATAGRAM]];
+    let mut control_buf = [0; 64];
+    let mut last_active = Instant::now();
+
+    loop {
+        let [v4_buf, v6_buf] = &mut remote_bufs;
+        let event = tokio::select! {
+            read = control.read(&mut control_buf) => match read {
+                Ok(0) | Err(_) => Event::ControlClosed,
+                // Anything the client writes on the control connection is ignored
+                Ok(_) => continue,
+            },
+            received = relay.recv_from(&mut client_buf) => Event::Client(received),
+            received = recv_from(egress[0].as_ref(), v4_buf) => Event::Remote(0, received),
+            received = recv_from(egress[1].as_ref(), v6_buf) => Event::Remote(1, received),
+            _ = sleep_until(last_active + settings.udp_idle_timeout) => Event::Idle,
+        };
+
+        match event {
+            Event::ControlClosed => break,
+            Event::Idle => {
+                debug!("UDP association idle, closing");
+                break;
+            }
+            Event::Client(received) => {
+                let (len, from) = received?;
+                if !filter.accept(from) {
+                    continue;
+                }
+                let datagram = &client_buf[..len];
+                let Some((target, payload)) = parse_datagram(datagram) else {
+                    continue;
+                };
+
+                let host = target.host();
+                let addrs = match target {
+                    TargetAddr::Ip(addr) => vec![addr],
+                    TargetAddr::Domain(domain, port) => match resolved.get(&(domain.clone(), port)) {
+                        Some(addrs) => addrs.clone(),
+                        None => match resolver.lookup(&domain, port, families, settings.nat64.is_some()).await {
+                            Ok(addrs) => {
+                                resolved.insert((domain, port), addrs.clone());
+                                addrs
+                            }
+                            Err(e) => {
+                                debug!(target = %domain, "UDP association failed to resolve: {}", e);
+                                continue;
+                            }
+                        },
+                    },
+                };
+                let allowed: Vec<SocketAddr> = addrs.iter().copied().filter(|addr| settings.destinations.allows(&host, *addr)).collect();
+                if allowed.is_empty() {
+                    debug!(target = %host, address = %addrs[0], "UDP destination not allowed");
+                    continue;
+                }
+                // After the ACL, which has to see the real address of a NAT64 target
+                let Some(&addr) = order_addrs(&allowed, families, settings.prefer_ipv6, settings.nat64).first() else {
+                    debug!(target = %host, "UDP association has no egress address family for the target");
+                    continue;
+                };
+
+                let family = if addr.is_ipv4() { 0 } else { 1 };
+                if egress[family].is_none() {
+                    let lease = match pool.acquire(addr.ip(), settings.strategy, key, subnets, timeout_duration).await {
+                        Ok(lease) => lease,
+                        Err(e) => {
+                            debug!(address = %addr, "UDP association has no egress address: {}", e);
+                            continue;
+                        }
+                    };
+                    let socket = UdpSocket::bind(SocketAddr::new(lease.ip(), 0)).await?;
+                    access.egress(lease.ip());
+                    debug!("UDP association sends from {}", lease.ip());
+                    egress[family] = Some(Egress { socket, _lease: lease });
+                }
+                if let Some(egress) = &egress[family] {
+                    permit.pace(Direction::Upload, payload.len() as u64).await;
+                    // One unreachable destination shouldn't end the association
+                    if let Err(e) = egress.socket.send_to(payload, addr).await {
+                        debug!(address = %addr, "UDP association failed to send: {}", e);
+                        continue;
+                    }
+                    peers.insert(addr);
+                    access.bytes(payload.len() as u64, 0);
+                    last_active = Instant::now();
+                }
+            }
+            Event::Remote(family, received) => {
+                let (len, from) = received?;
+                let Some(client_addr) = filter.pinned else {
+                    continue;
+                };
+                if !peers.contains(&from) {
+                    continue;
+                }
+                let mut datagram = Vec::with_capacity(len + 22);
+                datagram.extend_from_slice(&[0, 0, 0]);
+                // The client asked for the IPv4 address behind a NAT64 one
+                let source = match (settings.nat64, from) {
+                    (Some(prefix), SocketAddr::V6(v6)) => prefix.extract(*v6.ip()).map_or(from, |ip| SocketAddr::new(ip.into(), v6.port())),
+                    _ => from,
+                };
+                encode_addr(source, &mut datagram);
+                datagram.extend_from_slice(&remote_bufs[family][..len]);
+                permit.pace(Direction::Download, len as u64).await;
+                relay.send_to(&datagram, client_addr).await?;
+                access.bytes(0, len as u64);
+                last_active = Instant::now();
+            }
+        }
+    }
+    info!(peers = peers.len(), duration_ms = started.elapsed().as_millis() as u64, "UDP association closed");
+    Ok(())
+}
+
+async fn recv_from(egress: Option<&Egress>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match egress {
+        Some(egress) => egress.socket.recv_from(buf).await,
+        None => pending().await,
+    }
+}
+
+/// Splits a client datagram into its destination and payload. Fragments (FRAG other than
+/// zero) and malformed headers yield `None`; the datagram is dropped, as RFC 1928 allows.
+fn parse_datagram(datagram: &[u8]) -> Option<(TargetAddr, &[u8])> {
+    let header = datagram.get(..3)?;
+    if header[2] != 0 {
+        return None;
+    }
+    let (target, len) = TargetAddr::parse(&datagram[3..])?;
+    Some((target, &datagram[3 + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limit::{Limiter, Limits};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn fragments_and_truncated_headers_are_dropped() {
+        let datagram = [0, 0, 0, 0x01, 192, 0, 2, 1, 0, 53, b'x'];
+        let (target, payload) = parse_datagram(&datagram).unwrap();
+        assert_eq!(target, TargetAddr::Ip("192.0.2.1:53".parse().unwrap()));
+        assert_eq!(payload, b"x");
+
+        let mut fragment = datagram;
+        fragment[2] = 1;
+        assert!(parse_datagram(&fragment).is_none());
+        assert!(parse_datagram(&datagram[..8]).is_none());
+
+        let domain = [0, 0, 0, 0x03, 3, b'a', b'.', b'b', 1, 187, b'q'];
+        let (target, payload) = parse_datagram(&domain).unwrap();
+        assert_eq!(target, TargetAddr::Domain("a.b".into(), 443));
+        assert_eq!(payload, b"q");
+    }
+
+    #[test]
+    fn client_is_pinned_to_first_address() {
+        let mut filter = ClientFilter { ip: "127.0.0.1".parse().unwrap(), port: None, pinned: None };
+        assert!(!filter.accept("127.0.0.2:5000".parse().unwrap()));
+        assert!(filter.accept("127.0.0.1:5000".parse().unwrap()));
+        assert!(!filter.accept("127.0.0.1:5001".parse().unwrap()));
+
+        let mut filter = ClientFilter { ip: "127.0.0.1".parse().unwrap(), port: Some(6000), pinned: None };
+        assert!(!filter.accept("127.0.0.1:5000".parse().unwrap()));
+        assert!(filter.accept("127.0.0.1:6000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams_from_pool_address() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            // Report the source address back
+            echo.send_to(from.ip().to_string().as_bytes(), from).await.unwrap();
+            let _ = len;
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client_control = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut control, _) = listener.accept().await.unwrap();
+
+        let pool = AddressPool::for_tests(&[], &["127.0.0.0/24"]);
+        let settings = Listener::for_tests();
+        let relay = tokio::spawn(async move {
+            let permit = Limiter::new(Limits::default(), Limits::default()).admit(None, None, true).unwrap();
+            associate(&mut control, &pool, &settings, &Resolver::for_tests(), None, &SelectionKey::default(), None, &permit, &Access::new("socks5", settings.bind, None, "-"), Duration::from_secs(5)).await.unwrap();
+        });
+
+        let mut reply = [0; 10];
+        client_control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x01]);
+        let relay_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), u16::from_be_bytes([reply[8], reply[9]]));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = vec![0, 0, 0];
+        encode_addr(echo_addr, &mut datagram);
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        let mut buf = [0; 1500];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        let (source, header_len) = TargetAddr::parse(&buf[3..len]).unwrap();
+        assert_eq!(source, TargetAddr::Ip(echo_addr));
+        let egress: IpAddr = std::str::from_utf8(&buf[3 + header_len..len]).unwrap().parse().unwrap();
+        assert!("127.0.0.0/24".parse::<cidr::Ipv4Cidr>().unwrap().contains(&match egress {
+            IpAddr::V4(ip) => ip,
+            ip => panic!("unexpected {}", ip),
+        }));
+
+        // Closing the control connection ends the association
+        client_control.shutdown().await.unwrap();
+        drop(client_control);
+        timeout(Duration::from_secs(5), relay).await.unwrap().unwrap();
+    }
+}
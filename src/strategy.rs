@@ -0,0 +1,105 @@
+use cidr::{Ipv4Cidr, Ipv6Cidr};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::pool::{ipv4_with_host_bits, ipv6_with_host_bits};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How a listener picks the egress address for a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// A fresh random address for every connection.
+    Random,
+    /// Same address for every connection from one client IP.
+    PerClient,
+    /// Same address for every connection to one target host.
+    PerTarget,
+    /// Same address for every connection from one client IP to one target host.
+    PerClientTarget,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "random" => Ok(Strategy::Random),
+            "per-client" => Ok(Strategy::PerClient),
+            "per-target" => Ok(Strategy::PerTarget),
+            "per-client-target" => Ok(Strategy::PerClientTarget),
+            _ => Err(format!(
+                "Invalid strategy '{}', expected random, per-client, per-target or per-client-target",
+                s
+            )),
+        }
+    }
+}
+
+/// Inputs a hash-based strategy can derive an address from.
+#[derive(Clone, Copy, Default)]
+pub struct SelectionKey<'a> {
+    pub client: Option<IpAddr>,
+    pub target: Option<&'a str>,
+    pub session: Option<&'a str>,
+}
+
+/// Derives egress addresses from an HMAC of the selection inputs, so the same inputs map to
+/// the same address across restarts without keeping any state.
+pub struct AddressDeriver {
+    secret: Vec<u8>,
+}
+
+impl AddressDeriver {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        AddressDeriver { secret: secret.into() }
+    }
+
+    /// Returns `None` for `Strategy::Random` or when there is no subnet to pick from.
+    pub fn ipv6(&self, strategy: Strategy, key: &SelectionKey, subnets: &[Ipv6Cidr]) -> Option<IpAddr> {
+        let digest = self.digest(strategy, key, "ipv6")?;
+        let cidr = subnets.get(subnet_index(&digest, subnets.len())?)?;
+        let host_bits = u128::from_be_bytes(digest[8..24].try_into().unwrap());
+        Some(IpAddr::V6(ipv6_with_host_bits(cidr, host_bits)))
+    }
+
+    /// Returns `None` for `Strategy::Random` or when there is no subnet to pick from.
+    pub fn ipv4(&self, strategy: Strategy, key: &SelectionKey, subnets: &[Ipv4Cidr]) -> Option<IpAddr> {
+        let digest = self.digest(strategy, key, "ipv4")?;
+        let cidr = subnets.get(subnet_index(&digest, subnets.len())?)?;
+        let host_bits = u32::from_be_bytes(digest[8..12].try_into().unwrap());
+        Some(IpAddr::V4(ipv4_with_host_bits(cidr, host_bits)))
+    }
+
+    fn digest(&self, strategy: Strategy, key: &SelectionKey, family: &str) -> Option<[u8; 32]> {
+        let (client, target) = match strategy {
+            Strategy::Random => return None,
+            Strategy::PerClient => (key.client, None),
+            Strategy::PerTarget => (None, key.target),
+            Strategy::PerClientTarget => (key.client, key.target),
+        };
+
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        // Each input is length-prefixed so ("ab", "c") and ("a", "bc") never collide
+        for input in [
+            Some(family.to_string()),
+            client.map(|ip| ip.to_string()),
+            target.map(|host| host.to_lowercase()),
+            key.session.map(str::to_string),
+        ] {
+            let input = input.unwrap_or_default();
+            mac.update(&(input.len() as u32).to_be_bytes());
+            mac.update(input.as_bytes());
+        }
+        Some(mac.finalize().into_bytes().into())
+    }
+}
+
+fn subnet_index(digest: &[u8; 32], len: usize) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    Some((u64::from_be_bytes(digest[..8].try_into().unwrap()) % len as u64) as usize)
+}